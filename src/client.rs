@@ -1,221 +1,2789 @@
-use crate::protocol::{Request, Response};
-use anyhow::{Context, Result};
+use crate::protocol::{
+    self, signal_name, version_mismatch, write_frame, BuildCommand, BuildId, BuildSummary, Codec,
+    FrameReader, HistoryEntry, Request, Response, ServerStatus, ServerTruncation, PROTOCOL_VERSION,
+    VERSION,
+};
+use crate::shell::{quote_for_shell, ShellKind};
+use crate::transport::{Address, Stream};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use crossterm::{cursor, terminal, QueueableCommand};
+use regex::Regex;
+use serde::Serialize;
 use std::collections::VecDeque;
-use std::path::PathBuf;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::TcpStream;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsConnector;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Address of a build server, plus the token it expects
+pub struct Endpoint {
+    pub address: Address,
+    /// Secure the connection with TLS
+    pub tls: Option<TlsConnector>,
+    pub auth_token: Option<String>,
+    /// Further connection attempts after the first one fails
+    pub connect_retries: u32,
+    /// Pause before the first retry, doubled after each one
+    pub connect_delay: Duration,
+    /// Ask the server to compress build output; servers that can't send it
+    /// uncompressed instead
+    pub compression: Option<Codec>,
+}
+
+impl Endpoint {
+    /// Plain connection to `address`: no TLS, token, retries or compression
+    pub fn new(address: Address) -> Self {
+        Endpoint {
+            address,
+            tls: None,
+            auth_token: None,
+            connect_retries: 0,
+            connect_delay: Duration::from_millis(200),
+            compression: None,
+        }
+    }
+
+    /// Open a connection, check that the server speaks this client's protocol,
+    /// and present the `--auth-token` if there is one
+    async fn connect(&self) -> Result<Stream> {
+        let mut stream = self.connect_with_retries().await.with_context(|| {
+            format!("Failed to connect to build server at {}. Is the server running?", self)
+        })?;
+        if let Some(ref tls) = self.tls {
+            stream = stream.start_tls(tls, &self.address).await?;
+        }
+        let hello = Request::Hello {
+            protocol: PROTOCOL_VERSION,
+            version: VERSION.to_string(),
+            compression: self.compression,
+        };
+        send_request(&mut stream, &hello).await?;
+        match FrameReader::new(&mut stream).read_frame::<Response>().await {
+            Ok(Some(Response::Hello { protocol, .. })) if protocol == PROTOCOL_VERSION => {}
+            Ok(Some(Response::Hello { version, .. })) => bail!(version_mismatch(VERSION, &version)),
+            Ok(Some(Response::Error { message })) => bail!(message),
+            // Servers from before the handshake hang up on the unknown `Hello`,
+            // and so do servers expecting TLS
+            Ok(Some(_)) | Ok(None) | Err(_) => bail!(
+                "Build server at {} didn't answer the version handshake; it is probably older \
+                 than this client (v{}), restart the server{}",
+                self,
+                VERSION,
+                if self.tls.is_none() { " (or it serves TLS and needs --tls)" } else { "" }
+            ),
+        }
+        if let Some(ref token) = self.auth_token {
+            let request = Request::Auth {
+                token: token.clone(),
+            };
+            send_request(&mut stream, &request).await?;
+        }
+        Ok(stream)
+    }
+
+    /// Retry refused connections with a doubling delay, for a server that
+    /// is still starting up
+    async fn connect_with_retries(&self) -> std::io::Result<Stream> {
+        let mut delay = self.connect_delay;
+        for _ in 0..self.connect_retries {
+            match Stream::connect(&self.address).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => eprintln!("Cannot reach {} ({}), retrying in {:?}...", self, e, delay),
+            }
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+        Stream::connect(&self.address).await
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.address)
+    }
+}
+
+/// Client for driving builds from other Rust programs. Unlike the
+/// `build-runner` commands it prints nothing: builds report [`BuildEvent`]s
+/// and failures come back as errors.
+pub struct BuildClient {
+    endpoint: Endpoint,
+}
+
+/// Something that happened to a build started with [`BuildClient::build`]
+#[derive(Debug, Clone)]
+pub enum BuildEvent {
+    /// Waiting for a free build slot (`position` 1 = next in line)
+    Queued { build_id: BuildId, position: usize },
+    /// The build process was spawned
+    Started { build_id: BuildId, pid: Option<u32> },
+    Output(protocol::OutputLine),
+    /// How the build ended; always the last event
+    Finished(BuildOutcome),
+}
+
+/// Events a build's stream holds before the caller has read them
+const BUILD_EVENT_CAPACITY: usize = 256;
+
+impl BuildClient {
+    /// Client for `endpoint`, e.g. one using TLS or an auth token
+    pub fn new(endpoint: Endpoint) -> Self {
+        BuildClient { endpoint }
+    }
+
+    /// Client for the server at `address`, checking that it answers
+    pub async fn connect(address: Address) -> Result<Self> {
+        let client = BuildClient::new(Endpoint::new(address));
+        client.endpoint.connect().await?;
+        Ok(client)
+    }
+
+    /// Run `command` in `dir` (a path on the server) through the server's
+    /// shell. Dropping the stream before [`BuildEvent::Finished`] hangs up,
+    /// which cancels the build.
+    pub async fn build(
+        &self,
+        dir: impl Into<PathBuf>,
+        command: impl Into<String>,
+    ) -> Result<impl tokio_stream::Stream<Item = Result<BuildEvent>>> {
+        let request = Request::Build {
+            dir: dir.into(),
+            command: command.into(),
+            timeout: None,
+            env: Vec::new(),
+            max_lines: 0,
+            keep_running: false,
+            dry_run: false,
+            stdin: None,
+            shell: None,
+            server_truncate: None,
+        };
+        let mut stream = self.endpoint.connect().await?;
+        send_request(&mut stream, &request).await?;
+
+        let (events, receiver) = mpsc::channel(BUILD_EVENT_CAPACITY);
+        tokio::spawn(forward_build_events(stream, events));
+        Ok(ReceiverStream::new(receiver))
+    }
+
+    pub async fn status(&self) -> Result<ServerStatus> {
+        fetch_status(&self.endpoint).await
+    }
+
+    /// Stop the server, killing running builds if `force` is set and
+    /// otherwise waiting for them to finish
+    pub async fn stop(&self, force: bool) -> Result<()> {
+        let mut stream = self.endpoint.connect().await?;
+        send_request(&mut stream, &Request::Stop { force, drain: !force }).await?;
+        // The server hangs up once its builds have drained
+        let mut reader = FrameReader::new(&mut stream);
+        while let Some(response) = read_known(&mut reader, false).await? {
+            match response {
+                Response::Unauthorized => bail!(UNAUTHORIZED_MESSAGE),
+                Response::Error { message } => bail!(message),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Turn the responses on a build's connection into [`BuildEvent`]s until
+/// the build ends or the receiving stream is dropped
+async fn forward_build_events(mut stream: Stream, events: mpsc::Sender<Result<BuildEvent>>) {
+    let mut reader = FrameReader::new(&mut stream);
+    loop {
+        let response = tokio::select! {
+            response = read_known(&mut reader, false) => response,
+            _ = events.closed() => return,
+        };
+        let response = match response {
+            Ok(Some(response)) => response,
+            Ok(None) => Response::Error {
+                message: "Server closed the connection before the build finished".to_string(),
+            },
+            Err(e) => {
+                let _ = events.send(Err(e)).await;
+                return;
+            }
+        };
+
+        let mut batch = Vec::new();
+        let mut outcome = None;
+        match response {
+            Response::Queued { build_id, position } => {
+                batch.push(BuildEvent::Queued { build_id, position })
+            }
+            Response::BuildStarted { build_id, pid } => {
+                batch.push(BuildEvent::Started { build_id, pid })
+            }
+            Response::Output {
+                line,
+                is_stderr,
+                elapsed_ms,
+            } => batch.push(BuildEvent::Output(protocol::OutputLine {
+                line,
+                is_stderr,
+                elapsed_ms,
+            })),
+            Response::OutputBatch { lines } => batch.extend(lines.into_iter().map(BuildEvent::Output)),
+            Response::BuildComplete {
+                exit_code,
+                duration_ms,
+                stdout_lines,
+                stderr_lines,
+                signal,
+                queued_ms,
+                ..
+            } => {
+                outcome = Some(BuildOutcome::Completed {
+                    exit_code,
+                    duration_ms,
+                    stdout_lines,
+                    stderr_lines,
+                    signal,
+                    queued_ms,
+                })
+            }
+            Response::Cancelled => outcome = Some(BuildOutcome::Cancelled),
+            Response::Timeout { elapsed_secs } => outcome = Some(BuildOutcome::TimedOut { elapsed_secs }),
+            Response::Stalled { idle_secs } => outcome = Some(BuildOutcome::Stalled { idle_secs }),
+            Response::Error { message } | Response::NotReady { reason: message } => {
+                outcome = Some(BuildOutcome::Failed { message })
+            }
+            Response::Unauthorized => {
+                outcome = Some(BuildOutcome::Failed {
+                    message: UNAUTHORIZED_MESSAGE.to_string(),
+                })
+            }
+            _ => {}
+        }
+
+        let finished = outcome.is_some();
+        batch.extend(outcome.map(BuildEvent::Finished));
+        for event in batch {
+            if events.send(Ok(event)).await.is_err() {
+                return;
+            }
+        }
+        if finished {
+            return;
+        }
+    }
+}
 
 /// Output line with metadata for truncation
 struct OutputLine {
     content: String,
     is_stderr: bool,
+    /// Milliseconds since the build started, as measured by the server
+    elapsed_ms: u64,
+    /// Left out by `--grep`, but still held by a [`TruncatingBuffer`] so it
+    /// counts toward the limits (unless `--filter-before-truncate`)
+    hidden: bool,
 }
 
-/// Smart output buffer that keeps first N/2 and last N/2 lines
+/// Smart output buffer that keeps first N/2 and last N/2 lines, plus the
+/// error lines from the part in between. `--max-bytes` caps both halves by
+/// size the same way.
 struct TruncatingBuffer {
-    max_lines: usize,
+    /// Lines and bytes are held back and may be truncated at all
+    limited: bool,
     head: Vec<OutputLine>,
     tail: VecDeque<OutputLine>,
     total_count: usize,
-    head_limit: usize,
-    tail_limit: usize,
+    head_limit: Limit,
+    tail_limit: Limit,
+    head_bytes: usize,
+    tail_bytes: usize,
+    /// Room for error lines from the truncated middle
+    kept_limit: Limit,
+    kept_bytes: usize,
+    /// Bytes of the lines truncated, for the marker
+    skipped_bytes: usize,
+    /// Cut lines longer than this many bytes (0 = never)
+    max_line_length: usize,
+    /// Prefix each line with its `[+1.234s]` offset from the build start
+    timestamps: bool,
+    /// Which streams get severity highlighting
+    colors: Colors,
+    /// Print nothing as lines arrive, only at `finish` (`--quiet` and
+    /// `--live-tail`)
+    deferred: bool,
+    /// `--live-tail`, redrawn as lines arrive while everything else waits
+    live: Option<LiveTail>,
+    /// `--error-pattern`, replacing [`highlight::severity`]'s idea of an error
+    error_pattern: Option<Regex>,
+    /// Error lines that fell out of the buffer, in order, each marked with
+    /// whether lines were truncated just before it
+    kept: Vec<(bool, OutputLine)>,
+    /// Whether lines were truncated since the last kept one
+    truncated_since_kept: bool,
+}
+
+/// How many lines, and how many bytes of them, one part of a
+/// [`TruncatingBuffer`] holds
+#[derive(Clone, Copy)]
+struct Limit {
+    lines: usize,
+    bytes: usize,
+}
+
+impl Limit {
+    fn allows(self, lines: usize, bytes: usize) -> bool {
+        lines <= self.lines && bytes <= self.bytes
+    }
+}
+
+/// Split a `max` (0 = unlimited) into the share kept from the start and the
+/// share kept from the end
+fn split_limit(max: usize, keep_tail_ratio: f64) -> (usize, usize) {
+    if max == 0 {
+        return (usize::MAX, usize::MAX);
+    }
+    let mut tail_limit = (max as f64 * keep_tail_ratio).round() as usize;
+    // A ratio short of 0 or 1 keeps at least one line at that end, even
+    // when it rounds away on a small `max_lines`
+    if keep_tail_ratio > 0.0 {
+        tail_limit = tail_limit.max(1);
+    }
+    if keep_tail_ratio < 1.0 {
+        tail_limit = tail_limit.min(max.saturating_sub(1));
+    }
+    let tail_limit = tail_limit.min(max);
+    (max - tail_limit, tail_limit)
+}
+
+/// `--live-tail`: the latest lines of output, redrawn in place at the
+/// bottom of the terminal instead of scrolling by
+struct LiveTail {
+    rows: usize,
+    lines: VecDeque<String>,
+    /// Terminal rows taken by the last draw, to move back over
+    drawn: u16,
+    /// Lines pushed since the last draw
+    dirty: bool,
+}
+
+impl LiveTail {
+    fn new(rows: usize) -> Self {
+        Self {
+            rows,
+            lines: VecDeque::with_capacity(rows + 1),
+            drawn: 0,
+            dirty: false,
+        }
+    }
+
+    fn push(&mut self, line: &str) {
+        self.lines.push_back(line.to_string());
+        if self.lines.len() > self.rows {
+            self.lines.pop_front();
+        }
+        self.dirty = true;
+    }
+
+    /// Replace the last draw with the current lines, each cut to the
+    /// terminal's width so none wraps onto a row that won't be cleared
+    fn draw(&mut self) -> std::io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        self.dirty = false;
+        // Some ptys report a size of 0x0
+        let (columns, rows) = match terminal::size() {
+            Ok((columns, rows)) if columns > 0 && rows > 0 => (columns, rows),
+            _ => (80, 24),
+        };
+        let width = usize::from(columns.saturating_sub(1)).max(1);
+        let skip = self.lines.len().saturating_sub(usize::from(rows.saturating_sub(1)));
+
+        let mut out = std::io::stdout().lock();
+        self.erase(&mut out)?;
+        for line in self.lines.iter().skip(skip) {
+            let line: String = line
+                .chars()
+                .map(|c| if c.is_control() { ' ' } else { c })
+                .take(width)
+                .collect();
+            writeln!(out, "{}", line)?;
+            self.drawn += 1;
+        }
+        out.flush()
+    }
+
+    /// Take the view off the screen for good
+    fn clear(&mut self) -> std::io::Result<()> {
+        let mut out = std::io::stdout().lock();
+        self.erase(&mut out)?;
+        out.flush()
+    }
+
+    fn erase(&mut self, out: &mut impl Write) -> std::io::Result<()> {
+        if self.drawn > 0 {
+            out.queue(cursor::MoveToPreviousLine(self.drawn))?;
+            out.queue(terminal::Clear(terminal::ClearType::FromCursorDown))?;
+            self.drawn = 0;
+        }
+        Ok(())
+    }
+}
+
+/// How bad `line` is, with `--error-pattern` (when given) deciding what
+/// counts as an error
+fn severity(error_pattern: Option<&Regex>, line: &str) -> highlight::Severity {
+    match highlight::severity(line) {
+        _ if error_pattern.is_some_and(|pattern| pattern.is_match(line)) => highlight::Severity::Error,
+        highlight::Severity::Error if error_pattern.is_some() => highlight::Severity::Normal,
+        severity => severity,
+    }
+}
+
+/// Error and warning lines in a build's output, for the summary
+#[derive(Default)]
+struct Tally {
+    errors: u64,
+    warnings: u64,
+}
+
+impl Tally {
+    fn count(&mut self, severity: highlight::Severity) {
+        match severity {
+            highlight::Severity::Error => self.errors += 1,
+            highlight::Severity::Warning => self.warnings += 1,
+            highlight::Severity::Normal => {}
+        }
+    }
+}
+
+/// Cut `line` down to at most `max` bytes (on a character boundary), saying
+/// how much was left out
+fn shorten_line(line: &mut String, max: usize) {
+    if line.len() <= max {
+        return;
+    }
+    let mut end = max;
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+    let cut = line.len() - end;
+    line.truncate(end);
+    line.push_str(&format!("… [{} more]", format_bytes(cut as u64)));
 }
 
 impl TruncatingBuffer {
-    fn new(max_lines: usize) -> Self {
-        let head_limit = max_lines / 2;
-        let tail_limit = max_lines - head_limit;
+    fn new(options: &RunOptions, colors: Colors) -> Self {
+        let (head_lines, tail_lines) = split_limit(options.max_lines, options.keep_tail_ratio);
+        let (head_bytes, tail_bytes) = split_limit(options.max_bytes, options.keep_tail_ratio);
+        let (head_limit, tail_limit) = if options.quiet {
+            // Everything the limits allow comes from the end, as a failed
+            // build's last lines tell the most
+            let tail_limit = Limit {
+                lines: head_lines.saturating_add(tail_lines),
+                bytes: head_bytes.saturating_add(tail_bytes),
+            };
+            (Limit { lines: 0, bytes: 0 }, tail_limit)
+        } else {
+            (
+                Limit {
+                    lines: head_lines,
+                    bytes: head_bytes,
+                },
+                Limit {
+                    lines: tail_lines,
+                    bytes: tail_bytes,
+                },
+            )
+        };
+        // Only on a terminal; elsewhere output streams by as usual
+        let live = (options.live_tail > 0 && std::io::stdout().is_terminal())
+            .then(|| LiveTail::new(options.live_tail));
+        let deferred = options.quiet || live.is_some();
+        let kept_limit = Limit {
+            lines: if options.max_lines == 0 { usize::MAX } else { options.max_lines },
+            bytes: if options.max_bytes == 0 { usize::MAX } else { options.max_bytes },
+        };
         Self {
-            max_lines,
-            head: Vec::with_capacity(head_limit),
-            tail: VecDeque::with_capacity(tail_limit + 1),
+            limited: options.max_lines > 0 || options.max_bytes > 0 || deferred,
+            head: Vec::new(),
+            tail: VecDeque::new(),
             total_count: 0,
             head_limit,
             tail_limit,
+            head_bytes: 0,
+            tail_bytes: 0,
+            kept_limit,
+            kept_bytes: 0,
+            skipped_bytes: 0,
+            max_line_length: options.max_line_length,
+            timestamps: options.timestamps,
+            colors,
+            deferred,
+            live,
+            error_pattern: options.error_pattern.clone(),
+            kept: Vec::new(),
+            truncated_since_kept: false,
         }
     }
 
-    fn push(&mut self, line: OutputLine) {
+    fn push(&mut self, mut line: OutputLine) {
         self.total_count += 1;
+        if self.max_line_length > 0 {
+            shorten_line(&mut line.content, self.max_line_length);
+        }
+        if let Some(live) = self.live.as_mut().filter(|_| !line.hidden) {
+            live.push(&line.content);
+        }
 
-        if self.max_lines == 0 {
+        if !self.limited {
             // No truncation - print immediately
-            Self::print_line(&line);
+            self.print_line(&line);
             return;
         }
 
-        if self.head.len() < self.head_limit {
+        let size = line.content.len();
+        if self.tail.is_empty()
+            && self.head_limit.allows(self.head.len() + 1, self.head_bytes + size)
+        {
             // Still filling head buffer - print and store
-            Self::print_line(&line);
-            self.head.push(line);
-        } else {
-            // Head is full, add to tail ring buffer
-            if self.tail.len() >= self.tail_limit {
-                self.tail.pop_front();
+            if !self.deferred {
+                self.print_line(&line);
             }
-            self.tail.push_back(line);
+            self.head_bytes += size;
+            self.head.push(line);
+            return;
+        }
+
+        // Head is full, add to tail ring buffer
+        self.tail_bytes += size;
+        self.tail.push_back(line);
+        while !self.tail_limit.allows(self.tail.len(), self.tail_bytes) {
+            let dropped = self.tail.pop_front().unwrap();
+            self.tail_bytes -= dropped.content.len();
+            self.truncate(dropped);
         }
     }
 
-    fn finish(self) {
-        if self.max_lines == 0 {
-            return;
+    /// Drop `line` from the middle of the output, unless it's an error:
+    /// those are kept (up to `max_lines` of them, the earliest first) since
+    /// the error that broke a build is so often buried in the middle.
+    ///
+    /// Being on stderr doesn't make a line an error here. cargo, make and
+    /// most compilers write their progress there too, and those lines would
+    /// use up the room before the error that matters came along.
+    fn truncate(&mut self, line: OutputLine) {
+        let size = line.content.len();
+        if !line.hidden
+            && self.kept_limit.allows(self.kept.len() + 1, self.kept_bytes + size)
+            && self.is_error(&line.content)
+        {
+            self.kept_bytes += size;
+            self.kept.push((self.truncated_since_kept, line));
+            self.truncated_since_kept = false;
+        } else {
+            self.skipped_bytes += size;
+            self.truncated_since_kept = true;
         }
+    }
 
-        let skipped = self.total_count.saturating_sub(self.head.len() + self.tail.len());
+    /// Count lines the server left out (`--server-truncate`) as truncated
+    fn skip(&mut self, lines: u64, bytes: u64) {
+        self.total_count += lines as usize;
+        self.skipped_bytes += bytes as usize;
+        self.truncated_since_kept = true;
+    }
 
-        if skipped > 0 {
-            eprintln!();
-            eprintln!("... [{} lines truncated] ...", skipped);
-            eprintln!();
+    fn is_error(&self, content: &str) -> bool {
+        severity(self.error_pattern.as_ref(), content) == highlight::Severity::Error
+    }
+
+    /// Lines left out of the display so far
+    fn truncated(&self) -> usize {
+        if !self.limited {
+            return 0;
+        }
+        self.total_count - self.head.len() - self.kept.len() - self.tail.len()
+    }
 
-            // Print the tail (wasn't printed in real-time)
-            for line in self.tail {
-                Self::print_line(&line);
+    /// Bring the `--live-tail` view up to date with the lines pushed so far
+    fn redraw(&mut self) -> Result<()> {
+        if let Some(ref mut live) = self.live {
+            live.draw()?;
+        }
+        Ok(())
+    }
+
+    /// Print the lines held back so far, in place of the `--live-tail`
+    /// view. A quiet build's caller skips this when it succeeds.
+    fn finish(mut self) -> Result<()> {
+        if let Some(mut live) = self.live.take() {
+            live.clear()?;
+        }
+        if !self.limited {
+            return Ok(());
+        }
+        if self.deferred {
+            for line in &self.head {
+                self.print_line(line);
             }
-        } else if self.total_count > self.head.len() {
-            // No truncation but we have tail lines that weren't printed
-            for line in self.tail {
-                Self::print_line(&line);
+        }
+        let skipped = self.truncated();
+        let mut first_gap = true;
+        for (truncated_before, line) in &self.kept {
+            if *truncated_before {
+                self.print_gap(&mut first_gap, skipped);
             }
+            self.print_line(line);
+        }
+        if self.truncated_since_kept {
+            self.print_gap(&mut first_gap, skipped);
+        }
+
+        // Print the tail (wasn't printed in real-time)
+        for line in &self.tail {
+            self.print_line(line);
+        }
+        Ok(())
+    }
+
+    /// Mark where lines were truncated: the first time with the totals,
+    /// after that (between kept error lines) with a bare `...`
+    fn print_gap(&self, first: &mut bool, skipped: usize) {
+        if !*first {
+            eprintln!("...");
+            return;
+        }
+        *first = false;
+        if !self.head.is_empty() {
+            eprintln!();
         }
+        let kept = match self.kept.len() {
+            0 => String::new(),
+            1 => ", 1 error line kept".to_string(),
+            n => format!(", {} error lines kept", n),
+        };
+        eprintln!(
+            "... [{} lines ({}) truncated{}] ...",
+            skipped,
+            format_bytes(self.skipped_bytes as u64),
+            kept
+        );
+        eprintln!();
     }
 
-    fn print_line(line: &OutputLine) {
+    fn print_line(&self, line: &OutputLine) {
+        if line.hidden {
+            return;
+        }
+        let prefix = if self.timestamps {
+            format!("[+{:.3}s] ", line.elapsed_ms as f64 / 1000.0)
+        } else {
+            String::new()
+        };
+
+        let text = format!("{}{}", prefix, line.content);
+        let style = match severity(self.error_pattern.as_ref(), &line.content) {
+            highlight::Severity::Error => highlight::RED,
+            highlight::Severity::Warning => highlight::YELLOW,
+            // Dimming stderr sets it apart without drowning out stdout
+            highlight::Severity::Normal if line.is_stderr => highlight::DIM,
+            highlight::Severity::Normal => "",
+        };
         if line.is_stderr {
-            eprintln!("{}", line.content);
+            eprintln!("{}", highlight::paint(&text, style, self.colors.stderr));
         } else {
-            println!("{}", line.content);
+            println!("{}", highlight::paint(&text, style, self.colors.stdout));
         }
     }
 }
 
-pub async fn run_build(dir: PathBuf, command: String, port: u16, max_lines: usize) -> Result<()> {
-    let mut stream = TcpStream::connect(format!("127.0.0.1:{}", port))
-        .await
-        .context(format!(
-            "Failed to connect to build server on port {}. Is the server running?",
-            port
-        ))?;
+/// How often and how many times `stop` checks that the server has gone away
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const STOP_POLL_ATTEMPTS: u32 = 50;
 
-    let request = Request::Build { dir, command };
-    send_request(&mut stream, &request).await?;
+/// Exit code used when the server killed the build for exceeding its timeout
+const TIMEOUT_EXIT_CODE: i32 = 124;
 
-    let (reader, _) = stream.split();
-    let mut reader = BufReader::new(reader);
-    let mut line = String::new();
+/// Exit code used when the server killed the build for going silent
+const STALLED_EXIT_CODE: i32 = 125;
 
-    let mut exit_code = 0;
-    let mut buffer = TruncatingBuffer::new(max_lines);
+/// Exit codes of `status` when the server can't take builds, so scripts
+/// can branch on them (0 means running and initialized)
+const STATUS_NOT_INITIALIZED: i32 = 3;
+const STATUS_NOT_RUNNING: i32 = 4;
 
-    loop {
-        line.clear();
-        let n = reader.read_line(&mut line).await?;
-        if n == 0 {
-            break;
+const UNAUTHORIZED_MESSAGE: &str =
+    "The build server rejected the request: wrong or missing --auth-token";
+
+/// Untruncated copy of the build output, written as lines arrive.
+///
+/// Unless appending, each file is written as `<name>.partial` and only
+/// renamed once the build has ended, so a log left by a client that was
+/// killed or lost its terminal is easy to tell apart.
+struct BuildLog {
+    writer: BufWriter<File>,
+    /// `--log-stderr`; stderr lines are marked in `writer` otherwise
+    stderr: Option<BufWriter<File>>,
+    /// Partial files and the names they get once the log is complete
+    renames: Vec<(PathBuf, PathBuf)>,
+}
+
+impl BuildLog {
+    /// The log `options` ask for, if any
+    fn open(options: &RunOptions) -> Result<Option<Self>> {
+        let Some(ref path) = options.log_file else {
+            return Ok(None);
+        };
+        let mut renames = Vec::new();
+        let writer = open_log_file(path, options.log_append, &mut renames)?;
+        let stderr = match options.log_stderr {
+            Some(ref path) => Some(open_log_file(path, options.log_append, &mut renames)?),
+            None => None,
+        };
+        Ok(Some(Self {
+            writer,
+            stderr,
+            renames,
+        }))
+    }
+
+    fn write_line(&mut self, line: &OutputLine) -> Result<()> {
+        match self.stderr {
+            Some(ref mut stderr) if line.is_stderr => writeln!(stderr, "{}", line.content)?,
+            None if line.is_stderr => writeln!(self.writer, "[stderr] {}", line.content)?,
+            _ => writeln!(self.writer, "{}", line.content)?,
         }
+        Ok(())
+    }
 
-        let response: Response = serde_json::from_str(&line)?;
+    /// Write the trailing result line, flush everything to disk and give
+    /// the files their final names
+    fn finish(&mut self, result: &str) -> Result<()> {
+        writeln!(self.writer, "{}", result)?;
+        self.writer.flush()?;
+        if let Some(ref mut stderr) = self.stderr {
+            stderr.flush()?;
+        }
+        for (partial, path) in self.renames.drain(..) {
+            std::fs::rename(&partial, &path)
+                .with_context(|| format!("Failed to rename {} to {}", partial.display(), path.display()))?;
+        }
+        Ok(())
+    }
+}
 
-        match response {
-            Response::Output {
-                line: content,
-                is_stderr,
-            } => {
-                buffer.push(OutputLine { content, is_stderr });
-            }
-            Response::BuildComplete { exit_code: code } => {
-                exit_code = code;
-                break;
+/// Open one file of a [`BuildLog`]: `path` itself when appending, otherwise
+/// `<path>.partial`, noted in `renames`
+fn open_log_file(
+    path: &Path,
+    append: bool,
+    renames: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<BufWriter<File>> {
+    let file = if append {
+        std::fs::OpenOptions::new().create(true).append(true).open(path)
+    } else {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+        let file = File::create(&partial);
+        renames.push((partial, path.to_path_buf()));
+        file
+    };
+    let file = file.with_context(|| format!("Failed to create log file {}", path.display()))?;
+    Ok(BufWriter::new(file))
+}
+
+/// How a build request ended
+#[derive(Debug, Clone)]
+pub enum BuildOutcome {
+    Completed {
+        exit_code: i32,
+        duration_ms: u64,
+        stdout_lines: u64,
+        stderr_lines: u64,
+        /// Unix signal that killed the build process, if that's how it ended
+        signal: Option<i32>,
+        /// Time the build waited on the server before starting
+        queued_ms: u64,
+    },
+    Cancelled,
+    TimedOut {
+        elapsed_secs: u64,
+    },
+    Stalled {
+        idle_secs: u64,
+    },
+    /// The server refused or could not run the build
+    Failed {
+        message: String,
+    },
+}
+
+impl BuildOutcome {
+    /// What `build-runner run` exits with for this outcome
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            BuildOutcome::Completed { exit_code, .. } => *exit_code,
+            BuildOutcome::Cancelled | BuildOutcome::Failed { .. } => 1,
+            BuildOutcome::TimedOut { .. } => TIMEOUT_EXIT_CODE,
+            BuildOutcome::Stalled { .. } => STALLED_EXIT_CODE,
+        }
+    }
+
+    fn json_event(&self) -> JsonEvent<'_> {
+        match self {
+            BuildOutcome::Completed {
+                exit_code,
+                duration_ms,
+                stdout_lines,
+                stderr_lines,
+                signal,
+                queued_ms,
+            } => JsonEvent::Complete {
+                exit_code: *exit_code,
+                duration_ms: *duration_ms,
+                stdout_lines: *stdout_lines,
+                stderr_lines: *stderr_lines,
+                signal: signal.map(signal_name),
+                queued_ms: *queued_ms,
+            },
+            BuildOutcome::Cancelled => JsonEvent::Cancelled,
+            BuildOutcome::TimedOut { elapsed_secs } => JsonEvent::Timeout {
+                elapsed_secs: *elapsed_secs,
+            },
+            BuildOutcome::Stalled { idle_secs } => JsonEvent::Stalled {
+                idle_secs: *idle_secs,
+            },
+            BuildOutcome::Failed { message } => JsonEvent::Error { message },
+        }
+    }
+}
+
+/// Trailing line of the `--log-file`
+impl fmt::Display for BuildOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildOutcome::Completed {
+                signal: Some(signal),
+                duration_ms,
+                ..
+            } => write!(
+                f,
+                "killed by {} after {}",
+                signal_name(*signal),
+                format_duration_ms(*duration_ms)
+            ),
+            BuildOutcome::Completed {
+                exit_code,
+                duration_ms,
+                ..
+            } => write!(f, "exit code: {} after {}", exit_code, format_duration_ms(*duration_ms)),
+            BuildOutcome::Cancelled => write!(f, "build cancelled"),
+            BuildOutcome::TimedOut { elapsed_secs } => {
+                write!(f, "build timed out after {} seconds", elapsed_secs)
             }
-            Response::Error { message } => {
-                eprintln!("Error: {}", message);
-                std::process::exit(1);
+            BuildOutcome::Stalled { idle_secs } => {
+                write!(f, "build killed after {} seconds without output", idle_secs)
             }
-            _ => {}
+            BuildOutcome::Failed { message } => write!(f, "error: {}", message),
         }
     }
+}
 
-    buffer.finish();
+/// Whether build output is colored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// Color each stream that is a terminal, unless `NO_COLOR` is set
+    Auto,
+    Always,
+    Never,
+}
 
-    if exit_code != 0 {
-        eprintln!("\nBuild failed with exit code: {}", exit_code);
+impl ColorChoice {
+    fn colors(self) -> Colors {
+        match self {
+            ColorChoice::Auto => {
+                let allowed = std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty());
+                Colors {
+                    stdout: allowed && std::io::stdout().is_terminal(),
+                    stderr: allowed && std::io::stderr().is_terminal(),
+                }
+            }
+            ColorChoice::Always => Colors {
+                stdout: true,
+                stderr: true,
+            },
+            ColorChoice::Never => Colors {
+                stdout: false,
+                stderr: false,
+            },
+        }
     }
+}
 
-    std::process::exit(exit_code);
+/// Which of the client's streams [`ColorChoice`] settled on coloring, so
+/// piping stdout into a file keeps it plain while stderr on the terminal
+/// stays colored
+#[derive(Debug, Clone, Copy)]
+struct Colors {
+    stdout: bool,
+    stderr: bool,
 }
 
-pub async fn check_status(port: u16) -> Result<()> {
-    let mut stream = match TcpStream::connect(format!("127.0.0.1:{}", port)).await {
-        Ok(s) => s,
-        Err(_) => {
-            println!("Build server is NOT running on port {}", port);
-            return Ok(());
-        }
-    };
+/// Picking out compiler errors and warnings in build output
+mod highlight {
+    use regex::Regex;
+    use std::sync::LazyLock;
 
-    send_request(&mut stream, &Request::Status).await?;
+    pub const RED: &str = "31";
+    pub const YELLOW: &str = "33";
+    pub const GREEN: &str = "32";
+    pub const DIM: &str = "2";
 
-    let (reader, _) = stream.split();
-    let mut reader = BufReader::new(reader);
-    let mut line = String::new();
-    reader.read_line(&mut line).await?;
+    /// `error: ...` and `fatal error: ...` (gcc, clang, rustc, make), rustc's
+    /// `error[E0308]`, MSVC's `error C2065:` / `error LNK2019:`, and test
+    /// runners' `FAILED`
+    static ERROR: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(
+            r"(?i:\berror\[|(?:^|: )\s*(?:fatal )?error\s*:|\bfatal:)|\berror [A-Z]{1,4}\d{3,5}\b|\bFAILED\b",
+        )
+        .unwrap()
+    });
 
-    let response: Response = serde_json::from_str(&line)?;
+    /// The same shapes for warnings: `warning:`, `warning[...]`, `warning C4996:`
+    static WARNING: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"(?i:\bwarning\[|(?:^|: )\s*warning\s*:)|\bwarning [A-Z]{1,4}\d{3,5}\b")
+            .unwrap()
+    });
 
-    match response {
-        Response::Status {
-            initialized,
-            init_script,
-        } => {
-            println!("Build server is running on port {}", port);
-            println!("  Initialized: {}", initialized);
-            if let Some(script) = init_script {
-                println!("  Init script: {}", script);
-            }
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Severity {
+        Error,
+        Warning,
+        Normal,
+    }
+
+    pub fn severity(line: &str) -> Severity {
+        if ERROR.is_match(line) {
+            Severity::Error
+        } else if WARNING.is_match(line) {
+            Severity::Warning
+        } else {
+            Severity::Normal
         }
-        _ => {
-            println!("Unexpected response from server");
+    }
+
+    /// Wrap `text` in the ANSI codes for `style` when `color` is on
+    pub fn paint(text: &str, style: &str, color: bool) -> String {
+        if color && !style.is_empty() {
+            format!("\x1b[{}m{}\x1b[0m", style, text)
+        } else {
+            text.to_string()
         }
     }
+}
+
+/// How commands render what they report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable output, with build output truncated to `--max-lines`
+    Text,
+    /// One JSON object per line for every event, never truncated
+    Json,
+}
+
+/// One line of `--output json`
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsonEvent<'a> {
+    Queued {
+        build_id: BuildId,
+        position: usize,
+    },
+    Started {
+        build_id: BuildId,
+        pid: Option<u32>,
+    },
+    /// `run --detach` returned; the build carries on without this client
+    Detached {
+        build_id: BuildId,
+    },
+    Output {
+        stream: &'a str,
+        line: &'a str,
+        elapsed_ms: u64,
+    },
+    Complete {
+        exit_code: i32,
+        duration_ms: u64,
+        stdout_lines: u64,
+        stderr_lines: u64,
+        /// e.g. `SIGKILL` when a signal ended the build
+        signal: Option<String>,
+        queued_ms: u64,
+    },
+    Cancelled,
+    Timeout {
+        elapsed_secs: u64,
+    },
+    Stalled {
+        idle_secs: u64,
+    },
+    Error {
+        message: &'a str,
+    },
+    /// `status`; the server's fields are left out when it can't be reached
+    Status {
+        reachable: bool,
+        #[serde(flatten)]
+        status: Option<&'a ServerStatus>,
+    },
+    /// A running build, one per line for `list`
+    Build(&'a BuildSummary),
+    /// A finished build, one per line for `history`
+    Finished(&'a HistoryEntry),
+}
 
+fn print_json(event: &JsonEvent) -> Result<()> {
+    println!("{}", serde_json::to_string(event)?);
     Ok(())
 }
 
-pub async fn stop_server(port: u16) -> Result<()> {
-    let mut stream = match TcpStream::connect(format!("127.0.0.1:{}", port)).await {
-        Ok(s) => s,
-        Err(_) => {
-            println!("Build server is not running on port {}", port);
-            return Ok(());
-        }
-    };
+/// Report an error that ended a command as a JSON `error` event on stderr,
+/// for `--output json`
+pub fn print_json_error(error: &anyhow::Error) {
+    let message = format!("{:#}", error);
+    let event = JsonEvent::Error { message: &message };
+    match serde_json::to_string(&event) {
+        Ok(line) => eprintln!("{}", line),
+        Err(_) => eprintln!("Error: {}", message),
+    }
+}
 
-    send_request(&mut stream, &Request::Stop).await?;
+/// Options for `run` beyond the directory and command
+pub struct RunOptions {
+    /// Maximum lines to display (0 = unlimited)
+    pub max_lines: usize,
+    /// Maximum bytes to display, counted like `max_lines` (0 = unlimited)
+    pub max_bytes: usize,
+    /// Cut displayed lines longer than this many bytes (0 = never)
+    pub max_line_length: usize,
+    /// Have the server leave out the middle of the output rather than
+    /// sending every line
+    pub server_truncate: bool,
+    /// Keep only this many of the latest lines on screen, redrawn in place
+    /// (0 = off)
+    pub live_tail: usize,
+    /// Print the closing line with the result, timings and line counts
+    pub summary: bool,
+    /// Share of `max_lines` kept from the end when truncating
+    pub keep_tail_ratio: f64,
+    pub timeout: Option<u64>,
+    pub timestamps: bool,
+    /// Report build bookkeeping such as the build id
+    pub verbose: bool,
+    pub env: Vec<(String, String)>,
+    /// Write the complete output here regardless of truncation
+    pub log_file: Option<PathBuf>,
+    /// Write stderr lines here instead of marking them in `log_file`
+    pub log_stderr: Option<PathBuf>,
+    /// Add to the log files rather than replacing them
+    pub log_append: bool,
+    pub format: OutputFormat,
+    /// Severity highlighting of text output
+    pub color: ColorChoice,
+    /// Resolve `dir` locally before sending it (`--no-canonicalize` turns this off)
+    pub canonicalize: bool,
+    /// Let the build carry on if this client goes away
+    pub keep_running: bool,
+    /// Return as soon as the server has assigned a build id
+    pub detach: bool,
+    /// Ask the server what it would run instead of running it
+    pub dry_run: bool,
+    /// Local file sent to the build as its stdin
+    pub stdin_file: Option<PathBuf>,
+    /// Shell the server should run the command with instead of its own
+    pub shell: Option<String>,
+    /// If the server's init script is being re-run, wait for it instead
+    /// of failing
+    pub wait_for_ready: bool,
+    /// Only display output lines matching this
+    pub grep: Option<Regex>,
+    /// Display the lines that don't match `grep` instead
+    pub grep_invert: bool,
+    /// Lines shown around each `grep` match, like `grep -C`
+    pub context: usize,
+    /// Leave the lines `grep` hides out of `max_lines` and `max_bytes`,
+    /// rather than truncating the whole output and then filtering it
+    pub filter_before_truncate: bool,
+    /// What counts as an error line, kept through truncation
+    /// (`None` = compiler-style errors)
+    pub error_pattern: Option<Regex>,
+    /// Print nothing unless the build fails, then the last `max_lines` of
+    /// its output
+    pub quiet: bool,
+}
 
-    let (reader, _) = stream.split();
-    let mut reader = BufReader::new(reader);
-    let mut line = String::new();
-    reader.read_line(&mut line).await?;
+impl Default for RunOptions {
+    /// `run` with truncation off: every line is shown, colored when the
+    /// terminal supports it, and a summary closes the output
+    fn default() -> Self {
+        RunOptions {
+            max_lines: 0,
+            max_bytes: 0,
+            max_line_length: 0,
+            server_truncate: false,
+            live_tail: 0,
+            summary: true,
+            keep_tail_ratio: 0.5,
+            timeout: None,
+            timestamps: false,
+            verbose: false,
+            env: Vec::new(),
+            log_file: None,
+            log_stderr: None,
+            log_append: false,
+            format: OutputFormat::Text,
+            color: ColorChoice::Auto,
+            canonicalize: true,
+            keep_running: false,
+            detach: false,
+            dry_run: false,
+            stdin_file: None,
+            shell: None,
+            wait_for_ready: false,
+            grep: None,
+            grep_invert: false,
+            context: 0,
+            filter_before_truncate: false,
+            error_pattern: None,
+            quiet: false,
+        }
+    }
+}
 
-    let response: Response = serde_json::from_str(&line)?;
+/// `--grep` and its `--context`: which output lines make it to the display
+struct LineFilter<'a> {
+    grep: Option<&'a Regex>,
+    invert: bool,
+    context: usize,
+    /// Put grep's `--` between groups of lines that aren't adjacent
+    separators: bool,
+    /// The last few hidden lines, shown after all if a match follows
+    before: VecDeque<OutputLine>,
+    /// Lines still to be shown after the last match
+    after: usize,
+    /// Whether a line was dropped since the last one shown
+    gap: bool,
+    shown_any: bool,
+    matched: usize,
+    hidden: usize,
+}
 
-    match response {
-        Response::Stopping => {
-            println!("Build server is stopping...");
+impl<'a> LineFilter<'a> {
+    fn new(options: &'a RunOptions) -> Self {
+        Self {
+            grep: options.grep.as_ref(),
+            invert: options.grep_invert,
+            context: options.context,
+            separators: options.context > 0 && options.format == OutputFormat::Text,
+            before: VecDeque::with_capacity(options.context + 1),
+            after: 0,
+            gap: false,
+            shown_any: false,
+            matched: 0,
+            hidden: 0,
         }
-        _ => {
-            println!("Unexpected response from server");
+    }
+
+    /// The lines to display now that `line` has arrived: none, `line`
+    /// itself, or `line` after the context lines held back for it. Lines
+    /// that can no longer be context come back too, marked hidden, so they
+    /// reach the [`TruncatingBuffer`] in order.
+    fn filter(&mut self, line: OutputLine) -> Vec<OutputLine> {
+        let Some(grep) = self.grep else {
+            return vec![line];
+        };
+        if grep.is_match(&line.content) != self.invert {
+            self.matched += 1;
+            self.after = self.context;
+            let mut shown = Vec::with_capacity(self.before.len() + 2);
+            if self.gap && self.shown_any && self.separators {
+                shown.push(OutputLine {
+                    content: "--".to_string(),
+                    is_stderr: false,
+                    elapsed_ms: line.elapsed_ms,
+                    hidden: false,
+                });
+            }
+            shown.extend(self.before.drain(..));
+            shown.push(line);
+            self.gap = false;
+            self.shown_any = true;
+            shown
+        } else if self.after > 0 {
+            self.after -= 1;
+            vec![line]
+        } else {
+            self.before.push_back(line);
+            if self.before.len() <= self.context {
+                return Vec::new();
+            }
+            let mut dropped = self.before.pop_front().unwrap();
+            dropped.hidden = true;
+            self.hidden += 1;
+            self.gap = true;
+            vec![dropped]
         }
     }
 
-    Ok(())
+    /// The lines still held back as context once the output has ended,
+    /// marked hidden
+    fn finish(&mut self) -> Vec<OutputLine> {
+        self.hidden += self.before.len();
+        self.before
+            .drain(..)
+            .map(|line| OutputLine { hidden: true, ..line })
+            .collect()
+    }
+
+    /// Lines never shown, counting context still held back
+    fn hidden(&self) -> usize {
+        self.hidden + self.before.len()
+    }
 }
 
-async fn send_request(stream: &mut TcpStream, request: &Request) -> Result<()> {
-    let json = serde_json::to_string(request)?;
-    stream.write_all(json.as_bytes()).await?;
-    stream.write_all(b"\n").await?;
-    stream.flush().await?;
-    Ok(())
+/// What Ctrl+C does while following a build
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OnInterrupt {
+    /// Ask the server to cancel the build
+    CancelBuild,
+    /// Stop watching and leave the build running
+    Detach,
+}
+
+/// Turn the `--dir` argument into the absolute path the server should use.
+///
+/// The server resolves relative paths against its own working directory, so
+/// `-d .` has to be expanded here to mean the caller's directory.
+fn resolve_build_dir(dir: &Path) -> Result<PathBuf> {
+    let expanded = match dir.strip_prefix("~") {
+        Ok(rest) => dirs::home_dir()
+            .context("Cannot expand '~': home directory is unknown")?
+            .join(rest),
+        Err(_) => dir.to_path_buf(),
+    };
+    // dunce avoids the `\\?\` prefix std adds on Windows, which cmd can't handle
+    let resolved = dunce::canonicalize(&expanded)
+        .with_context(|| format!("Build directory does not exist: {}", dir.display()))?;
+    if !resolved.is_dir() {
+        bail!("Build path is not a directory: {}", dir.display());
+    }
+    Ok(resolved)
+}
+
+pub async fn run_build(
+    dir: PathBuf,
+    command: BuildCommand,
+    endpoint: &Endpoint,
+    options: RunOptions,
+) -> Result<i32> {
+    let dir = if options.canonicalize {
+        resolve_build_dir(&dir)?
+    } else {
+        dir
+    };
+
+    let log = BuildLog::open(&options)?;
+    let stdin = match options.stdin_file {
+        Some(ref path) => Some(
+            std::fs::read(path)
+                .with_context(|| format!("Failed to read --stdin-file {}", path.display()))?,
+        ),
+        None => None,
+    };
+
+    if options.wait_for_ready {
+        wait_until_ready(endpoint, READY_POLL_INTERVAL, false).await?;
+    }
+
+    let mut stream = endpoint.connect().await?;
+
+    let json = options.format == OutputFormat::Json;
+
+    // JSON output is never truncated
+    let max_lines = if json { 0 } else { options.max_lines };
+    let keep_running = options.keep_running || options.detach;
+    // The same split as the `TruncatingBuffer` the rest arrives in
+    let server_truncate = (options.server_truncate && max_lines > 0).then(|| {
+        let (head, tail) = if options.quiet {
+            (0, max_lines)
+        } else {
+            split_limit(max_lines, options.keep_tail_ratio)
+        };
+        ServerTruncation { head, tail }
+    });
+    let request = match command {
+        BuildCommand::Shell(command) => Request::Build {
+            dir,
+            command,
+            timeout: options.timeout,
+            env: options.env.clone(),
+            max_lines,
+            keep_running,
+            dry_run: options.dry_run,
+            stdin,
+            shell: options.shell.clone(),
+            server_truncate,
+        },
+        BuildCommand::Argv { program, args } => Request::BuildArgv {
+            dir,
+            program,
+            args,
+            timeout: options.timeout,
+            env: options.env.clone(),
+            max_lines,
+            keep_running,
+            dry_run: options.dry_run,
+            stdin,
+            server_truncate,
+        },
+    };
+    send_request(&mut stream, &request).await?;
+
+    follow_build(stream, endpoint, &options, log, OnInterrupt::CancelBuild).await
+}
+
+/// Watch a build that is already running on the server, starting with the
+/// output it has buffered. Ctrl+C detaches without stopping the build.
+pub async fn attach_build(
+    endpoint: &Endpoint,
+    id: Option<BuildId>,
+    from_line: u64,
+    tail: Option<u64>,
+    options: RunOptions,
+) -> Result<i32> {
+    let log = BuildLog::open(&options)?;
+
+    let mut stream = endpoint.connect().await?;
+    let request = Request::Attach {
+        id,
+        from_line,
+        tail,
+    };
+    send_request(&mut stream, &request).await?;
+
+    follow_build(stream, endpoint, &options, log, OnInterrupt::Detach).await
+}
+
+/// Hand a `--detach` build over to the server: the id goes to stdout so
+/// scripts can pass it to `wait`
+fn report_detached(build_id: BuildId, json: bool) -> Result<()> {
+    if json {
+        print_json(&JsonEvent::Detached { build_id })
+    } else {
+        println!("{}", build_id);
+        eprintln!(
+            "Build {} runs in the background; `build-runner wait --id {}` waits for it",
+            build_id, build_id
+        );
+        Ok(())
+    }
+}
+
+/// Wait for build `id` to finish, returning its exit code
+pub async fn wait_build(endpoint: &Endpoint, id: BuildId, options: RunOptions) -> Result<i32> {
+    let mut stream = endpoint.connect().await?;
+    send_request(&mut stream, &Request::Wait { id }).await?;
+
+    follow_build(stream, endpoint, &options, None, OnInterrupt::Detach).await
+}
+
+/// Log an output line and show whatever `--grep` lets through
+fn show_output(
+    line: OutputLine,
+    options: &RunOptions,
+    log: &mut Option<BuildLog>,
+    buffer: &mut TruncatingBuffer,
+    filter: &mut LineFilter,
+    tally: &mut Tally,
+) -> Result<()> {
+    if let Some(log) = log {
+        log.write_line(&line)?;
+    }
+    tally.count(severity(options.error_pattern.as_ref(), &line.content));
+    for line in filter.filter(line) {
+        display_line(line, options, buffer)?;
+    }
+    Ok(())
+}
+
+/// Print `line`, or hand it to `buffer` to print or truncate. Hidden lines
+/// only go to the buffer, to count toward --max-lines, unless
+/// --filter-before-truncate leaves them out of it as well.
+fn display_line(line: OutputLine, options: &RunOptions, buffer: &mut TruncatingBuffer) -> Result<()> {
+    if line.hidden && options.filter_before_truncate {
+        return Ok(());
+    }
+    if options.format != OutputFormat::Json {
+        buffer.push(line);
+    } else if !line.hidden {
+        print_json(&JsonEvent::Output {
+            stream: if line.is_stderr { "stderr" } else { "stdout" },
+            line: &line.content,
+            elapsed_ms: line.elapsed_ms,
+        })?;
+    }
+    Ok(())
+}
+
+/// Wait for the server to come up and finish its init script, for scripts
+/// that start it and then fire builds right away. Returns the exit code:
+/// [`TIMEOUT_EXIT_CODE`] if it isn't ready within `timeout`, 0 otherwise.
+pub async fn wait_ready(
+    endpoint: &Endpoint,
+    timeout: Option<Duration>,
+    interval: Duration,
+) -> Result<i32> {
+    let waiting = wait_until_ready(endpoint, interval, true);
+    match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, waiting).await {
+            Ok(result) => result?,
+            Err(_) => {
+                eprintln!(
+                    "Build server at {} was not ready after {}",
+                    endpoint,
+                    format_elapsed(timeout.as_secs())
+                );
+                return Ok(TIMEOUT_EXIT_CODE);
+            }
+        },
+        None => waiting.await?,
+    }
+    println!("Build server at {} is ready", endpoint);
+    Ok(0)
+}
+
+/// Poll the server every `interval` until its init script has finished, or
+/// fail if it ended without giving builds an environment. With
+/// `await_start`, a server that can't be reached yet counts as starting up.
+async fn wait_until_ready(endpoint: &Endpoint, interval: Duration, await_start: bool) -> Result<()> {
+    let mut announced = false;
+    loop {
+        let status = match fetch_status(endpoint).await {
+            Ok(status) => status,
+            Err(e) if await_start && e.is::<std::io::Error>() => {
+                tokio::time::sleep(interval).await;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if status.initialized {
+            return Ok(());
+        }
+        if !status.init_running {
+            let reason = status.init_error.unwrap_or_else(|| "no reason given".to_string());
+            bail!("Server is not initialized: {}", reason);
+        }
+        if !announced {
+            eprintln!("Waiting for the server's init script to finish...");
+            announced = true;
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// How often `--wait-for-ready` asks the server whether it is ready
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Render a build's responses until it ends, returning the exit code the
+/// client should exit with
+async fn follow_build(
+    mut stream: Stream,
+    endpoint: &Endpoint,
+    options: &RunOptions,
+    mut log: Option<BuildLog>,
+    on_interrupt: OnInterrupt,
+) -> Result<i32> {
+    let json = options.format == OutputFormat::Json;
+    let (reader, mut writer) = stream.split();
+    let mut reader = FrameReader::new(reader);
+
+    let colors = options.color.colors();
+    let mut buffer = TruncatingBuffer::new(options, colors);
+
+    // First Ctrl+C asks the server to cancel, a second one exits immediately
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    let mut cancel_requested = false;
+    let mut build_id = None;
+    let mut filter = LineFilter::new(options);
+    let mut tally = Tally::default();
+
+    let outcome = loop {
+        let response = tokio::select! {
+            response = read_known(&mut reader, options.verbose) => match response {
+                Ok(response) => response,
+                // Still show the output held back for truncation, and end the log
+                Err(e) => break BuildOutcome::Failed {
+                    message: format!("Lost the connection to the server: {:#}", e),
+                },
+            },
+            _ = &mut ctrl_c => {
+                if on_interrupt == OnInterrupt::Detach {
+                    eprintln!("\nDetached; the build keeps running.");
+                    return Ok(130);
+                }
+                if cancel_requested {
+                    eprintln!("\nForce exit.");
+                    return Ok(130);
+                }
+                cancel_requested = true;
+                eprintln!("\nCancelling build (press Ctrl+C again to force exit)...");
+                // A failed cancel is only reported: the build's own connection
+                // still brings its end, and the output and log get finished
+                let cancelled = match build_id {
+                    Some(id) => match endpoint.connect().await {
+                        Ok(stream) => send_cancel(stream, Some(id)).await.map(drop),
+                        Err(e) => Err(e),
+                    },
+                    // Not started yet, so the server can only match us by connection
+                    None => send_request(&mut writer, &Request::Cancel { build_id: None }).await,
+                };
+                if let Err(e) = cancelled {
+                    eprintln!("Failed to cancel the build: {:#}", e);
+                }
+                ctrl_c.set(tokio::signal::ctrl_c());
+                continue;
+            }
+        };
+        let Some(response) = response else {
+            break BuildOutcome::Failed {
+                message: "Server closed the connection before the build finished".to_string(),
+            };
+        };
+
+        match response {
+            Response::Accepted { build_id: id } => {
+                build_id = Some(id);
+                if options.detach {
+                    report_detached(id, json)?;
+                    return Ok(0);
+                }
+            }
+            Response::Queued {
+                build_id: id,
+                position,
+            } => {
+                build_id = Some(id);
+                if json {
+                    print_json(&JsonEvent::Queued { build_id: id, position })?;
+                } else if options.quiet {
+                    // Nothing until the build is known to have failed
+                } else if position == 1 {
+                    eprintln!("Waiting for a free build slot (next in line)...");
+                } else {
+                    eprintln!(
+                        "Waiting for a free build slot ({} build(s) ahead)...",
+                        position - 1
+                    );
+                }
+            }
+            Response::BuildStarted { build_id: id, pid } => {
+                build_id = Some(id);
+                if json {
+                    print_json(&JsonEvent::Started { build_id: id, pid })?;
+                } else if options.verbose {
+                    match pid {
+                        Some(pid) => eprintln!("Build {} started (pid {})", id, pid),
+                        None => eprintln!("Build {} started", id),
+                    }
+                }
+            }
+            Response::Output {
+                line: content,
+                is_stderr,
+                elapsed_ms,
+            } => {
+                let line = OutputLine {
+                    content,
+                    is_stderr,
+                    elapsed_ms,
+                    hidden: false,
+                };
+                show_output(line, options, &mut log, &mut buffer, &mut filter, &mut tally)?;
+                buffer.redraw()?;
+            }
+            Response::OutputBatch { lines } => {
+                for line in lines {
+                    let line = OutputLine {
+                        content: line.line,
+                        is_stderr: line.is_stderr,
+                        elapsed_ms: line.elapsed_ms,
+                        hidden: false,
+                    };
+                    show_output(line, options, &mut log, &mut buffer, &mut filter, &mut tally)?;
+                }
+                buffer.redraw()?;
+            }
+            Response::BuildComplete {
+                exit_code,
+                duration_ms,
+                stdout_lines,
+                stderr_lines,
+                signal,
+                queued_ms,
+                ..
+            } => {
+                break BuildOutcome::Completed {
+                    exit_code,
+                    duration_ms,
+                    stdout_lines,
+                    stderr_lines,
+                    signal,
+                    queued_ms,
+                }
+            }
+            Response::Truncated {
+                skipped,
+                skipped_bytes,
+            } => buffer.skip(skipped, skipped_bytes),
+            // Only there so the server notices if this client goes away
+            Response::Heartbeat => {}
+            Response::Cancelled => break BuildOutcome::Cancelled,
+            Response::Timeout { elapsed_secs } => break BuildOutcome::TimedOut { elapsed_secs },
+            Response::Stalled { idle_secs } => break BuildOutcome::Stalled { idle_secs },
+            Response::Error { message } => break BuildOutcome::Failed { message },
+            Response::NotReady { reason } => {
+                let message = if options.wait_for_ready {
+                    reason
+                } else {
+                    format!("{} (pass --wait-for-ready to wait for it)", reason)
+                };
+                break BuildOutcome::Failed { message };
+            }
+            Response::Unauthorized => {
+                break BuildOutcome::Failed {
+                    message: UNAUTHORIZED_MESSAGE.to_string(),
+                }
+            }
+            _ => {}
+        }
+    };
+
+    for line in filter.finish() {
+        display_line(line, options, &mut buffer)?;
+    }
+    if json {
+        print_json(&outcome.json_event())?;
+    } else if options.quiet && outcome.exit_code() == 0 {
+        // Successful quiet builds say nothing at all
+    } else {
+        let truncated = buffer.truncated();
+        buffer.finish()?;
+        match outcome {
+            // A script that only wants the exit code can go without
+            BuildOutcome::Completed { .. } if !options.summary => {}
+            BuildOutcome::Completed {
+                exit_code,
+                duration_ms,
+                stdout_lines,
+                stderr_lines,
+                signal,
+                queued_ms,
+            } => {
+                let mut counts = Vec::new();
+                if tally.errors > 0 {
+                    counts.push(plural(tally.errors, "error"));
+                }
+                if tally.warnings > 0 {
+                    counts.push(plural(tally.warnings, "warning"));
+                }
+                counts.push(plural(stdout_lines + stderr_lines, "line"));
+                let mut details = Vec::new();
+                if truncated > 0 {
+                    details.push(format!("{} truncated", truncated));
+                }
+                if options.grep.is_some() {
+                    details.push(format!("{} matched --grep, {} hidden", filter.matched, filter.hidden()));
+                }
+                if !details.is_empty() {
+                    let last = counts.len() - 1;
+                    counts[last] = format!("{} ({})", counts[last], details.join(", "));
+                }
+                let ending = match signal {
+                    Some(signal) => format!("killed by {}", signal_name(signal)),
+                    None => format!("exit {}", exit_code),
+                };
+                // Tells a slow build apart from one stuck behind others
+                let queued = if queued_ms > 0 {
+                    format!(" (after {} queued)", format_duration_ms(queued_ms))
+                } else {
+                    String::new()
+                };
+                let succeeded = exit_code == 0 && signal.is_none();
+                let banner = format!(
+                    "Build {} in {}{}, {} - {}",
+                    if succeeded { "finished" } else { "failed" },
+                    format_duration_ms(duration_ms),
+                    queued,
+                    ending,
+                    counts.join(", ")
+                );
+                // The marks are only for terminals, where the color goes too
+                let (style, mark) = if succeeded {
+                    (highlight::GREEN, "✓ ")
+                } else {
+                    (highlight::RED, "✗ ")
+                };
+                let mark = if colors.stderr { mark } else { "" };
+                let banner = format!("{}{}", mark, banner);
+                eprintln!("\n{}", highlight::paint(&banner, style, colors.stderr));
+            }
+            BuildOutcome::Cancelled => {
+                eprintln!("\n{}", highlight::paint("Build was cancelled", highlight::RED, colors.stderr));
+            }
+            BuildOutcome::TimedOut { elapsed_secs } => {
+                let banner = format!("Build timed out after {} seconds", elapsed_secs);
+                eprintln!("\n{}", highlight::paint(&banner, highlight::RED, colors.stderr));
+            }
+            BuildOutcome::Stalled { idle_secs } => {
+                let banner = format!("Build killed after {} seconds without output", idle_secs);
+                eprintln!("\n{}", highlight::paint(&banner, highlight::RED, colors.stderr));
+            }
+            BuildOutcome::Failed { ref message } => {
+                let message = format!("Error: {}", message);
+                eprintln!("{}", highlight::paint(&message, highlight::RED, colors.stderr));
+            }
+        }
+        if options.verbose && endpoint.compression.is_some() {
+            let (received, decoded) = reader.bytes_read();
+            eprintln!(
+                "Received {} ({} uncompressed)",
+                format_bytes(received),
+                format_bytes(decoded)
+            );
+        }
+    }
+
+    if let Some(ref mut log) = log {
+        log.finish(&outcome.to_string())?;
+    }
+
+    Ok(outcome.exit_code())
+}
+
+/// Print the server's state. Returns the exit code: [`STATUS_NOT_INITIALIZED`]
+/// or [`STATUS_NOT_RUNNING`] unless it is ready for builds, 0 if it is.
+pub async fn check_status(endpoint: &Endpoint, verbose: bool, format: OutputFormat) -> Result<i32> {
+    let json = format == OutputFormat::Json;
+    let mut stream = match endpoint.connect().await {
+        Ok(s) => s,
+        Err(e) if e.is::<std::io::Error>() => {
+            if json {
+                print_json(&JsonEvent::Status {
+                    reachable: false,
+                    status: None,
+                })?;
+            } else {
+                println!("Build server is NOT running at {}", endpoint);
+            }
+            return Ok(STATUS_NOT_RUNNING);
+        }
+        Err(e) => return Err(e),
+    };
+
+    send_request(&mut stream, &Request::Status).await?;
+
+    let response = read_response(&mut stream).await?;
+
+    match response {
+        Response::Status(status) if json => {
+            print_json(&JsonEvent::Status {
+                reachable: true,
+                status: Some(&status),
+            })?;
+            if !status.initialized {
+                return Ok(STATUS_NOT_INITIALIZED);
+            }
+        }
+        Response::Status(status) => {
+            let ServerStatus {
+                initialized,
+                version,
+                hostname,
+                uptime_secs,
+                builds_started,
+                running,
+                init_scripts,
+                init_command,
+                env_vars,
+                shell,
+                allowed_shells,
+                default_command,
+                last_build,
+                init_duration_secs,
+                init_output_tail,
+                init_error,
+                init_running,
+            } = *status;
+            match hostname {
+                Some(hostname) => println!("Build server is running at {} on {}", endpoint, hostname),
+                None => println!("Build server is running at {}", endpoint),
+            }
+            // Servers from before these fields were added leave them empty
+            if !version.is_empty() {
+                println!("  Version: {}", version);
+                println!("  Up for: {}", format_elapsed(uptime_secs));
+            }
+            if init_running {
+                println!("  Initialized: {} (the init script is being re-run)", initialized);
+            } else {
+                println!("  Initialized: {}", initialized);
+            }
+            if !shell.is_empty() {
+                println!("  Shell: {}", shell);
+            }
+            if !allowed_shells.is_empty() {
+                println!("  Other shells allowed: {}", allowed_shells.join(", "));
+            }
+            if let Some(command) = default_command {
+                println!("  Default command: {}", command);
+            }
+            let init = match (init_scripts.as_slice(), init_command) {
+                ([], Some(command)) => Some(("Init command", command)),
+                ([], None) => None,
+                ([script], _) => Some(("Init script", script.clone())),
+                (scripts, _) => Some(("Init scripts", scripts.join(", "))),
+            };
+            if let Some((label, init)) = init {
+                println!("  {}: {}", label, init);
+                if let Some(secs) = init_duration_secs {
+                    println!("  Init took: {:.1}s", secs);
+                }
+                println!("  Captured env vars: {}", env_vars);
+                if let Some(error) = init_error {
+                    println!("  Init failed: {} (builds are refused until `reinit` succeeds)", error);
+                }
+                if verbose && !init_output_tail.is_empty() {
+                    println!("  Init output (last {} lines):", init_output_tail.len());
+                    for line in init_output_tail {
+                        println!("    {}", line);
+                    }
+                }
+            }
+            if let Some(last) = last_build {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs());
+                println!(
+                    "  Last build: #{} '{}' exited with {} ({} ago)",
+                    last.id,
+                    last.command,
+                    last.exit_code,
+                    format_age(now.saturating_sub(last.finished_at))
+                );
+            }
+            if !version.is_empty() {
+                println!("  Builds started: {}, running: {}", builds_started, running.len());
+                for build in running {
+                    println!(
+                        "    #{} '{}' in {} ({})",
+                        build.id,
+                        build.command,
+                        build.dir.display(),
+                        format_elapsed(build.elapsed_secs)
+                    );
+                }
+            }
+            if !initialized {
+                return Ok(STATUS_NOT_INITIALIZED);
+            }
+        }
+        _ if json => bail!("Unexpected response from server"),
+        _ => {
+            println!("Unexpected response from server");
+        }
+    }
+
+    Ok(0)
+}
+
+pub async fn stop_server(endpoint: &Endpoint, force: bool, drain: bool) -> Result<()> {
+    let mut stream = match endpoint.connect().await {
+        Ok(s) => s,
+        Err(e) if e.is::<std::io::Error>() => {
+            println!("Build server is not running at {}", endpoint);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+
+    send_request(&mut stream, &Request::Stop { force, drain }).await?;
+
+    let response = read_response(&mut stream).await?;
+
+    match response {
+        Response::Stopping { active_builds: 0 } => println!("Build server is stopping..."),
+        Response::Stopping { active_builds } if force => {
+            println!(
+                "Build server is stopping ({} running build(s) killed)...",
+                active_builds
+            );
+        }
+        Response::Stopping { active_builds } => {
+            println!(
+                "Build server is stopping once {} running build(s) finish \
+                 (`stop --force` kills them)...",
+                active_builds
+            );
+        }
+        _ => {
+            println!("Unexpected response from server");
+            return Ok(());
+        }
+    }
+
+    // The server keeps this connection open until its builds have drained,
+    // with a fresh count whenever one of them finishes
+    let mut reader = FrameReader::new(&mut stream);
+    while let Ok(Some(response)) = read_known(&mut reader, false).await {
+        match response {
+            Response::Stopping { active_builds: 0 } => println!("Running builds finished."),
+            Response::Stopping { active_builds } => {
+                println!("Waiting for {} running build(s) to finish...", active_builds);
+            }
+            _ => {}
+        }
+    }
+
+    // Only report success once the server actually refuses connections
+    for _ in 0..STOP_POLL_ATTEMPTS {
+        if Stream::connect(&endpoint.address).await.is_err() {
+            println!("Build server stopped.");
+            return Ok(());
+        }
+        tokio::time::sleep(STOP_POLL_INTERVAL).await;
+    }
+
+    anyhow::bail!(
+        "Build server at {} is still accepting connections after {:?}",
+        endpoint,
+        STOP_POLL_INTERVAL * STOP_POLL_ATTEMPTS
+    )
+}
+
+pub async fn cancel_build(endpoint: &Endpoint, build_id: Option<BuildId>) -> Result<()> {
+    let stream = match endpoint.connect().await {
+        Ok(s) => s,
+        Err(e) if e.is::<std::io::Error>() => {
+            println!("Build server is not running at {}", endpoint);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+
+    match send_cancel(stream, build_id).await? {
+        Response::Cancelled => {
+            println!("Build cancelled.");
+        }
+        Response::Error { message } => {
+            println!("{}", message);
+        }
+        _ => {
+            println!("Unexpected response from server");
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn reinit_server(endpoint: &Endpoint) -> Result<()> {
+    let mut stream = endpoint.connect().await?;
+
+    send_request(&mut stream, &Request::Reinit).await?;
+
+    // The init script's output streams back like a build's
+    let mut reader = FrameReader::new(stream);
+    loop {
+        let response = read_known(&mut reader, false)
+            .await?
+            .context("Server closed the connection before the init script finished")?;
+        match response {
+            Response::Output {
+                line, is_stderr, ..
+            } => {
+                if is_stderr {
+                    eprintln!("{}", line);
+                } else {
+                    println!("{}", line);
+                }
+            }
+            Response::ReinitComplete { env_vars } => {
+                println!(
+                    "Init script re-run successfully ({} environment variables captured).",
+                    env_vars
+                );
+                return Ok(());
+            }
+            Response::Error { message } => bail!("Reinit failed: {}", message),
+            Response::Unauthorized => bail!(UNAUTHORIZED_MESSAGE),
+            _ => bail!("Unexpected response from server"),
+        }
+    }
+}
+
+pub async fn show_log(
+    endpoint: &Endpoint,
+    build_id: Option<BuildId>,
+    tail: Option<usize>,
+) -> Result<()> {
+    let mut stream = endpoint.connect().await?;
+
+    let request = Request::GetLog { id: build_id, tail };
+    send_request(&mut stream, &request).await?;
+
+    let mut reader = FrameReader::new(stream);
+    loop {
+        let response = read_known(&mut reader, false)
+            .await?
+            .context("Server closed the connection before the log was complete")?;
+        match response {
+            // Logs read from memory still know which stream each line came from
+            Response::Output { line, is_stderr: true, .. } => eprintln!("{}", line),
+            Response::Output { line, .. } => println!("{}", line),
+            Response::LogComplete { .. } => return Ok(()),
+            Response::Error { message } => bail!(message),
+            Response::Unauthorized => bail!(UNAUTHORIZED_MESSAGE),
+            _ => bail!("Unexpected response from server"),
+        }
+    }
+}
+
+/// Set (`vars`) or remove (`keys`) variables in the server's build environment
+pub async fn update_env(endpoint: &Endpoint, request: Request) -> Result<()> {
+    let mut stream = endpoint.connect().await?;
+
+    send_request(&mut stream, &request).await?;
+
+    match read_response(&mut stream).await? {
+        Response::EnvUpdated { env_vars } => {
+            println!("Environment updated ({} variables).", env_vars);
+            Ok(())
+        }
+        Response::Error { message } => bail!(message),
+        _ => bail!("Unexpected response from server"),
+    }
+}
+
+/// Print the environment builds start with, as `KEY=VALUE` lines or, with
+/// `export`, as commands that set it in the local shell
+pub async fn show_env(endpoint: &Endpoint, prefix: Option<String>, export: bool) -> Result<()> {
+    let mut stream = endpoint.connect().await?;
+
+    send_request(&mut stream, &Request::GetEnv { prefix }).await?;
+
+    let Response::Env { vars } = read_response(&mut stream).await? else {
+        bail!("Unexpected response from server");
+    };
+    for (key, value) in vars {
+        if !export {
+            println!("{}={}", key, value);
+        } else if cfg!(windows) {
+            println!("$env:{} = {}", key, quote_for_shell(ShellKind::Powershell, &value));
+        } else {
+            println!("export {}={}", key, quote_for_shell(ShellKind::Sh, &value));
+        }
+    }
+    Ok(())
+}
+
+pub async fn list_builds(endpoint: &Endpoint, format: OutputFormat) -> Result<()> {
+    let json = format == OutputFormat::Json;
+    let mut stream = match endpoint.connect().await {
+        // Scripts get the failure as an error rather than an empty list
+        Err(e) if json => return Err(e),
+        Ok(s) => s,
+        Err(e) if e.is::<std::io::Error>() => {
+            println!("Build server is not running at {}", endpoint);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+
+    send_request(&mut stream, &Request::ListBuilds).await?;
+
+    match read_response(&mut stream).await? {
+        Response::Builds { builds } if json => {
+            for build in &builds {
+                print_json(&JsonEvent::Build(build))?;
+            }
+        }
+        Response::Builds { builds } if builds.is_empty() => {
+            println!("no builds running or queued");
+        }
+        Response::Builds { builds } => {
+            let rows: Vec<[String; 7]> = builds
+                .into_iter()
+                .map(|build| {
+                    [
+                        build.id.to_string(),
+                        if build.queued { "queued" } else { "running" }.to_string(),
+                        build.pid.map_or("-".to_string(), |pid| pid.to_string()),
+                        format_elapsed(build.elapsed_secs),
+                        build.client,
+                        build.dir.display().to_string(),
+                        build.command,
+                    ]
+                })
+                .collect();
+            print_table(["ID", "STATE", "PID", "ELAPSED", "CLIENT", "DIR", "COMMAND"], &rows);
+        }
+        Response::Error { message } if json => bail!(message),
+        Response::Error { message } => {
+            println!("{}", message);
+        }
+        _ if json => bail!("Unexpected response from server"),
+        _ => {
+            println!("Unexpected response from server");
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn show_history(endpoint: &Endpoint, limit: usize, format: OutputFormat) -> Result<()> {
+    let json = format == OutputFormat::Json;
+    let mut stream = match endpoint.connect().await {
+        Err(e) if json => return Err(e),
+        Ok(s) => s,
+        Err(e) if e.is::<std::io::Error>() => {
+            println!("Build server is not running at {}", endpoint);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+
+    let request = Request::History {
+        limit: Some(limit),
+    };
+    send_request(&mut stream, &request).await?;
+
+    match read_response(&mut stream).await? {
+        Response::History { builds } if json => {
+            for build in &builds {
+                print_json(&JsonEvent::Finished(build))?;
+            }
+        }
+        Response::History { builds } if builds.is_empty() => {
+            println!("no builds in history");
+        }
+        Response::History { builds } => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs());
+            let rows: Vec<[String; 8]> = builds
+                .into_iter()
+                .map(|build| {
+                    [
+                        build.id.to_string(),
+                        format!("{} ago", format_age(now.saturating_sub(build.started_at))),
+                        format_duration_ms(build.duration_ms),
+                        if build.queued_ms > 0 {
+                            format_duration_ms(build.queued_ms)
+                        } else {
+                            String::new()
+                        },
+                        match build.signal {
+                            Some(signal) => signal_name(signal),
+                            None => build.result.to_string(),
+                        },
+                        if build.truncated { "yes" } else { "" }.to_string(),
+                        build.dir.display().to_string(),
+                        build.command,
+                    ]
+                })
+                .collect();
+            print_table(
+                [
+                    "ID", "STARTED", "DURATION", "QUEUED", "RESULT", "TRUNCATED", "DIR", "COMMAND",
+                ],
+                &rows,
+            );
+        }
+        Response::Error { message } if json => bail!(message),
+        Response::Error { message } => {
+            println!("{}", message);
+        }
+        _ if json => bail!("Unexpected response from server"),
+        _ => {
+            println!("Unexpected response from server");
+        }
+    }
+
+    Ok(())
+}
+
+/// Coarse age for "N ago": `12s`, `3m`, `5h`, `2d`
+fn format_age(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+/// `1 error`, `3 errors`
+fn plural(count: u64, noun: &str) -> String {
+    if count == 1 {
+        format!("1 {}", noun)
+    } else {
+        format!("{} {}s", count, noun)
+    }
+}
+
+/// `512 B`, `3.4 KiB`, `12.0 MiB`
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{} B", bytes),
+        1024..=1048575 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0)),
+    }
+}
+
+/// Build durations are often under a minute, so keep a decimal there
+fn format_duration_ms(ms: u64) -> String {
+    if ms < 60_000 {
+        format!("{:.1}s", ms as f64 / 1000.0)
+    } else {
+        format_elapsed(ms / 1000)
+    }
+}
+
+/// `59s`, `2m05s`, `1h02m`
+fn format_elapsed(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+/// Print left-aligned columns sized to their widest cell; the last column is
+/// left unpadded so long values don't leave trailing spaces
+fn print_table<const N: usize>(header: [&str; N], rows: &[[String; N]]) {
+    let mut widths = header.map(str::len);
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let print_row = |cells: [&str; N]| {
+        let mut line = String::new();
+        for (i, cell) in cells.iter().enumerate() {
+            if i + 1 == N {
+                line.push_str(cell);
+            } else {
+                line.push_str(&format!("{:<width$}  ", cell, width = widths[i]));
+            }
+        }
+        println!("{}", line);
+    };
+
+    print_row(header);
+    for row in rows {
+        print_row(row.each_ref().map(String::as_str));
+    }
+}
+
+/// Ask the server to cancel a build over its own connection
+async fn send_cancel(mut stream: Stream, build_id: Option<BuildId>) -> Result<Response> {
+    send_request(&mut stream, &Request::Cancel { build_id }).await?;
+
+    read_response(&mut stream).await
+}
+
+async fn send_request<W: AsyncWrite + Unpin>(writer: &mut W, request: &Request) -> Result<()> {
+    write_frame(writer, request).await
+}
+
+/// Read the next response in a stream of them, skipping any this client
+/// doesn't understand (most likely added in a newer server) and reporting
+/// them on stderr when `verbose`
+async fn read_known<R: AsyncRead + Unpin>(
+    reader: &mut FrameReader<R>,
+    verbose: bool,
+) -> Result<Option<Response>> {
+    loop {
+        match reader.read_frame::<Response>().await {
+            Err(e) if e.is::<serde_json::Error>() => {
+                if verbose {
+                    eprintln!("Ignoring a message from the server this client doesn't understand: {}", e);
+                }
+            }
+            response => return response,
+        }
+    }
+}
+
+async fn fetch_status(endpoint: &Endpoint) -> Result<ServerStatus> {
+    let mut stream = endpoint.connect().await?;
+    send_request(&mut stream, &Request::Status).await?;
+    match read_response(&mut stream).await? {
+        Response::Status(status) => Ok(*status),
+        _ => bail!("Unexpected response from server"),
+    }
+}
+
+/// Read the single response to a one-shot request
+async fn read_response(stream: &mut Stream) -> Result<Response> {
+    FrameReader::new(stream)
+        .read_frame()
+        .await?
+        .context("Server closed the connection without responding")
+        .and_then(|response| match response {
+            Response::Unauthorized => bail!(UNAUTHORIZED_MESSAGE),
+            response => Ok(response),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{Server, ServerConfig};
+
+    /// Path for a test's scratch file, removed first in case an earlier
+    /// run left it behind
+    fn scratch(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("build-runner-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn line(content: &str, is_stderr: bool) -> OutputLine {
+        OutputLine {
+            content: content.to_string(),
+            is_stderr,
+            elapsed_ms: 0,
+            hidden: false,
+        }
+    }
+
+    #[test]
+    fn build_log_keeps_every_line_and_renames_when_done() {
+        let path = scratch("full.log");
+        let options = RunOptions {
+            log_file: Some(path.clone()),
+            ..Default::default()
+        };
+        let mut log = BuildLog::open(&options).unwrap().unwrap();
+        for n in 1..=5 {
+            log.write_line(&line(&format!("line {}", n), n == 3)).unwrap();
+        }
+        let mut partial = path.clone().into_os_string();
+        partial.push(".partial");
+        assert!(Path::new(&partial).exists());
+        assert!(!path.exists());
+
+        log.finish("Build finished with exit code 0").unwrap();
+        assert!(!Path::new(&partial).exists());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "line 1\nline 2\n[stderr] line 3\nline 4\nline 5\nBuild finished with exit code 0\n"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// Loopback address of a port that was free a moment ago
+    fn free_port() -> Address {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .port();
+        Address::Tcp {
+            host: "127.0.0.1".to_string(),
+            port,
+        }
+    }
+
+    /// Serve `config` on `address` in the background
+    async fn serve(address: Address, configure: impl FnOnce(&mut ServerConfig)) {
+        let mut config = ServerConfig::new(address);
+        configure(&mut config);
+        let server = Server::bind(config).await.unwrap();
+        tokio::spawn(server.serve());
+    }
+
+    #[tokio::test]
+    async fn connect_retries_until_the_server_is_up() {
+        let address = free_port();
+        let mut endpoint = Endpoint::new(address.clone());
+        let Err(error) = endpoint.connect().await else {
+            panic!("connected to a port nobody listens on");
+        };
+        assert!(error.to_string().contains("Is the server running?"), "{:#}", error);
+
+        let late_server = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            serve(address, |_| {}).await;
+        });
+        endpoint.connect_retries = 5;
+        endpoint.connect_delay = Duration::from_millis(50);
+        endpoint.connect().await.unwrap();
+        late_server.await.unwrap();
+    }
+
+    #[test]
+    fn durations_keep_a_decimal_under_a_minute() {
+        assert_eq!(format_duration_ms(0), "0.0s");
+        assert_eq!(format_duration_ms(1250), "1.2s");
+        assert_eq!(format_duration_ms(59_949), "59.9s");
+        assert_eq!(format_duration_ms(125_000), "2m05s");
+        assert_eq!(format_duration_ms(3_720_000), "1h02m");
+    }
+
+    /// Run `lines` through `filter`, returning what it displays
+    fn filtered(filter: &mut LineFilter, lines: &[&str]) -> Vec<String> {
+        lines
+            .iter()
+            .flat_map(|content| filter.filter(line(content, false)))
+            .filter(|line| !line.hidden)
+            .map(|line| line.content)
+            .collect()
+    }
+
+    #[test]
+    fn grep_shows_matching_lines_or_the_rest() {
+        let lines = ["Compiling a", "warning: unused", "Compiling b", "error: mismatched types"];
+        let options = RunOptions {
+            grep: Some(Regex::new("^(warning|error)").unwrap()),
+            ..Default::default()
+        };
+        let mut filter = LineFilter::new(&options);
+        assert_eq!(filtered(&mut filter, &lines), ["warning: unused", "error: mismatched types"]);
+        assert_eq!((filter.matched, filter.hidden()), (2, 2));
+
+        let options = RunOptions {
+            grep_invert: true,
+            ..options
+        };
+        let mut filter = LineFilter::new(&options);
+        assert_eq!(filtered(&mut filter, &lines), ["Compiling a", "Compiling b"]);
+
+        let options = RunOptions::default();
+        assert_eq!(filtered(&mut LineFilter::new(&options), &lines), lines);
+    }
+
+    #[test]
+    fn color_choice_and_painting() {
+        assert!(matches!(ColorChoice::Always.colors(), Colors { stdout: true, stderr: true }));
+        assert!(matches!(ColorChoice::Never.colors(), Colors { stdout: false, stderr: false }));
+        assert_eq!(highlight::paint("boom", highlight::RED, true), "\x1b[31mboom\x1b[0m");
+        assert_eq!(highlight::paint("boom", highlight::RED, false), "boom");
+        assert_eq!(highlight::paint("plain", "", true), "plain");
+    }
+
+    #[test]
+    fn picks_out_error_and_warning_lines() {
+        use highlight::Severity::{Error, Normal, Warning};
+
+        for (line, expected) in [
+            ("src/main.rs:3:5: error: expected `;`", Error),
+            ("error[E0308]: mismatched types", Error),
+            ("main.c:1:10: fatal error: stdio.h: No such file", Error),
+            ("main.obj : error LNK2019: unresolved external symbol", Error),
+            ("test parse ... FAILED", Error),
+            ("warning: unused variable: `x`", Warning),
+            ("foo.cpp(12): warning C4996: 'strcpy': unsafe", Warning),
+            ("Compiling error-chain v0.12.4", Normal),
+            ("0 errors, 0 warnings", Normal),
+        ] {
+            assert_eq!(highlight::severity(line), expected, "{}", line);
+        }
+
+        // --error-pattern replaces the built-in idea of an error
+        let pattern = Regex::new("^BUILD BROKEN").unwrap();
+        assert_eq!(severity(Some(&pattern), "BUILD BROKEN in step 3"), Error);
+        assert_eq!(severity(Some(&pattern), "error: ignored now"), Normal);
+        assert_eq!(severity(Some(&pattern), "warning: still a warning"), Warning);
+    }
+
+    #[test]
+    fn build_dir_is_resolved_on_the_client() {
+        let cwd = dunce::canonicalize(".").unwrap();
+        assert_eq!(resolve_build_dir(Path::new(".")).unwrap(), cwd);
+        assert_eq!(resolve_build_dir(Path::new("src/..")).unwrap(), cwd);
+        if let Some(home) = dirs::home_dir().filter(|home| home.is_dir()) {
+            let home = dunce::canonicalize(home).unwrap();
+            assert_eq!(resolve_build_dir(Path::new("~")).unwrap(), home);
+        }
+
+        let error = resolve_build_dir(Path::new("no/such/dir")).unwrap_err();
+        assert_eq!(error.to_string(), "Build directory does not exist: no/such/dir");
+        let error = resolve_build_dir(Path::new("Cargo.toml")).unwrap_err();
+        assert_eq!(error.to_string(), "Build path is not a directory: Cargo.toml");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn status_exit_code_tells_whether_builds_can_run() {
+        let address = free_port();
+        let endpoint = Endpoint::new(address.clone());
+        let status = check_status(&endpoint, false, OutputFormat::Text).await.unwrap();
+        assert_eq!(status, STATUS_NOT_RUNNING);
+
+        serve(address, |config| {
+            config.init = vec![crate::shell::InitSource::Command("exit 1".to_string())];
+            config.require_init = false;
+        })
+        .await;
+        let status = check_status(&endpoint, false, OutputFormat::Json).await.unwrap();
+        assert_eq!(status, STATUS_NOT_INITIALIZED);
+
+        let address = free_port();
+        serve(address.clone(), |_| {}).await;
+        let status = check_status(&Endpoint::new(address), true, OutputFormat::Text).await.unwrap();
+        assert_eq!(status, 0);
+    }
+
+    /// Contents of the lines a buffer holds at its start and its end
+    fn held(buffer: &TruncatingBuffer) -> (Vec<&str>, Vec<&str>) {
+        (
+            buffer.head.iter().map(|line| line.content.as_str()).collect(),
+            buffer.tail.iter().map(|line| line.content.as_str()).collect(),
+        )
+    }
+
+    #[test]
+    fn keep_tail_ratio_splits_the_limit() {
+        assert_eq!(split_limit(10, 0.5), (5, 5));
+        assert_eq!(split_limit(10, 0.0), (10, 0));
+        assert_eq!(split_limit(10, 1.0), (0, 10));
+        assert_eq!(split_limit(10, 0.2), (8, 2));
+        // Each end keeps a line unless the ratio gives it none
+        assert_eq!(split_limit(3, 0.1), (2, 1));
+        assert_eq!(split_limit(3, 0.9), (1, 2));
+        assert_eq!(split_limit(0, 0.2), (usize::MAX, usize::MAX));
+
+        let colors = Colors { stdout: false, stderr: false };
+        for (ratio, head, tail) in [
+            (0.0, vec!["1", "2", "3", "4"], vec![]),
+            (1.0, vec![], vec!["7", "8", "9", "10"]),
+            (0.25, vec!["1", "2", "3"], vec!["10"]),
+        ] {
+            let options = RunOptions {
+                max_lines: 4,
+                keep_tail_ratio: ratio,
+                ..Default::default()
+            };
+            let mut buffer = TruncatingBuffer::new(&options, colors);
+            for n in 1..=10 {
+                buffer.push(line(&n.to_string(), false));
+            }
+            assert_eq!(held(&buffer), (head, tail), "ratio {}", ratio);
+            assert_eq!(buffer.truncated(), 6);
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_from_a_newer_server_are_skipped() {
+        let mut bytes = Vec::new();
+        let unknown = serde_json::json!({ "Progress": { "percent": 40 } });
+        write_frame(&mut bytes, &unknown).await.unwrap();
+        write_frame(&mut bytes, &Response::Heartbeat).await.unwrap();
+        write_frame(&mut bytes, &unknown).await.unwrap();
+
+        let mut reader = FrameReader::new(&bytes[..]);
+        assert!(matches!(read_known(&mut reader, true).await.unwrap(), Some(Response::Heartbeat)));
+        assert!(read_known(&mut reader, false).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn quiet_holds_the_last_lines_back_until_the_end() {
+        let colors = Colors { stdout: false, stderr: false };
+        let quiet = |max_lines| RunOptions {
+            quiet: true,
+            max_lines,
+            ..Default::default()
+        };
+
+        let mut buffer = TruncatingBuffer::new(&quiet(4), colors);
+        assert!(buffer.deferred);
+        for n in 1..=10 {
+            buffer.push(line(&n.to_string(), false));
+        }
+        // The whole limit goes to the end, where a failure shows
+        assert_eq!(held(&buffer), (vec![], vec!["7", "8", "9", "10"]));
+        assert_eq!(buffer.truncated(), 6);
+
+        let mut buffer = TruncatingBuffer::new(&quiet(0), colors);
+        for n in 1..=10 {
+            buffer.push(line(&n.to_string(), false));
+        }
+        assert_eq!(buffer.tail.len(), 10);
+        assert_eq!(buffer.truncated(), 0);
+    }
+
+    #[test]
+    fn context_surrounds_matches_with_separators_between_groups() {
+        let lines = ["a", "b", "error: one", "c", "d", "e", "f", "error: two", "g"];
+        let options = RunOptions {
+            grep: Some(Regex::new("^error").unwrap()),
+            context: 1,
+            ..Default::default()
+        };
+        let mut filter = LineFilter::new(&options);
+        assert_eq!(
+            filtered(&mut filter, &lines),
+            ["b", "error: one", "c", "--", "f", "error: two", "g"]
+        );
+        assert_eq!((filter.matched, filter.hidden()), (2, 3));
+
+        // Groups that touch run together
+        let lines = ["error: one", "a", "b", "error: two"];
+        assert_eq!(filtered(&mut LineFilter::new(&options), &lines), lines);
+
+        // JSON events carry no separators
+        let options = RunOptions {
+            format: OutputFormat::Json,
+            ..options
+        };
+        let lines = ["error: one", "a", "b", "c", "error: two"];
+        assert_eq!(
+            filtered(&mut LineFilter::new(&options), &lines),
+            ["error: one", "a", "c", "error: two"]
+        );
+    }
+
+    #[test]
+    fn hidden_lines_count_toward_max_lines_unless_filtered_first() {
+        let colors = Colors { stdout: false, stderr: false };
+        let lines = ["a", "match 1", "b", "match 2", "c", "match 3", "d"];
+        let run = |options: &RunOptions| {
+            let mut filter = LineFilter::new(options);
+            let mut buffer = TruncatingBuffer::new(options, colors);
+            for content in lines {
+                for shown in filter.filter(line(content, false)) {
+                    display_line(shown, options, &mut buffer).unwrap();
+                }
+            }
+            for held_back in filter.finish() {
+                display_line(held_back, options, &mut buffer).unwrap();
+            }
+            assert_eq!((filter.matched, filter.hidden()), (3, 4));
+            buffer
+        };
+        let options = RunOptions {
+            max_lines: 2,
+            grep: Some(Regex::new("^match").unwrap()),
+            ..Default::default()
+        };
+
+        // The whole output is truncated, then filtered: only hidden lines are left
+        let buffer = run(&options);
+        assert_eq!(held(&buffer), (vec!["a"], vec!["d"]));
+        assert!(buffer.head.iter().chain(&buffer.tail).all(|line| line.hidden));
+        assert_eq!(buffer.truncated(), 5);
+
+        let options = RunOptions {
+            filter_before_truncate: true,
+            ..options
+        };
+        let buffer = run(&options);
+        assert_eq!(held(&buffer), (vec!["match 1"], vec!["match 3"]));
+        assert_eq!(buffer.truncated(), 1);
+    }
+
+    #[test]
+    fn error_lines_in_the_truncated_middle_are_kept_in_order() {
+        let colors = Colors { stdout: false, stderr: false };
+        let options = RunOptions {
+            max_lines: 4,
+            ..Default::default()
+        };
+        let mut buffer = TruncatingBuffer::new(&options, colors);
+        let lines = ["error: A", "ok", "x1", "error: B", "x2", "error: C", "x3", "error: D", "done"];
+        for content in lines {
+            buffer.push(line(content, false));
+        }
+        // Before the gap and after it, errors stay where they were
+        assert_eq!(held(&buffer), (vec!["error: A", "ok"], vec!["error: D", "done"]));
+        let kept: Vec<_> = buffer
+            .kept
+            .iter()
+            .map(|(gap_before, line)| (*gap_before, line.content.as_str()))
+            .collect();
+        assert_eq!(kept, [(true, "error: B"), (true, "error: C")]);
+        assert!(buffer.truncated_since_kept);
+        assert_eq!(buffer.truncated(), 3);
+
+        // Only the earliest `max_lines` of them
+        let mut buffer = TruncatingBuffer::new(&options, colors);
+        for n in 1..=10 {
+            buffer.push(line(&format!("error: {}", n), true));
+        }
+        let kept: Vec<_> = buffer.kept.iter().map(|(_, line)| line.content.as_str()).collect();
+        assert_eq!(kept, ["error: 3", "error: 4", "error: 5", "error: 6"]);
+        assert_eq!(buffer.truncated(), 2);
+
+        // Plain stderr lines in the gap are truncated like any other
+        let mut buffer = TruncatingBuffer::new(&options, colors);
+        let lines = ["a", "b", "   Compiling foo v0.1.0", "error: E", "   Compiling bar v0.1.0", "c", "d"];
+        for content in lines {
+            buffer.push(line(content, true));
+        }
+        let kept: Vec<_> = buffer.kept.iter().map(|(_, line)| line.content.as_str()).collect();
+        assert_eq!(kept, ["error: E"]);
+        assert_eq!(buffer.truncated(), 2);
+    }
+
+    #[test]
+    fn live_tail_keeps_the_latest_lines() {
+        let mut live = LiveTail::new(3);
+        assert!(!live.dirty);
+        for n in 1..=5 {
+            live.push(&format!("line {}", n));
+        }
+        assert_eq!(live.lines, ["line 3", "line 4", "line 5"]);
+        assert!(live.dirty);
+
+        // Nothing new since the last draw, so nothing to redraw
+        live.dirty = false;
+        live.draw().unwrap();
+        assert_eq!(live.drawn, 0);
+    }
+
+    #[test]
+    fn json_events_are_tagged_by_type() {
+        let output = JsonEvent::Output {
+            stream: "stderr",
+            line: "error: \"x\"",
+            elapsed_ms: 1500,
+        };
+        assert_eq!(
+            serde_json::to_string(&output).unwrap(),
+            r#"{"type":"output","stream":"stderr","line":"error: \"x\"","elapsed_ms":1500}"#
+        );
+        let complete = JsonEvent::Complete {
+            exit_code: -1,
+            duration_ms: 20,
+            stdout_lines: 3,
+            stderr_lines: 0,
+            signal: Some("SIGKILL".to_string()),
+            queued_ms: 5,
+        };
+        assert_eq!(
+            serde_json::to_string(&complete).unwrap(),
+            r#"{"type":"complete","exit_code":-1,"duration_ms":20,"stdout_lines":3,"stderr_lines":0,"signal":"SIGKILL","queued_ms":5}"#
+        );
+        assert_eq!(serde_json::to_string(&JsonEvent::Cancelled).unwrap(), r#"{"type":"cancelled"}"#);
+    }
 }
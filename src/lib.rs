@@ -0,0 +1,40 @@
+//! Client and server of build-runner, for driving builds from other Rust
+//! programs rather than through the `build-runner` command.
+//!
+//! [`server::Server`] runs a build server inside the calling program, and
+//! [`client::BuildClient`] starts builds on any server and streams back
+//! their output:
+//!
+//! ```no_run
+//! use build_runner::client::{BuildClient, BuildEvent};
+//! use build_runner::server::{Server, ServerConfig};
+//! use build_runner::transport::Address;
+//! use tokio_stream::StreamExt;
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let address = Address::Tcp { host: "127.0.0.1".to_string(), port: 0 };
+//! let server = Server::bind(ServerConfig::new(address)).await?;
+//! let port = server.local_addr().rsplit_once(':').unwrap().1.parse()?;
+//! tokio::spawn(server.serve());
+//!
+//! let client = BuildClient::connect(Address::Tcp { host: "127.0.0.1".to_string(), port }).await?;
+//! let mut events = client.build(".", "cargo build").await?;
+//! while let Some(event) = events.next().await {
+//!     match event? {
+//!         BuildEvent::Output(line) => println!("{}", line.line),
+//!         BuildEvent::Finished(outcome) => println!("exit code {}", outcome.exit_code()),
+//!         _ => {}
+//!     }
+//! }
+//! client.stop(false).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod client;
+mod process_tree;
+pub mod protocol;
+pub mod server;
+pub mod shell;
+pub mod tls;
+pub mod transport;
@@ -1,10 +1,13 @@
-mod client;
-mod protocol;
-mod server;
+use build_runner::protocol::{BuildCommand, Codec, Request};
+use build_runner::shell::{InitSource, Shell};
+use build_runner::{client, server, tls, transport};
 
-use anyhow::Result;
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use anyhow::{bail, Context, Result};
+use clap::{ArgGroup, Parser, Subcommand};
+use regex::Regex;
+use std::io::{IsTerminal, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(name = "build-runner")]
@@ -12,85 +15,1064 @@ use std::path::PathBuf;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Shared secret: the server rejects connections that don't present it,
+    /// and clients send it with every request
+    #[arg(long, global = true, value_name = "TOKEN")]
+    auth_token: Option<String>,
+
+    /// Read --auth-token from this file instead, keeping it out of the
+    /// process list and shell history
+    #[arg(long, global = true, value_name = "PATH", conflicts_with = "auth_token")]
+    auth_token_file: Option<PathBuf>,
+
+    /// Unix domain socket to serve on or connect to instead of TCP
+    /// (--port/--host/--bind are then ignored; not available on Windows)
+    #[arg(long, global = true, value_name = "PATH")]
+    socket: Option<PathBuf>,
+
+    /// Windows named pipe to serve on or connect to instead of TCP, e.g.
+    /// \\.\pipe\build-runner (--port/--host/--bind are then ignored)
+    #[arg(long, global = true, value_name = "NAME", conflicts_with = "socket")]
+    pipe: Option<String>,
+
+    /// Times a client retries connecting if the server can't be reached
+    #[arg(long, global = true, default_value = "0")]
+    connect_retries: u32,
+
+    /// Milliseconds before the first connection retry, doubled after each
+    #[arg(long, global = true, value_name = "MS", default_value = "200")]
+    connect_delay: u64,
+
+    /// Connect to the server over TLS (it needs --tls-cert)
+    #[arg(long, global = true)]
+    tls: bool,
+
+    /// PEM file with the server's certificate, or the CA that signed it,
+    /// to trust for --tls (implies --tls)
+    #[arg(long, global = true, value_name = "PATH")]
+    tls_ca: Option<PathBuf>,
+
+    /// Use TLS without checking the server's certificate, e.g. a
+    /// self-signed one while testing (implies --tls)
+    #[arg(long, global = true, conflicts_with = "tls_ca")]
+    tls_insecure: bool,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Start the build server (run this in your initialized terminal)
+    #[command(group(ArgGroup::new("init_source").args(["init", "init_cmd"])))]
     Server {
-        /// Path to init script to run on startup (optional)
+        /// Path to an init script to run on startup (optional); repeat it to
+        /// run several in order, each starting from the last one's environment
         #[arg(short, long)]
-        init: Option<PathBuf>,
+        init: Vec<PathBuf>,
+
+        /// Command line to run through the shell on startup instead of an
+        /// init script, e.g. "Import-Module VSSetup; Enter-VsDevShell ..."
+        #[arg(long)]
+        init_cmd: Option<String>,
+
+        /// Kill an init script if it runs longer than this many seconds;
+        /// at startup the server then exits
+        #[arg(long, requires = "init_source")]
+        init_timeout: Option<u64>,
+
+        /// Exit if the init script fails; with `false` the server starts
+        /// anyway and refuses builds until a `reinit` succeeds
+        #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+        require_init: bool,
+
+        /// Command line for `run` requests that don't give one; empty makes
+        /// `run` require -c
+        #[arg(long, default_value = "quickbuild debug")]
+        default_command: String,
+
+        /// Kill builds that run longer than this many seconds unless `run
+        /// --timeout` says otherwise (0 = unlimited)
+        #[arg(long, default_value = "0")]
+        default_timeout: u64,
+
+        /// Seconds between heartbeats sent to a build's client, so a dead
+        /// connection is noticed and its build killed (0 = off)
+        #[arg(long, default_value = "10")]
+        heartbeat_interval: u64,
+
+        /// Warn the client when a build has printed nothing for this many
+        /// seconds (off by default)
+        #[arg(long)]
+        stall_warn_secs: Option<u64>,
+
+        /// Kill a build that has printed nothing for this many seconds (off
+        /// by default)
+        #[arg(long)]
+        stall_kill_secs: Option<u64>,
 
         /// Port to listen on
         #[arg(short, long, default_value = "19527")]
         port: u16,
+
+        /// Shell program used to run the init script and builds, e.g. `pwsh`,
+        /// `cmd` or `bash` (default: powershell on Windows, sh elsewhere)
+        #[arg(long)]
+        shell: Option<String>,
+
+        /// Flags passed to the shell before the command, replacing the defaults
+        /// for its dialect (e.g. "-NoLogo -NoProfile -Command")
+        #[arg(long, allow_hyphen_values = true, requires = "shell")]
+        shell_args: Option<String>,
+
+        /// Let a build ask to be run with this shell instead, via
+        /// `run --shell` (repeatable). `run --arg` builds are then limited
+        /// to the server's shell and these
+        #[arg(long, value_name = "PROGRAM")]
+        allow_shell: Vec<String>,
+
+        /// Reject build commands that chain statements with `;`, `&&`, `|`,
+        /// backticks, `$(` or line breaks, so each build runs one command;
+        /// --arg builds may then only run a shell, whose arguments are checked
+        #[arg(long)]
+        no_shell_chaining: bool,
+
+        /// Address to listen on; anything other than loopback exposes the
+        /// server to the network, so pair it with --auth-token
+        #[arg(long, default_value = "127.0.0.1")]
+        bind: String,
+
+        /// PEM certificate (chain) to serve TLS with; clients then need --tls
+        #[arg(long, value_name = "PATH", requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+
+        /// PEM private key for --tls-cert
+        #[arg(long, value_name = "PATH", requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+
+        /// Maximum number of builds running at once; extra builds wait in a
+        /// queue (0 = unlimited)
+        #[arg(long, default_value = "1")]
+        max_concurrent: usize,
+
+        /// Allow builds in the same (or a nested) directory to run at once
+        #[arg(long)]
+        no_dir_lock: bool,
+
+        /// Largest request accepted, in MiB; mostly limits `run --stdin-file`
+        #[arg(long, default_value = "64")]
+        max_request_mb: usize,
+
+        /// On Ctrl+C, let running builds finish instead of killing them; a
+        /// second Ctrl+C kills them
+        #[arg(long)]
+        drain_on_shutdown: bool,
+
+        /// Number of finished builds remembered for `history`
+        #[arg(long, default_value = "100")]
+        history_size: usize,
+
+        /// Directory for each build's complete output, read back with `logs`
+        /// (default: build-runner/logs under the local data directory)
+        #[arg(long)]
+        log_dir: Option<PathBuf>,
+
+        /// Number of build logs to keep before deleting the oldest (0 = all)
+        #[arg(long, default_value = "50")]
+        keep_logs: usize,
+
+        /// Don't write build logs at all
+        #[arg(long, conflicts_with = "log_dir")]
+        no_build_logs: bool,
+
+        /// Append connections, build starts and finishes, and errors to this
+        /// file as `key=value` lines
+        #[arg(long)]
+        log_file: Option<PathBuf>,
+
+        /// Move --log-file aside to `<file>.1` once it grows past this many
+        /// bytes (0 = never)
+        #[arg(long, default_value = "10485760", requires = "log_file")]
+        log_max_bytes: u64,
     },
 
     /// Send a build request to the server
     Run {
-        /// Working directory for the build
+        /// Working directory for the build. Relative paths and `~` are
+        /// resolved on this machine and must exist here; the server receives
+        /// an absolute path
         #[arg(short = 'd', long)]
         dir: PathBuf,
 
-        /// Build command to execute (default: "quickbuild debug")
-        #[arg(short, long, default_value = "quickbuild debug")]
-        command: String,
+        /// Build command to execute (default: the server's --default-command)
+        #[arg(short, long)]
+        command: Option<String>,
+
+        /// Read the build command from stdin (a heredoc, say) instead of
+        /// --command, sparing long commands the quoting and the command
+        /// line length limit
+        #[arg(long, conflicts_with_all = ["command", "args"])]
+        command_stdin: bool,
+
+        /// Run a program directly instead of --command, bypassing the shell:
+        /// the first --arg is the program, the rest its arguments (repeatable)
+        #[arg(long = "arg", value_name = "ARG", allow_hyphen_values = true)]
+        args: Vec<String>,
+
+        /// Run the command with this shell instead of the server's; the
+        /// server must allow it with --allow-shell
+        #[arg(long, value_name = "PROGRAM", conflicts_with = "args")]
+        shell: Option<String>,
+
+        /// If the server is re-running its init script, wait for it to
+        /// finish instead of failing
+        #[arg(long)]
+        wait_for_ready: bool,
 
         /// Port to connect to
         #[arg(short, long, default_value = "19527")]
         port: u16,
 
+        /// Host the build server runs on
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+
         /// Maximum number of output lines to display (0 = unlimited).
-        /// When truncating, keeps first N/2 and last N/2 lines.
+        /// When truncating, keeps the first and last lines as split by
+        /// --keep-tail-ratio.
+        #[arg(short = 'l', long, default_value = "500")]
+        max_lines: usize,
+
+        /// Maximum bytes of output to display, split like --max-lines
+        /// (0 = unlimited); whichever limit is hit first truncates
+        #[arg(long, value_name = "BYTES", default_value = "0")]
+        max_bytes: usize,
+
+        /// Cut displayed lines longer than this many bytes (0 = never);
+        /// --log-file still gets them whole
+        #[arg(long, value_name = "BYTES", default_value = "0")]
+        max_line_length: usize,
+
+        /// Have the server leave out the middle of the output instead of
+        /// sending it all, to save bandwidth on a slow link. The end of the
+        /// output then only arrives once the build is over
+        #[arg(long, conflicts_with_all = ["log_file", "grep", "no_truncate"])]
+        server_truncate: bool,
+
+        /// Keep only the latest N lines on screen while the build runs,
+        /// redrawn in place, then print the usual truncated output. Only on
+        /// a terminal; otherwise output streams as usual
+        #[arg(long, value_name = "N", default_value = "0", conflicts_with = "quiet")]
+        live_tail: usize,
+
+        /// Leave out the closing line with the result, duration and error,
+        /// warning and line counts
+        #[arg(long)]
+        no_summary: bool,
+
+        /// Show all output without truncation
+        #[arg(long, default_value = "false")]
+        no_truncate: bool,
+
+        /// Share of --max-lines kept from the end of the output when
+        /// truncating, from 0.0 (only the start) to 1.0 (only the end)
+        #[arg(long, value_name = "RATIO", default_value = "0.5", value_parser = parse_ratio)]
+        keep_tail_ratio: f64,
+
+        /// Kill the build if it runs longer than this many seconds
+        /// (default: the server's --default-timeout; 0 = unlimited)
+        #[arg(short, long)]
+        timeout: Option<u64>,
+
+        /// Prefix each line with the time since the build started
+        #[arg(long)]
+        timestamps: bool,
+
+        /// Print the build id and process id when the build starts
+        #[arg(short, long)]
+        verbose: bool,
+
+        /// Print nothing if the build succeeds; if it fails, print the last
+        /// --max-lines lines of its output and the summary. --log-file
+        /// still gets every line
+        #[arg(short, long, conflicts_with_all = ["verbose", "detach"])]
+        quiet: bool,
+
+        /// Set an environment variable for this build (repeatable)
+        #[arg(short, long = "env", value_name = "KEY=VALUE", value_parser = parse_env_var)]
+        env: Vec<(String, String)>,
+
+        /// Write the complete, untruncated output to this file, as
+        /// `<file>.partial` until the build ends
+        #[arg(long, alias = "output-file")]
+        log_file: Option<PathBuf>,
+
+        /// Write stderr lines to this file instead of marking them in
+        /// --log-file
+        #[arg(long, value_name = "PATH", requires = "log_file")]
+        log_stderr: Option<PathBuf>,
+
+        /// Add this build's output to the end of the log files instead of
+        /// replacing them
+        #[arg(long, alias = "append", requires = "log_file")]
+        log_append: bool,
+
+        /// Output format: human-readable text, or one JSON object per event
+        #[arg(long, value_enum, default_value = "text")]
+        output: client::OutputFormat,
+
+        /// Send --dir to the server as given instead of resolving it locally
+        #[arg(long)]
+        no_canonicalize: bool,
+
+        /// Let the build finish even if this client exits; follow it again with `attach`
+        #[arg(long)]
+        keep_running: bool,
+
+        /// Print the build id and return at once, leaving the build to run on the server
+        #[arg(long)]
+        detach: bool,
+
+        /// Show the exact invocation the server would run, without running it
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Send this local file to the build as its stdin (otherwise the
+        /// build's stdin is empty)
+        #[arg(long, value_name = "PATH")]
+        stdin_file: Option<PathBuf>,
+
+        /// Only display output lines matching this regex; --log-file still
+        /// gets every line
+        #[arg(long, alias = "filter", value_name = "PATTERN", value_parser = Regex::new)]
+        grep: Option<Regex>,
+
+        /// Display the lines that don't match --grep instead
+        #[arg(long, requires = "grep")]
+        grep_invert: bool,
+
+        /// Also display this many lines before and after each --grep match
+        #[arg(short = 'C', long, value_name = "LINES", default_value = "0", requires = "grep")]
+        context: usize,
+
+        /// Filter before truncating, so --max-lines counts only the lines
+        /// --grep displays (by default it counts every line of output)
+        #[arg(long, requires = "grep")]
+        filter_before_truncate: bool,
+
+        /// Lines matching this regex count as errors, which are kept when
+        /// truncating and shown in red (default: compiler-style error lines)
+        #[arg(long, value_name = "PATTERN", value_parser = Regex::new)]
+        error_pattern: Option<Regex>,
+
+        /// Highlight errors in red, warnings in yellow and dim other stderr
+        /// lines: always, never, or on each stream that is a terminal
+        /// without NO_COLOR set
+        #[arg(long, value_enum, default_value = "auto")]
+        color: client::ColorChoice,
+
+        /// Have the server compress the output, for slow links (zstd unless
+        /// a codec is given); -v reports the bytes saved
+        #[arg(long, value_enum, value_name = "CODEC", num_args = 0..=1, default_missing_value = "zstd")]
+        compress: Option<Codec>,
+    },
+
+    /// Change or show the environment the server starts builds with
+    Env {
+        #[command(subcommand)]
+        action: EnvAction,
+
+        /// Port to connect to
+        #[arg(short, long, global = true, default_value = "19527")]
+        port: u16,
+
+        /// Host the build server runs on
+        #[arg(long, global = true, default_value = "127.0.0.1")]
+        host: String,
+    },
+
+    /// Wait for a build to finish and exit with its exit code, or without
+    /// --id, for the server to be ready for builds
+    Wait {
+        /// Build to wait for, as printed by `run --detach`
+        #[arg(long)]
+        id: Option<u64>,
+
+        /// Give up waiting for the server after this many seconds, exiting
+        /// with 124 (default: wait indefinitely)
+        #[arg(long, conflicts_with = "id")]
+        timeout: Option<u64>,
+
+        /// Milliseconds between checks on the server
+        #[arg(long, value_name = "MS", default_value = "250", conflicts_with = "id")]
+        interval: u64,
+
+        /// Output format: human-readable text, or one JSON object per event
+        #[arg(long, value_enum, default_value = "text")]
+        output: client::OutputFormat,
+
+        /// Port to connect to
+        #[arg(short, long, default_value = "19527")]
+        port: u16,
+
+        /// Host the build server runs on
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+    },
+
+    /// Follow the output of a build that is already running
+    Attach {
+        /// Build to attach to (default: the most recently started one)
+        #[arg(long)]
+        id: Option<u64>,
+
+        /// Replay buffered output starting at this line (0 = the first line)
+        #[arg(long, default_value = "0")]
+        from_line: u64,
+
+        /// Only replay the last N lines, like `tail -f` (0 = only new output)
+        #[arg(long, value_name = "N", conflicts_with = "from_line")]
+        tail: Option<u64>,
+
+        /// Maximum number of output lines to display (0 = unlimited)
         #[arg(short = 'l', long, default_value = "500")]
         max_lines: usize,
 
+        /// Maximum bytes of output to display, split like --max-lines
+        /// (0 = unlimited); whichever limit is hit first truncates
+        #[arg(long, value_name = "BYTES", default_value = "0")]
+        max_bytes: usize,
+
+        /// Cut displayed lines longer than this many bytes (0 = never);
+        /// --log-file still gets them whole
+        #[arg(long, value_name = "BYTES", default_value = "0")]
+        max_line_length: usize,
+
         /// Show all output without truncation
         #[arg(long, default_value = "false")]
         no_truncate: bool,
+
+        /// Share of --max-lines kept from the end of the output when
+        /// truncating (see `run --keep-tail-ratio`)
+        #[arg(long, value_name = "RATIO", default_value = "0.5", value_parser = parse_ratio)]
+        keep_tail_ratio: f64,
+
+        /// Prefix each line with the time since the build started
+        #[arg(long)]
+        timestamps: bool,
+
+        /// Highlight errors in red, warnings in yellow and dim other stderr
+        /// lines: always, never, or on each stream that is a terminal
+        /// without NO_COLOR set
+        #[arg(long, value_enum, default_value = "auto")]
+        color: client::ColorChoice,
+
+        /// Output format: human-readable text, or one JSON object per event
+        #[arg(long, value_enum, default_value = "text")]
+        output: client::OutputFormat,
+
+        /// Port to connect to
+        #[arg(short, long, default_value = "19527")]
+        port: u16,
+
+        /// Host the build server runs on
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
     },
 
     /// Check if the server is running
     Status {
+        /// Also show the last lines the init script printed
+        #[arg(short, long)]
+        verbose: bool,
+
+        /// Output format: human-readable text, or one JSON object per line
+        #[arg(long, value_enum, default_value = "text")]
+        output: client::OutputFormat,
+
         /// Port to check
         #[arg(short, long, default_value = "19527")]
         port: u16,
+
+        /// Host the build server runs on
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
     },
 
-    /// Stop the server
+    /// Stop the server once running builds finish
     Stop {
+        /// Kill running builds instead of waiting for them to finish
+        #[arg(long)]
+        force: bool,
+
+        /// Refuse new builds and exit once running ones finish; what `stop`
+        /// does without --force anyway, spelled out for scripts
+        #[arg(long, conflicts_with = "force")]
+        drain: bool,
+
+        /// Port to connect to
+        #[arg(short, long, default_value = "19527")]
+        port: u16,
+
+        /// Host the build server runs on
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+    },
+
+    /// Re-run the server's init script and use its environment for new builds
+    Reinit {
+        /// Port to connect to
+        #[arg(short, long, default_value = "19527")]
+        port: u16,
+
+        /// Host the build server runs on
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+    },
+
+    /// Print the complete output of a build, as saved by the server
+    Logs {
+        /// Build to show (default: the most recent one)
+        #[arg(long)]
+        id: Option<u64>,
+
+        /// Only print the last N lines
+        #[arg(short = 'n', long)]
+        tail: Option<usize>,
+
+        /// Port to connect to
+        #[arg(short, long, default_value = "19527")]
+        port: u16,
+
+        /// Host the build server runs on
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+    },
+
+    /// List the builds running on the server and those queued to start
+    List {
+        /// Output format: human-readable text, or one JSON object per line
+        #[arg(long, value_enum, default_value = "text")]
+        output: client::OutputFormat,
+
         /// Port to connect to
         #[arg(short, long, default_value = "19527")]
         port: u16,
+
+        /// Host the build server runs on
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+    },
+
+    /// Show recently finished builds
+    History {
+        /// Number of builds to show
+        #[arg(short = 'n', long, default_value = "20")]
+        limit: usize,
+
+        /// Output format: human-readable text, or one JSON object per line
+        #[arg(long, value_enum, default_value = "text")]
+        output: client::OutputFormat,
+
+        /// Port to connect to
+        #[arg(short, long, default_value = "19527")]
+        port: u16,
+
+        /// Host the build server runs on
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
+    },
+
+    /// Cancel a build running on the server
+    Cancel {
+        /// Id of the build to cancel (default: all running builds)
+        #[arg(long)]
+        id: Option<u64>,
+
+        /// Port to connect to
+        #[arg(short, long, default_value = "19527")]
+        port: u16,
+
+        /// Host the build server runs on
+        #[arg(long, default_value = "127.0.0.1")]
+        host: String,
     },
 }
 
+impl Commands {
+    /// Whether the command was asked for `--output json`
+    fn json_output(&self) -> bool {
+        matches!(
+            self,
+            Commands::Run { output, .. }
+                | Commands::Attach { output, .. }
+                | Commands::Wait { output, .. }
+                | Commands::Status { output, .. }
+                | Commands::List { output, .. }
+                | Commands::History { output, .. }
+                if *output == client::OutputFormat::Json
+        )
+    }
+}
+
+#[derive(Subcommand)]
+enum EnvAction {
+    /// Set variables for all later builds (lost on `reinit`)
+    Set {
+        #[arg(required = true, value_name = "KEY=VALUE", value_parser = parse_env_var)]
+        vars: Vec<(String, String)>,
+    },
+    /// Remove variables for all later builds
+    Unset {
+        #[arg(required = true, value_name = "KEY")]
+        keys: Vec<String>,
+    },
+    /// Print the build environment
+    Show {
+        /// Only variables whose names start with this
+        prefix: Option<String>,
+
+        /// Print commands that set the variables in a local shell
+        /// (`$env:KEY = ...` on Windows, `export KEY=...` elsewhere)
+        #[arg(long)]
+        export: bool,
+    },
+}
+
+/// Parse a `KEY=VALUE` pair for `--env`
+fn parse_env_var(spec: &str) -> Result<(String, String), String> {
+    match spec.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got '{}'", spec)),
+    }
+}
+
+/// Parse a fraction between 0 and 1 for `--keep-tail-ratio`
+fn parse_ratio(spec: &str) -> Result<f64, String> {
+    match spec.parse::<f64>() {
+        Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+        _ => Err(format!("expected a number from 0.0 to 1.0, got '{}'", spec)),
+    }
+}
+
+/// Read the `--command-stdin` command, without the trailing newline
+fn read_command_stdin(input: impl Read) -> Result<String> {
+    let command = std::io::read_to_string(input).context("Failed to read the command from stdin")?;
+    let command = command.trim_end_matches(['\r', '\n']);
+    if command.trim().is_empty() {
+        bail!("--command-stdin got an empty command");
+    }
+    Ok(command.to_string())
+}
+
+/// Read the token from `--auth-token-file`, without the trailing newline
+/// editors leave behind
+fn read_auth_token(path: &Path) -> Result<String> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read auth token from {}", path.display()))?;
+    let token = contents.trim_end_matches(['\r', '\n']);
+    if token.is_empty() {
+        bail!("Auth token file {} is empty", path.display());
+    }
+    Ok(token.to_string())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
+    let json = cli.command.json_output();
+    let exit_code = match run(cli).await {
+        Ok(exit_code) => exit_code,
+        // Scripts reading --output json get failures in the same form
+        Err(e) if json => {
+            client::print_json_error(&e);
+            1
+        }
+        Err(e) => return Err(e),
+    };
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+    Ok(())
+}
 
-    match cli.command {
-        Commands::Server { init, port } => {
-            server::run(init, port).await?;
+/// Carry out the command, returning the exit code it reports through
+async fn run(cli: Cli) -> Result<i32> {
+    let auth_token = match cli.auth_token_file {
+        Some(ref path) => Some(read_auth_token(path)?),
+        None => cli.auth_token.clone(),
+    };
+    let address = |host, port| match (&cli.socket, &cli.pipe) {
+        (Some(path), _) => transport::Address::Unix(path.clone()),
+        (None, Some(name)) => transport::Address::Pipe(name.clone()),
+        (None, None) => transport::Address::Tcp { host, port },
+    };
+    let tls = if cli.tls_ca.is_some() || cli.tls_insecure {
+        Some(tls::connector(cli.tls_ca.as_deref())?)
+    } else if cli.tls {
+        bail!("--tls needs --tls-ca with the server's certificate (or its CA), or --tls-insecure");
+    } else {
+        None
+    };
+    let endpoint = |host, port| client::Endpoint {
+        address: address(host, port),
+        tls: tls.clone(),
+        auth_token: auth_token.clone(),
+        connect_retries: cli.connect_retries,
+        connect_delay: Duration::from_millis(cli.connect_delay),
+        compression: None,
+    };
+
+    // Commands that report through their exit code return it rather than
+    // exiting, so everything they print is flushed and logged first
+    let exit_code = match cli.command {
+        Commands::Server {
+            init,
+            init_cmd,
+            init_timeout,
+            require_init,
+            default_command,
+            default_timeout,
+            heartbeat_interval,
+            stall_warn_secs,
+            stall_kill_secs,
+            port,
+            bind,
+            tls_cert,
+            tls_key,
+            shell,
+            shell_args,
+            allow_shell,
+            no_shell_chaining,
+            max_concurrent,
+            no_dir_lock,
+            drain_on_shutdown,
+            max_request_mb,
+            history_size,
+            log_dir,
+            keep_logs,
+            no_build_logs,
+            log_file,
+            log_max_bytes,
+        } => {
+            let shell_args =
+                shell_args.map(|args| args.split_whitespace().map(String::from).collect());
+            let shell = match shell {
+                Some(program) => Shell::new(program, shell_args),
+                None => Shell::platform_default(),
+            };
+            let log_dir = if no_build_logs {
+                None
+            } else {
+                log_dir.or_else(|| {
+                    dirs::data_local_dir().map(|dir| dir.join("build-runner").join("logs"))
+                })
+            };
+            server::run(server::ServerConfig {
+                init: match init_cmd {
+                    Some(command) => vec![InitSource::Command(command)],
+                    None => init.into_iter().map(InitSource::Script).collect(),
+                },
+                init_timeout: init_timeout.map(Duration::from_secs),
+                require_init,
+                default_command: Some(default_command).filter(|command| !command.trim().is_empty()),
+                default_timeout: (default_timeout > 0).then(|| Duration::from_secs(default_timeout)),
+                heartbeat_interval: (heartbeat_interval > 0)
+                    .then(|| Duration::from_secs(heartbeat_interval)),
+                stall_warn: stall_warn_secs.map(Duration::from_secs),
+                stall_kill: stall_kill_secs.map(Duration::from_secs),
+                address: address(bind, port),
+                tls: match (tls_cert, tls_key) {
+                    (Some(cert), Some(key)) => Some(tls::acceptor(&cert, &key)?),
+                    _ => None,
+                },
+                shell,
+                allowed_shells: allow_shell,
+                no_shell_chaining,
+                max_concurrent,
+                dir_lock: !no_dir_lock,
+                drain_on_shutdown,
+                max_request_bytes: max_request_mb.saturating_mul(1024 * 1024),
+                history_size,
+                auth_token: auth_token.clone(),
+                log_dir,
+                keep_logs,
+                log_file,
+                log_max_bytes,
+                catch_ctrl_c: true,
+            })
+            .await?;
+            0
         }
         Commands::Run {
             dir,
             command,
+            command_stdin,
+            args,
+            shell,
+            wait_for_ready,
             port,
+            host,
             max_lines,
+            max_bytes,
+            max_line_length,
+            server_truncate,
+            live_tail,
+            no_summary,
             no_truncate,
+            keep_tail_ratio,
+            timeout,
+            timestamps,
+            verbose,
+            quiet,
+            env,
+            log_file,
+            log_stderr,
+            log_append,
+            output,
+            no_canonicalize,
+            keep_running,
+            detach,
+            dry_run,
+            stdin_file,
+            grep,
+            grep_invert,
+            context,
+            filter_before_truncate,
+            error_pattern,
+            color,
+            compress,
+        } => {
+            let options = client::RunOptions {
+                max_lines: if no_truncate { 0 } else { max_lines },
+                max_bytes: if no_truncate { 0 } else { max_bytes },
+                max_line_length: if no_truncate { 0 } else { max_line_length },
+                server_truncate,
+                live_tail,
+                summary: !no_summary,
+                keep_tail_ratio,
+                timeout,
+                timestamps,
+                verbose,
+                env,
+                log_file,
+                log_stderr,
+                log_append,
+                format: output,
+                color,
+                canonicalize: !no_canonicalize,
+                keep_running,
+                detach,
+                dry_run,
+                stdin_file,
+                shell,
+                wait_for_ready,
+                grep,
+                grep_invert,
+                context,
+                filter_before_truncate,
+                error_pattern,
+                quiet,
+            };
+            let command = match args.split_first() {
+                Some((program, args)) => BuildCommand::Argv {
+                    program: program.clone(),
+                    args: args.to_vec(),
+                },
+                None if command_stdin => {
+                    let stdin = std::io::stdin();
+                    if stdin.is_terminal() {
+                        bail!("--command-stdin reads the command from a pipe or file, but stdin is a terminal");
+                    }
+                    BuildCommand::Shell(read_command_stdin(stdin)?)
+                }
+                // An empty command line asks for the server's default
+                None => BuildCommand::Shell(command.unwrap_or_default()),
+            };
+            let endpoint = client::Endpoint {
+                compression: compress,
+                ..endpoint(host, port)
+            };
+            client::run_build(dir, command, &endpoint, options).await?
+        }
+        Commands::Attach {
+            id,
+            from_line,
+            tail,
+            max_lines,
+            max_bytes,
+            max_line_length,
+            no_truncate,
+            keep_tail_ratio,
+            timestamps,
+            color,
+            output,
+            port,
+            host,
+        } => {
+            let options = client::RunOptions {
+                max_lines: if no_truncate { 0 } else { max_lines },
+                max_bytes: if no_truncate { 0 } else { max_bytes },
+                max_line_length: if no_truncate { 0 } else { max_line_length },
+                keep_tail_ratio,
+                timestamps,
+                // Say which build was picked when no --id was given
+                verbose: true,
+                format: output,
+                color,
+                ..Default::default()
+            };
+            let endpoint = endpoint(host, port);
+            client::attach_build(&endpoint, id, from_line, tail, options).await?
+        }
+        Commands::Env { action, port, host } => {
+            let endpoint = endpoint(host, port);
+            match action {
+                EnvAction::Set { vars } => {
+                    client::update_env(&endpoint, Request::SetEnv { vars }).await?;
+                }
+                EnvAction::Unset { keys } => {
+                    client::update_env(&endpoint, Request::UnsetEnv { keys }).await?;
+                }
+                EnvAction::Show { prefix, export } => {
+                    client::show_env(&endpoint, prefix, export).await?;
+                }
+            }
+            0
+        }
+        Commands::Wait {
+            id: None,
+            timeout,
+            interval,
+            port,
+            host,
+            ..
+        } => {
+            let timeout = timeout.map(Duration::from_secs);
+            let interval = Duration::from_millis(interval);
+            client::wait_ready(&endpoint(host, port), timeout, interval).await?
+        }
+        Commands::Wait {
+            id: Some(id),
+            output,
+            port,
+            host,
+            ..
+        } => {
+            let options = client::RunOptions {
+                format: output,
+                ..Default::default()
+            };
+            client::wait_build(&endpoint(host, port), id, options).await?
+        }
+        Commands::Status {
+            verbose,
+            output,
+            port,
+            host,
+        } => client::check_status(&endpoint(host, port), verbose, output).await?,
+        Commands::Stop {
+            force,
+            drain,
+            port,
+            host,
+        } => {
+            client::stop_server(&endpoint(host, port), force, drain).await?;
+            0
+        }
+        Commands::Reinit { port, host } => {
+            client::reinit_server(&endpoint(host, port)).await?;
+            0
+        }
+        Commands::Logs {
+            id,
+            tail,
+            port,
+            host,
         } => {
-            let limit = if no_truncate { 0 } else { max_lines };
-            client::run_build(dir, command, port, limit).await?;
+            client::show_log(&endpoint(host, port), id, tail).await?;
+            0
         }
-        Commands::Status { port } => {
-            client::check_status(port).await?;
+        Commands::List { output, port, host } => {
+            client::list_builds(&endpoint(host, port), output).await?;
+            0
         }
-        Commands::Stop { port } => {
-            client::stop_server(port).await?;
+        Commands::History {
+            limit,
+            output,
+            port,
+            host,
+        } => {
+            client::show_history(&endpoint(host, port), limit, output).await?;
+            0
         }
+        Commands::Cancel { id, port, host } => {
+            client::cancel_build(&endpoint(host, port), id).await?;
+            0
+        }
+    };
+
+    Ok(exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_tail_ratio_must_be_a_fraction() {
+        assert_eq!(parse_ratio("0"), Ok(0.0));
+        assert_eq!(parse_ratio("0.25"), Ok(0.25));
+        assert_eq!(parse_ratio("1.0"), Ok(1.0));
+        for spec in ["1.5", "-0.1", "half", "NaN"] {
+            assert_eq!(
+                parse_ratio(spec),
+                Err(format!("expected a number from 0.0 to 1.0, got '{}'", spec))
+            );
+        }
+
+        let args = |ratio| ["build-runner", "run", "-d", ".", "--keep-tail-ratio", ratio];
+        assert!(Cli::try_parse_from(args("0.8")).is_ok());
+        let error = Cli::try_parse_from(args("2")).err().unwrap();
+        assert_eq!(error.kind(), clap::error::ErrorKind::ValueValidation);
     }
 
-    Ok(())
+    #[test]
+    fn auth_token_file_loses_only_its_trailing_newline() {
+        let path = std::env::temp_dir().join(format!("build-runner-{}-token", std::process::id()));
+        std::fs::write(&path, "s3cret token\r\n").unwrap();
+        assert_eq!(read_auth_token(&path).unwrap(), "s3cret token");
+
+        std::fs::write(&path, "\n").unwrap();
+        let error = read_auth_token(&path).unwrap_err();
+        assert_eq!(error.to_string(), format!("Auth token file {} is empty", path.display()));
+
+        std::fs::remove_file(&path).unwrap();
+        let error = read_auth_token(&path).unwrap_err();
+        assert!(error.to_string().starts_with("Failed to read auth token from "), "{}", error);
+
+        let args = ["build-runner", "status", "--auth-token", "a", "--auth-token-file", "b"];
+        let error = Cli::try_parse_from(args).err().unwrap();
+        assert_eq!(error.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn command_stdin_is_read_whole_and_excludes_command() {
+        let command = read_command_stdin("make -j8 all \\\n    CFLAGS='-O2 -g'\r\n".as_bytes()).unwrap();
+        assert_eq!(command, "make -j8 all \\\n    CFLAGS='-O2 -g'");
+
+        let error = read_command_stdin(" \n\n".as_bytes()).unwrap_err();
+        assert_eq!(error.to_string(), "--command-stdin got an empty command");
+
+        let args = ["build-runner", "run", "-d", ".", "--command-stdin", "--command", "make"];
+        let error = Cli::try_parse_from(args).err().unwrap();
+        assert_eq!(error.kind(), clap::error::ErrorKind::ArgumentConflict);
+        assert!(Cli::try_parse_from(&args[..5]).is_ok());
+    }
+
+    #[test]
+    fn invalid_grep_is_rejected_before_connecting() {
+        let args = |pattern| ["build-runner", "run", "-d", ".", "--grep", pattern];
+        assert!(Cli::try_parse_from(args("error|warning")).is_ok());
+        let error = Cli::try_parse_from(args("error(")).err().unwrap();
+        assert_eq!(error.kind(), clap::error::ErrorKind::ValueValidation);
+    }
 }
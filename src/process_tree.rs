@@ -0,0 +1,174 @@
+use tokio::process::Child;
+
+/// Everything a spawned build or init script starts, so it can be killed as
+/// a whole. Killing only the shell would leave msbuild/cl.exe workers running.
+///
+/// On Unix the child leads its own process group (it must be spawned with
+/// `process_group(0)`) and the group is signalled. On Windows the child is
+/// put in a Job Object set to kill on close: descendants stay in the job even
+/// after their parent exits, and whatever is left in it dies once the tree is
+/// dropped, including when the server itself goes away.
+pub struct ProcessTree {
+    #[cfg(windows)]
+    job: Option<job::JobObject>,
+}
+
+impl ProcessTree {
+    pub fn new(child: &Child) -> Self {
+        #[cfg(windows)]
+        let job = job::JobObject::assign(child)
+            .map_err(|e| {
+                eprintln!(
+                    "Failed to put pid {} in a job object, falling back to taskkill: {}",
+                    child.id().unwrap_or_default(),
+                    e
+                )
+            })
+            .ok();
+        #[cfg(not(windows))]
+        let _ = child;
+
+        Self {
+            #[cfg(windows)]
+            job,
+        }
+    }
+
+    /// Kill `child` along with everything it spawned, then reap it
+    pub async fn kill(&self, child: &mut Child) {
+        #[cfg(unix)]
+        if let Some(pid) = child.id() {
+            // SAFETY: plain syscall; a negative pid targets the process group
+            unsafe {
+                libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+            }
+        }
+
+        #[cfg(windows)]
+        match self.job {
+            Some(ref job) => job.terminate(),
+            None => {
+                if let Some(pid) = child.id() {
+                    let _ = tokio::process::Command::new("taskkill")
+                        .args(["/F", "/T", "/PID", &pid.to_string()])
+                        .stdout(std::process::Stdio::null())
+                        .stderr(std::process::Stdio::null())
+                        .status()
+                        .await;
+                }
+            }
+        }
+
+        // Reap the child (and kill it directly if nothing above got it)
+        let _ = child.kill().await;
+    }
+}
+
+#[cfg(windows)]
+mod job {
+    use std::io;
+    use tokio::process::Child;
+    use windows_sys::Win32::Foundation::{CloseHandle, HANDLE};
+    use windows_sys::Win32::System::JobObjects::{
+        AssignProcessToJobObject, CreateJobObjectW, JobObjectExtendedLimitInformation,
+        SetInformationJobObject, TerminateJobObject, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+        JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+    };
+
+    /// Owned handle to a Job Object that kills its processes when closed
+    pub struct JobObject(HANDLE);
+
+    // SAFETY: job handles may be used and closed from any thread
+    unsafe impl Send for JobObject {}
+    unsafe impl Sync for JobObject {}
+
+    impl JobObject {
+        /// Create a job and move `child` into it. Processes the child starts
+        /// from then on join the job too.
+        pub fn assign(child: &Child) -> io::Result<Self> {
+            let process = child
+                .raw_handle()
+                .ok_or_else(|| io::Error::other("the process has already exited"))?;
+
+            // SAFETY: the handles passed in are valid for the duration of each
+            // call, and `limits` outlives `SetInformationJobObject`
+            unsafe {
+                let handle = CreateJobObjectW(std::ptr::null(), std::ptr::null());
+                if handle.is_null() {
+                    return Err(io::Error::last_os_error());
+                }
+                // Closed again by `Drop` if anything below fails
+                let job = JobObject(handle);
+
+                let mut limits = JOBOBJECT_EXTENDED_LIMIT_INFORMATION::default();
+                limits.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+                let set = SetInformationJobObject(
+                    handle,
+                    JobObjectExtendedLimitInformation,
+                    &limits as *const JOBOBJECT_EXTENDED_LIMIT_INFORMATION as *const _,
+                    std::mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+                );
+                if set == 0 || AssignProcessToJobObject(handle, process) == 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(job)
+            }
+        }
+
+        /// Kill every process in the job
+        pub fn terminate(&self) {
+            // SAFETY: `self.0` is a job handle we own
+            unsafe {
+                TerminateJobObject(self.0, 1);
+            }
+        }
+    }
+
+    impl Drop for JobObject {
+        fn drop(&mut self) {
+            // SAFETY: `self.0` is a job handle we own and nothing uses it after this
+            unsafe {
+                CloseHandle(self.0);
+            }
+        }
+    }
+}
+
+// Reads process states from /proc
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::process::Stdio;
+    use tokio::io::AsyncBufReadExt;
+
+    /// Whether `pid` is still running: not gone and not a zombie waiting to
+    /// be reaped by whoever inherited it
+    fn running(pid: &str) -> bool {
+        std::fs::read_to_string(format!("/proc/{}/stat", pid))
+            .is_ok_and(|stat| stat.rsplit_once(") ").is_some_and(|(_, rest)| !rest.starts_with('Z')))
+    }
+
+    #[tokio::test]
+    async fn kill_takes_down_grandchildren() {
+        let mut child = tokio::process::Command::new("sh")
+            .args(["-c", "sleep 30 & echo $!; wait"])
+            .stdout(Stdio::piped())
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        let tree = ProcessTree::new(&child);
+        let mut stdout = tokio::io::BufReader::new(child.stdout.take().unwrap()).lines();
+        let grandchild = stdout.next_line().await.unwrap().unwrap();
+        assert!(running(&grandchild));
+
+        tree.kill(&mut child).await;
+        assert!(child.try_wait().unwrap().is_some());
+        for _ in 0..50 {
+            if !running(&grandchild) {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        panic!("pid {} outlived the kill", grandchild);
+    }
+}
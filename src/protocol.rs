@@ -1,43 +1,662 @@
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::path::PathBuf;
 
+pub mod codec;
+
+pub use codec::{
+    write_frame, write_frame_with, Codec, FrameReader, FrameTooLarge, TruncatedFrame,
+    DEFAULT_MAX_FRAME_BYTES,
+};
+
+/// Server-assigned identifier of a build
+pub type BuildId = u64;
+
+/// Bumped when a change to the messages below would make an older client or
+/// server misread them. New fields with `#[serde(default)]` don't count, and
+/// neither do new variants: unknown requests get an `Error` and clients skip
+/// unknown responses, so only add responses a client can safely miss.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Version of this build-runner binary, exchanged in `Hello`
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// What to tell the user when client and server can't talk to each other
+pub fn version_mismatch(client_version: &str, server_version: &str) -> String {
+    format!(
+        "client is v{} but server is v{}, restart the server with the client's version",
+        client_version, server_version
+    )
+}
+
+/// Name of a Unix signal that ended a build, e.g. `SIGKILL`. Only the
+/// signals numbered alike on every Unix get a name, since the client may not
+/// run on the server's platform.
+pub fn signal_name(signal: i32) -> String {
+    let name = match signal {
+        1 => "SIGHUP",
+        2 => "SIGINT",
+        3 => "SIGQUIT",
+        4 => "SIGILL",
+        5 => "SIGTRAP",
+        6 => "SIGABRT",
+        8 => "SIGFPE",
+        9 => "SIGKILL",
+        11 => "SIGSEGV",
+        13 => "SIGPIPE",
+        14 => "SIGALRM",
+        15 => "SIGTERM",
+        _ => return format!("signal {}", signal),
+    };
+    name.to_string()
+}
+
+/// Output truncation done by the server (`run --server-truncate`): it sends
+/// the first `head` lines, then a `Truncated` marker and the last `tail`
+/// lines once the build ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerTruncation {
+    pub head: usize,
+    pub tail: usize,
+}
+
+/// A line of build output, tagged with the pipe it came from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutputLine {
+    pub line: String,
+    pub is_stderr: bool,
+    /// Milliseconds since the build started, taken when the server read the line
+    #[serde(default)]
+    pub elapsed_ms: u64,
+}
+
+/// What a build runs: a command line for the server's shell, or a program
+/// and arguments passed to it as they are
+#[derive(Debug, Clone)]
+pub enum BuildCommand {
+    Shell(String),
+    Argv { program: String, args: Vec<String> },
+}
+
+impl fmt::Display for BuildCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildCommand::Shell(line) => f.write_str(line),
+            BuildCommand::Argv { program, args } => {
+                f.write_str(program)?;
+                for arg in args {
+                    // Quote only where needed to show where an argument ends
+                    if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '"') {
+                        write!(f, " {:?}", arg)?;
+                    } else {
+                        write!(f, " {}", arg)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
 /// Request from client to server
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
+    /// First frame of every connection, ahead of `Auth`; the server answers
+    /// with its own `Hello`, or an `Error` if the protocols don't match.
+    /// Clients from before the handshake skip it.
+    Hello {
+        protocol: u32,
+        version: String,
+        /// Ask for build output to be compressed (`run --compress`)
+        #[serde(default)]
+        compression: Option<Codec>,
+    },
+    /// Shared secret sent ahead of the real request when the client has an
+    /// `--auth-token`
+    Auth {
+        token: String,
+    },
     /// Execute a build command
     Build {
-        /// Working directory
+        /// Working directory; a relative path is resolved against the
+        /// server's own working directory, so clients normally send an
+        /// absolute one
         dir: PathBuf,
-        /// Command to execute
+        /// Command to execute; empty runs the server's default command
         command: String,
+        /// Kill the build if it runs longer than this many seconds
+        /// (`None` = the server's default, 0 = unlimited)
+        #[serde(default)]
+        timeout: Option<u64>,
+        /// Extra environment variables, applied on top of the server's environment
+        #[serde(default)]
+        env: Vec<(String, String)>,
+        /// Lines the client will display before truncating (0 = unlimited),
+        /// recorded so the build history can say whether output was cut
+        #[serde(default)]
+        max_lines: usize,
+        /// Keep the build running if this connection drops, so it can be
+        /// picked up again with `Attach`
+        #[serde(default)]
+        keep_running: bool,
+        /// Reply with the invocation the server would run instead of running it
+        #[serde(default)]
+        dry_run: bool,
+        /// Written to the build's stdin, which is then closed (`None` = no input)
+        #[serde(default)]
+        stdin: Option<Vec<u8>>,
+        /// Run the command with this shell instead of the server's; it must
+        /// be the server's own or one it allows with `--allow-shell`
+        #[serde(default)]
+        shell: Option<String>,
+        /// Leave out the middle of the output instead of sending it all
+        #[serde(default)]
+        server_truncate: Option<ServerTruncation>,
+    },
+    /// Execute `program` with `args` directly, without a shell re-parsing them.
+    /// The remaining fields are as for `Build`.
+    BuildArgv {
+        dir: PathBuf,
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default)]
+        timeout: Option<u64>,
+        #[serde(default)]
+        env: Vec<(String, String)>,
+        #[serde(default)]
+        max_lines: usize,
+        #[serde(default)]
+        keep_running: bool,
+        #[serde(default)]
+        dry_run: bool,
+        #[serde(default)]
+        stdin: Option<Vec<u8>>,
+        #[serde(default)]
+        server_truncate: Option<ServerTruncation>,
+    },
+    /// Set variables in the environment new builds start with
+    SetEnv { vars: Vec<(String, String)> },
+    /// Remove variables from the environment new builds start with
+    UnsetEnv { keys: Vec<String> },
+    /// List the environment new builds start with, optionally only the
+    /// variables whose names start with `prefix`
+    GetEnv {
+        #[serde(default)]
+        prefix: Option<String>,
+    },
+    /// Reply with a build's final response once it ends, or straight away
+    /// if it already has
+    Wait { id: BuildId },
+    /// Follow a running build's output (the most recent build when no id is
+    /// given): buffered lines from `from_line` on, then live output until it ends
+    Attach {
+        #[serde(default)]
+        id: Option<BuildId>,
+        #[serde(default)]
+        from_line: u64,
+        /// Only replay this many of the latest lines instead (0 = only new output)
+        #[serde(default)]
+        tail: Option<u64>,
     },
     /// Check server status
     Status,
-    /// Stop the server
-    Stop,
+    /// Stop the server once running builds finish, or kill them with `force`
+    Stop {
+        #[serde(default)]
+        force: bool,
+        /// Sent by `stop --drain`; a stop without `force` drains anyway
+        #[serde(default)]
+        drain: bool,
+    },
+    /// Cancel a running build (all running builds when no id is given)
+    Cancel {
+        #[serde(default)]
+        build_id: Option<BuildId>,
+    },
+    /// Re-run the server's init script and replace the build environment.
+    /// The script's output streams back as `Output`, ending in
+    /// `ReinitComplete` or an `Error`; builds requested meanwhile get an `Error`.
+    Reinit,
+    /// List the builds currently running
+    ListBuilds,
+    /// Stream a build's saved output (the most recent build when no id is
+    /// given), from its log file or, for the last few builds, server memory
+    GetLog {
+        #[serde(default)]
+        id: Option<BuildId>,
+        /// Only the last this many lines
+        #[serde(default)]
+        tail: Option<usize>,
+    },
+    /// Recently finished builds, newest first
+    History {
+        /// Return at most this many entries (default: all that are kept)
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+}
+
+/// One running build, as reported by `ListBuilds` and `Status`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildSummary {
+    pub id: BuildId,
+    pub dir: PathBuf,
+    pub command: String,
+    pub elapsed_secs: u64,
+    /// Address of the client that requested the build
+    pub client: String,
+    /// Server-side process id of the build's shell
+    #[serde(default)]
+    pub pid: Option<u32>,
+    /// Still waiting for its directory or a build slot; `elapsed_secs` is
+    /// then the time waited so far
+    #[serde(default)]
+    pub queued: bool,
+}
+
+/// How a finished build ended
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum BuildResult {
+    Exited(i32),
+    Cancelled,
+    TimedOut,
+    /// The client went away and the build was killed
+    Disconnected,
+    /// Killed after printing nothing for this many seconds (`--stall-kill-secs`)
+    Stalled(u64),
+    /// The server couldn't wait for the process to exit and killed it
+    Lost,
+}
+
+impl fmt::Display for BuildResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildResult::Exited(code) => write!(f, "{}", code),
+            BuildResult::Cancelled => f.write_str("cancelled"),
+            BuildResult::TimedOut => f.write_str("timed out"),
+            BuildResult::Disconnected => f.write_str("disconnected"),
+            BuildResult::Stalled(secs) => write!(f, "stalled for {}s", secs),
+            BuildResult::Lost => f.write_str("lost"),
+        }
+    }
+}
+
+/// Reply to `Status`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerStatus {
+    pub initialized: bool,
+    /// Version of the server binary
+    #[serde(default)]
+    pub version: String,
+    /// Machine the server runs on
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// Seconds since the server came up
+    #[serde(default)]
+    pub uptime_secs: u64,
+    /// Builds started since the server came up
+    #[serde(default)]
+    pub builds_started: u64,
+    /// Builds running now, oldest first
+    #[serde(default)]
+    pub running: Vec<BuildSummary>,
+    /// `--init` scripts the server runs, in order
+    #[serde(default)]
+    pub init_scripts: Vec<String>,
+    /// `--init-cmd` command line the server runs instead of a script
+    #[serde(default)]
+    pub init_command: Option<String>,
+    /// Number of environment variables captured from the init script
+    #[serde(default)]
+    pub env_vars: usize,
+    /// Shell (and its flags) used to run builds
+    #[serde(default)]
+    pub shell: String,
+    /// Other shells a build may ask for (`--allow-shell`)
+    #[serde(default)]
+    pub allowed_shells: Vec<String>,
+    /// Command run for builds that don't give one (`--default-command`)
+    #[serde(default)]
+    pub default_command: Option<String>,
+    /// Cancelled, timed-out and abandoned builds are not counted
+    #[serde(default)]
+    pub last_build: Option<LastBuild>,
+    /// How long the latest init script run took
+    #[serde(default)]
+    pub init_duration_secs: Option<f64>,
+    /// Last lines that run printed
+    #[serde(default)]
+    pub init_output_tail: Vec<String>,
+    /// Why the init script failed, if builds are refused because of it
+    #[serde(default)]
+    pub init_error: Option<String>,
+    /// The init script is being re-run; builds are refused until it finishes
+    #[serde(default)]
+    pub init_running: bool,
+}
+
+/// The most recent build that ran to completion, as reported by `Status`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LastBuild {
+    pub id: BuildId,
+    pub command: String,
+    pub exit_code: i32,
+    /// Seconds since the Unix epoch
+    pub finished_at: u64,
+}
+
+/// A finished build, as reported by `History`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: BuildId,
+    /// Seconds since the Unix epoch
+    pub started_at: u64,
+    pub dir: PathBuf,
+    pub command: String,
+    pub duration_ms: u64,
+    pub result: BuildResult,
+    /// More lines were produced than the client displayed
+    pub truncated: bool,
+    #[serde(default)]
+    pub stdout_lines: u64,
+    #[serde(default)]
+    pub stderr_lines: u64,
+    /// Signal that killed the build, for `Exited(-1)` on Unix
+    #[serde(default)]
+    pub signal: Option<i32>,
+    /// Time waited for a build slot or directory lock before starting
+    #[serde(default)]
+    pub queued_ms: u64,
 }
 
 /// Response from server to client
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Response {
-    /// Build output line (stdout or stderr)
+    /// Answer to `Hello`
+    Hello {
+        protocol: u32,
+        version: String,
+        /// The compression the server agreed to; `None` (as from servers
+        /// that don't compress) means output comes uncompressed
+        #[serde(default)]
+        compression: Option<Codec>,
+    },
+    /// Build request passed validation and has an id; it may still have to
+    /// wait for its directory or a slot
+    Accepted { build_id: BuildId },
+    /// Build is waiting for a free slot (`position` 1 = next in line)
+    Queued {
+        /// Id the build will run under; it can be cancelled by id while queued
+        #[serde(default)]
+        build_id: BuildId,
+        position: usize,
+    },
+    /// Build process was spawned
+    BuildStarted {
+        build_id: BuildId,
+        /// OS process id of the shell running the build, if still known
+        #[serde(default)]
+        pid: Option<u32>,
+    },
+    /// Build output line (stdout or stderr).
+    ///
+    /// Lines arrive in the order the server read them from the child's pipes.
+    /// stdout and stderr are separate pipes, so lines written to both at
+    /// effectively the same instant can still swap places.
     Output {
         line: String,
         is_stderr: bool,
+        /// Milliseconds since the build started, taken when the server read the line
+        #[serde(default)]
+        elapsed_ms: u64,
+    },
+    /// Several output lines at once, in order, each as `Output` would carry
+    /// it. Sent instead of `Output` to clients that sent `Hello`, so a
+    /// chatty build doesn't cost a frame and a flush per line.
+    OutputBatch {
+        lines: Vec<OutputLine>,
+    },
+    /// This many lines (of `skipped_bytes` bytes) were left out here, as
+    /// the build's `server_truncate` asked; only sent to that client
+    Truncated {
+        skipped: u64,
+        #[serde(default)]
+        skipped_bytes: u64,
     },
     /// Build completed
     BuildComplete {
+        #[serde(default)]
+        build_id: BuildId,
         exit_code: i32,
+        #[serde(default)]
+        duration_ms: u64,
+        #[serde(default)]
+        stdout_lines: u64,
+        #[serde(default)]
+        stderr_lines: u64,
+        /// Signal that killed the build on a Unix server; `exit_code` is
+        /// then -1
+        #[serde(default)]
+        signal: Option<i32>,
+        /// Time spent waiting for a build slot or directory lock before
+        /// the build started, on top of `duration_ms`
+        #[serde(default)]
+        queued_ms: u64,
+    },
+    /// Server status (boxed to keep the other responses small; the JSON is
+    /// the same as for an inline struct)
+    Status(Box<ServerStatus>),
+    /// Build was killed after exceeding its timeout
+    Timeout {
+        elapsed_secs: u64,
+    },
+    /// Sent to a build's client every `--heartbeat-interval` seconds; a
+    /// failed send tells the server the client is gone
+    Heartbeat,
+    /// Build was killed after producing no output for `idle_secs`
+    Stalled {
+        idle_secs: u64,
+    },
+    /// Server is stopping; sent again to the `Stop` connection each time
+    /// the number of running builds changes
+    Stopping {
+        /// Builds still running when the stop was requested
+        active_builds: usize,
     },
-    /// Server status
-    Status {
-        initialized: bool,
-        init_script: Option<String>,
+    /// `SetEnv` or `UnsetEnv` applied; `env_vars` is the new total
+    EnvUpdated { env_vars: usize },
+    /// Response to `GetEnv`, sorted by name
+    Env { vars: Vec<(String, String)> },
+    /// Init script re-ran successfully (its output precedes this as `Output`)
+    ReinitComplete {
+        env_vars: usize,
     },
-    /// Server is stopping
-    Stopping,
+    /// End of a log sent as `Output` lines in reply to `GetLog`
+    LogComplete {
+        build_id: BuildId,
+    },
+    /// Builds currently running, oldest first
+    Builds {
+        builds: Vec<BuildSummary>,
+    },
+    /// Finished builds, newest first
+    History {
+        builds: Vec<HistoryEntry>,
+    },
+    /// The connection did not present the server's `--auth-token`; the
+    /// server closes the connection after sending this
+    Unauthorized,
+    /// The build was refused because the server has no environment to run
+    /// it in yet, e.g. while `reinit` re-runs the init script. Clients that
+    /// didn't send `Hello` get an `Error` instead.
+    NotReady { reason: String },
+    /// Build was cancelled (sent to both the building and the cancelling client)
+    Cancelled,
     /// Error occurred
     Error {
         message: String,
     },
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_from_clients_without_stdin_still_parse() {
+        // As sent by clients from before `--stdin-file`
+        let json = r#"{"Build":{"dir":"/src/app","command":"make","timeout":null,"env":[],"max_lines":50,"keep_running":false,"dry_run":false}}"#;
+        match serde_json::from_str::<Request>(json).unwrap() {
+            Request::Build { command, max_lines, stdin, shell, server_truncate, .. } => {
+                assert_eq!((command.as_str(), max_lines), ("make", 50));
+                assert!(stdin.is_none() && shell.is_none() && server_truncate.is_none());
+            }
+            other => panic!("expected a build, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn responses_from_older_and_newer_servers_still_parse() {
+        // From the first servers, before build ids, line counts and status details
+        let json = r#"{"BuildComplete":{"exit_code":2}}"#;
+        match serde_json::from_str::<Response>(json).unwrap() {
+            Response::BuildComplete { build_id, exit_code, duration_ms, signal, queued_ms, .. } => {
+                assert_eq!((build_id, exit_code, duration_ms, queued_ms), (0, 2, 0, 0));
+                assert_eq!(signal, None);
+            }
+            other => panic!("expected a completion, got {:?}", other),
+        }
+        let json = r#"{"Status":{"initialized":true}}"#;
+        match serde_json::from_str::<Response>(json).unwrap() {
+            Response::Status(status) => {
+                assert!(status.initialized && status.version.is_empty());
+                assert!(status.hostname.is_none() && status.running.is_empty());
+            }
+            other => panic!("expected a status, got {:?}", other),
+        }
+
+        // Fields a newer server added are ignored
+        let json = r#"{"Output":{"line":"ok","is_stderr":false,"elapsed_ms":5,"color":"green"}}"#;
+        assert!(matches!(
+            serde_json::from_str::<Response>(json).unwrap(),
+            Response::Output { ref line, elapsed_ms: 5, .. } if line == "ok"
+        ));
+        // while a response it added is a JSON error the client can skip
+        let error = serde_json::from_str::<Response>(r#"{"Progress":{"percent":40}}"#).unwrap_err();
+        assert!(error.is_data());
+    }
+    /// `value` serializes to exactly `json`, and `json` parses back to it
+    fn assert_wire_format<T>(value: &T, json: &str)
+    where
+        T: Serialize + serde::de::DeserializeOwned,
+    {
+        assert_eq!(serde_json::to_string(value).unwrap(), json);
+        let parsed: T = serde_json::from_str(json).unwrap();
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+    }
+
+    // Older binaries read exactly these; a change here breaks them
+    #[test]
+    fn requests_keep_their_wire_format() {
+        let hello = Request::Hello {
+            protocol: 1,
+            version: "0.2.1".to_string(),
+            compression: Some(Codec::Zstd),
+        };
+        assert_wire_format(&hello, r#"{"Hello":{"protocol":1,"version":"0.2.1","compression":"zstd"}}"#);
+
+        let build = Request::Build {
+            dir: PathBuf::from("/src/app"),
+            command: "make all".to_string(),
+            timeout: Some(600),
+            env: vec![("CC".to_string(), "clang".to_string())],
+            max_lines: 50,
+            keep_running: true,
+            dry_run: false,
+            stdin: Some(b"y\n".to_vec()),
+            shell: Some("bash".to_string()),
+            server_truncate: Some(ServerTruncation { head: 25, tail: 25 }),
+        };
+        assert_wire_format(
+            &build,
+            r#"{"Build":{"dir":"/src/app","command":"make all","timeout":600,"env":[["CC","clang"]],"max_lines":50,"keep_running":true,"dry_run":false,"stdin":[121,10],"shell":"bash","server_truncate":{"head":25,"tail":25}}}"#,
+        );
+
+        let build_argv = Request::BuildArgv {
+            dir: PathBuf::from("/src/app"),
+            program: "cargo".to_string(),
+            args: vec!["build".to_string(), "--release".to_string()],
+            timeout: None,
+            env: Vec::new(),
+            max_lines: 0,
+            keep_running: false,
+            dry_run: true,
+            stdin: None,
+            server_truncate: None,
+        };
+        assert_wire_format(
+            &build_argv,
+            r#"{"BuildArgv":{"dir":"/src/app","program":"cargo","args":["build","--release"],"timeout":null,"env":[],"max_lines":0,"keep_running":false,"dry_run":true,"stdin":null,"server_truncate":null}}"#,
+        );
+
+        let stop = Request::Stop { force: false, drain: true };
+        assert_wire_format(&stop, r#"{"Stop":{"force":false,"drain":true}}"#);
+        assert_wire_format(&Request::Wait { id: 7 }, r#"{"Wait":{"id":7}}"#);
+        assert_wire_format(&Request::Status, r#""Status""#);
+    }
+
+    #[test]
+    fn responses_keep_their_wire_format() {
+        let hello = Response::Hello {
+            protocol: 1,
+            version: "0.2.1".to_string(),
+            compression: None,
+        };
+        assert_wire_format(&hello, r#"{"Hello":{"protocol":1,"version":"0.2.1","compression":null}}"#);
+
+        let output = Response::Output {
+            line: "error: \"x\"".to_string(),
+            is_stderr: true,
+            elapsed_ms: 1500,
+        };
+        assert_wire_format(
+            &output,
+            r#"{"Output":{"line":"error: \"x\"","is_stderr":true,"elapsed_ms":1500}}"#,
+        );
+
+        let batch = Response::OutputBatch {
+            lines: vec![
+                OutputLine {
+                    line: "a".to_string(),
+                    is_stderr: false,
+                    elapsed_ms: 1,
+                },
+                OutputLine {
+                    line: "b".to_string(),
+                    is_stderr: true,
+                    elapsed_ms: 2,
+                },
+            ],
+        };
+        assert_wire_format(
+            &batch,
+            r#"{"OutputBatch":{"lines":[{"line":"a","is_stderr":false,"elapsed_ms":1},{"line":"b","is_stderr":true,"elapsed_ms":2}]}}"#,
+        );
+
+        let complete = Response::BuildComplete {
+            build_id: 3,
+            exit_code: -1,
+            duration_ms: 2500,
+            stdout_lines: 10,
+            stderr_lines: 2,
+            signal: Some(9),
+            queued_ms: 40,
+        };
+        assert_wire_format(
+            &complete,
+            r#"{"BuildComplete":{"build_id":3,"exit_code":-1,"duration_ms":2500,"stdout_lines":10,"stderr_lines":2,"signal":9,"queued_ms":40}}"#,
+        );
+        assert_wire_format(&Response::Heartbeat, r#""Heartbeat""#);
+    }
+}
@@ -0,0 +1,342 @@
+//! Framing of protocol messages on the wire: each is a 4-byte big-endian
+//! length followed by its JSON payload, optionally compressed.
+
+use anyhow::Result;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Read, Write};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// How frames may be compressed, agreed in `Hello`. Only build output is
+/// compressed, and only frames big enough to gain from it; readers tell a
+/// compressed payload from JSON by the codec's magic number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Zstd,
+    Gzip,
+}
+
+/// Payloads shorter than this are sent as they are
+const COMPRESS_MIN_BYTES: usize = 512;
+
+impl Codec {
+    /// The codec that produced `payload`, if it is compressed at all
+    fn detect(payload: &[u8]) -> Option<Codec> {
+        [Codec::Zstd, Codec::Gzip]
+            .into_iter()
+            .find(|codec| payload.starts_with(codec.magic()))
+    }
+
+    fn magic(self) -> &'static [u8] {
+        match self {
+            Codec::Zstd => &[0x28, 0xb5, 0x2f, 0xfd],
+            Codec::Gzip => &[0x1f, 0x8b],
+        }
+    }
+
+    fn compress(self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::Zstd => zstd::bulk::compress(payload, zstd::DEFAULT_COMPRESSION_LEVEL),
+            Codec::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+                encoder.write_all(payload)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Decompress `payload`, refusing to expand it past `limit` bytes
+    fn decompress(self, payload: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        let decoder: Box<dyn Read + '_> = match self {
+            Codec::Zstd => Box::new(zstd::Decoder::new(payload)?),
+            Codec::Gzip => Box::new(GzDecoder::new(payload)),
+        };
+        let mut decompressed = Vec::new();
+        decoder.take(limit as u64 + 1).read_to_end(&mut decompressed)?;
+        if decompressed.len() > limit {
+            let message = format!("compressed message expands past the {}-byte limit", limit);
+            return Err(io::Error::other(message));
+        }
+        Ok(decompressed)
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Codec::Zstd => "zstd",
+            Codec::Gzip => "gzip",
+        })
+    }
+}
+
+/// Write `message` as one frame: a 4-byte big-endian length followed by the JSON payload
+pub async fn write_frame<W, T>(writer: &mut W, message: &T) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    write_frame_with(writer, message, None).await
+}
+
+/// [`write_frame`], compressing the payload with `compression` when it is
+/// long enough to be worth it
+pub async fn write_frame_with<W, T>(
+    writer: &mut W,
+    message: &T,
+    compression: Option<Codec>,
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let mut payload = serde_json::to_vec(message)?;
+    if let Some(codec) = compression.filter(|_| payload.len() >= COMPRESS_MIN_BYTES) {
+        payload = codec.compress(&payload)?;
+    }
+    let len = u32::try_from(payload.len())?;
+    writer.write_all(&len.to_be_bytes()).await?;
+    writer.write_all(&payload).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Largest frame a [`FrameReader`] accepts unless given another limit
+pub const DEFAULT_MAX_FRAME_BYTES: usize = 64 * 1024 * 1024;
+
+/// The peer announced a frame over the reader's limit. Its payload is left
+/// unread, so nothing more can be read from the connection.
+#[derive(Debug)]
+pub struct FrameTooLarge {
+    pub len: usize,
+    pub limit: usize,
+}
+
+impl fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "message of {} bytes exceeds the {}-byte limit", self.len, self.limit)
+    }
+}
+
+impl std::error::Error for FrameTooLarge {}
+
+/// The peer closed the connection partway through a frame
+#[derive(Debug)]
+pub struct TruncatedFrame {
+    /// Bytes received of the frame, length prefix included
+    pub received: usize,
+}
+
+impl fmt::Display for TruncatedFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "connection closed in the middle of a message ({} bytes received)",
+            self.received
+        )
+    }
+}
+
+impl std::error::Error for TruncatedFrame {}
+
+/// Reads length-prefixed frames written by [`write_frame`].
+///
+/// Partially received frames stay buffered between calls, so `read_frame`
+/// is safe to use as a `tokio::select!` branch.
+pub struct FrameReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    /// Frames announcing more than this fail with [`FrameTooLarge`] before
+    /// any of the payload is buffered
+    max_frame_bytes: usize,
+    /// Frame bytes read so far, as they came over the connection...
+    received_bytes: u64,
+    /// ...and as they would have been without compression
+    decoded_bytes: u64,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+            max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+            received_bytes: 0,
+            decoded_bytes: 0,
+        }
+    }
+
+    /// Bytes of the frames read so far as sent, and as they would have
+    /// been uncompressed
+    pub fn bytes_read(&self) -> (u64, u64) {
+        (self.received_bytes, self.decoded_bytes)
+    }
+
+    pub fn with_max_frame_bytes(mut self, limit: usize) -> Self {
+        self.max_frame_bytes = limit;
+        self
+    }
+
+    /// Read and throw away everything until the peer closes the connection,
+    /// so a peer still sending can finish and read what was sent back
+    pub async fn discard_rest(&mut self) -> std::io::Result<()> {
+        self.buf.clear();
+        let mut chunk = [0; 8192];
+        while self.inner.read(&mut chunk).await? > 0 {}
+        Ok(())
+    }
+
+    /// Read the next frame, or `None` if the peer closed the connection.
+    ///
+    /// A payload that fails to parse is consumed, so the stream stays in sync.
+    pub async fn read_frame<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        loop {
+            if let Some(header) = self.buf.first_chunk::<4>() {
+                let len = u32::from_be_bytes(*header) as usize;
+                if len > self.max_frame_bytes {
+                    let limit = self.max_frame_bytes;
+                    return Err(FrameTooLarge { len, limit }.into());
+                }
+                if self.buf.len() >= 4 + len {
+                    let payload = &self.buf[4..4 + len];
+                    let mut json_len = len;
+                    let message = match Codec::detect(payload) {
+                        Some(codec) => codec
+                            .decompress(payload, self.max_frame_bytes)
+                            .map_err(anyhow::Error::from)
+                            .and_then(|json| {
+                                json_len = json.len();
+                                Ok(serde_json::from_slice(&json)?)
+                            }),
+                        None => serde_json::from_slice(payload).map_err(anyhow::Error::from),
+                    };
+                    self.received_bytes += 4 + len as u64;
+                    self.decoded_bytes += 4 + json_len as u64;
+                    self.buf.drain(..4 + len);
+                    return Ok(Some(message?));
+                }
+            }
+
+            if self.inner.read_buf(&mut self.buf).await? == 0 {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                let received = self.buf.len();
+                return Err(TruncatedFrame { received }.into());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{OutputLine, Request, Response};
+    use std::path::PathBuf;
+
+    async fn encode<T: Serialize>(message: &T, compression: Option<Codec>) -> Vec<u8> {
+        let mut frame = Vec::new();
+        write_frame_with(&mut frame, message, compression).await.unwrap();
+        frame
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip_multiline_commands() {
+        let request = Request::Build {
+            dir: PathBuf::from("/src/app"),
+            command: "make clean\nmake all".to_string(),
+            timeout: Some(60),
+            env: vec![("CC".to_string(), "clang".to_string())],
+            max_lines: 0,
+            keep_running: false,
+            dry_run: false,
+            stdin: None,
+            shell: None,
+            server_truncate: None,
+        };
+        let mut bytes = encode(&request, None).await;
+        bytes.extend(encode(&Request::Status, None).await);
+
+        let mut reader = FrameReader::new(&bytes[..]);
+        match reader.read_frame::<Request>().await.unwrap() {
+            Some(Request::Build { command, env, timeout, .. }) => {
+                assert_eq!(command, "make clean\nmake all");
+                assert_eq!(env, [("CC".to_string(), "clang".to_string())]);
+                assert_eq!(timeout, Some(60));
+            }
+            other => panic!("expected a build, got {:?}", other),
+        }
+        assert!(matches!(reader.read_frame().await.unwrap(), Some(Request::Status)));
+        assert!(reader.read_frame::<Request>().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_is_refused_unread() {
+        let bytes = encode(&Request::Status, None).await;
+        let mut reader = FrameReader::new(&bytes[..]).with_max_frame_bytes(4);
+        let error = reader.read_frame::<Request>().await.unwrap_err();
+        let too_large = error.downcast_ref::<FrameTooLarge>().unwrap();
+        assert_eq!((too_large.len, too_large.limit), (bytes.len() - 4, 4));
+    }
+
+    #[tokio::test]
+    async fn truncated_frame_is_reported() {
+        let bytes = encode(&Request::Status, None).await;
+        let mut reader = FrameReader::new(&bytes[..bytes.len() - 1]);
+        let error = reader.read_frame::<Request>().await.unwrap_err();
+        assert_eq!(error.downcast_ref::<TruncatedFrame>().unwrap().received, bytes.len() - 1);
+    }
+
+    #[tokio::test]
+    async fn unparsable_frame_is_skipped() {
+        let mut bytes = 3u32.to_be_bytes().to_vec();
+        bytes.extend(b"{x}");
+        bytes.extend(encode(&Request::Status, None).await);
+        let mut reader = FrameReader::new(&bytes[..]);
+        assert!(reader.read_frame::<Request>().await.is_err());
+        assert!(matches!(reader.read_frame().await.unwrap(), Some(Request::Status)));
+    }
+
+    #[tokio::test]
+    async fn compressed_frames_are_detected_and_decoded() {
+        let line = OutputLine {
+            line: "warning: unused variable".repeat(50),
+            is_stderr: true,
+            elapsed_ms: 12,
+        };
+        let batch = Response::OutputBatch {
+            lines: vec![line.clone(), line],
+        };
+        let json = serde_json::to_vec(&batch).unwrap();
+        for codec in [Codec::Zstd, Codec::Gzip] {
+            let bytes = encode(&batch, Some(codec)).await;
+            assert_eq!(Codec::detect(&bytes[4..]), Some(codec));
+            assert!(bytes.len() < json.len());
+
+            let mut reader = FrameReader::new(&bytes[..]);
+            match reader.read_frame::<Response>().await.unwrap() {
+                Some(Response::OutputBatch { lines }) => assert_eq!(lines[1].line.len(), 24 * 50),
+                other => panic!("expected a batch, got {:?}", other),
+            }
+            assert_eq!(reader.bytes_read(), (bytes.len() as u64, 4 + json.len() as u64));
+        }
+    }
+
+    #[tokio::test]
+    async fn small_frames_stay_uncompressed() {
+        let bytes = encode(&Response::Heartbeat, Some(Codec::Zstd)).await;
+        assert_eq!(&bytes[4..], b"\"Heartbeat\"");
+        assert_eq!(Codec::detect(&bytes[4..]), None);
+    }
+
+    #[test]
+    fn decompression_stops_at_the_limit() {
+        let payload = Codec::Gzip.compress(&[b'x'; 4096]).unwrap();
+        assert!(Codec::Gzip.decompress(&payload, 1024).is_err());
+        assert_eq!(Codec::Gzip.decompress(&payload, 4096).unwrap().len(), 4096);
+    }
+}
@@ -1,228 +1,4181 @@
-use crate::protocol::{Request, Response};
+use crate::protocol::{
+    signal_name, version_mismatch, write_frame, write_frame_with, BuildCommand, BuildId, BuildResult,
+    BuildSummary, Codec, FrameReader, FrameTooLarge, HistoryEntry, LastBuild, OutputLine, Request,
+    Response, ServerStatus, ServerTruncation, TruncatedFrame, PROTOCOL_VERSION, VERSION,
+};
+use crate::process_tree::ProcessTree;
+use crate::shell::{chaining_operator, InitSource, Shell, ENV_SENTINEL};
+use crate::transport::{Address, Listener, Peer, ReadHalf, Stream, WriteHalf};
 use anyhow::{Context, Result};
-use std::path::PathBuf;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::process::Command;
+use tokio::sync::{broadcast, mpsc, oneshot, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio_rustls::TlsAcceptor;
 
-pub async fn run(init_script: Option<PathBuf>, port: u16) -> Result<()> {
-    let initialized = Arc::new(AtomicBool::new(false));
-    let init_script_path = init_script.clone();
+/// State shared between all connection handlers
+struct ServerState {
+    /// Signalled by a `stop` request to break out of the accept loop
+    shutdown: Notify,
+    /// Set by `stop`; new builds are refused from then on
+    stopping: AtomicBool,
+    /// Set along with `stopping` when running builds are left to finish
+    draining: AtomicBool,
+    /// Build requests being handled, queued or running
+    builds_in_flight: AtomicUsize,
+    /// Output of every build in `builds_in_flight`, for `attach` and `wait`
+    streams: Mutex<HashMap<BuildId, Arc<BuildStream>>>,
+    /// Output of the last `RECENT_STREAMS` finished builds, oldest first, for
+    /// `logs` when there is no log file to read
+    recent_streams: Mutex<VecDeque<(BuildId, Arc<BuildStream>)>>,
+    /// Signalled when `builds_in_flight` drops to zero
+    builds_drained: Notify,
+    /// Signalled whenever a build in flight ends
+    build_ended: Notify,
+    /// Builds can run: the init script (if any) succeeded and no reinit is
+    /// in progress
+    initialized: AtomicBool,
+    /// Held by the reinit in progress
+    reinit_running: AtomicBool,
+    /// Run in order at startup and on `reinit` (empty = no init)
+    init: Vec<InitSource>,
+    init_timeout: Option<Duration>,
+    /// Run for build requests with an empty command
+    default_command: Option<String>,
+    /// Limit for builds whose request doesn't set one (`None` = unlimited)
+    default_timeout: Option<Duration>,
+    /// Time between heartbeats to a build's client (`None` = off)
+    heartbeat_interval: Option<Duration>,
+    /// Silence after which a build's client is warned (`--stall-warn-secs`)
+    stall_warn: Option<Duration>,
+    /// Silence after which a build is killed (`--stall-kill-secs`)
+    stall_kill: Option<Duration>,
+    /// Output and duration of the latest init script run
+    init_record: Mutex<InitRecord>,
+    /// Environment left behind by the init script, applied to every build
+    /// Replaced wholesale by `reinit`; builds take a snapshot when they spawn
+    init_env: Mutex<Option<Arc<HashMap<String, String>>>>,
+    shell: Shell,
+    /// Programs a build may ask to be run with instead of `shell`
+    allowed_shells: Vec<String>,
+    /// Refuse build commands that chain several statements together
+    no_shell_chaining: bool,
+    /// When the server came up, for `status`
+    started: Instant,
+    /// Name of this machine, for `status`
+    hostname: Option<String>,
+    /// Builds spawned since then
+    builds_started: AtomicU64,
+    next_build_id: AtomicU64,
+    /// Builds currently in flight
+    active_builds: Mutex<HashMap<BuildId, BuildInfo>>,
+    /// Limits how many builds run at once (`None` = unlimited)
+    build_slots: Option<Arc<Semaphore>>,
+    /// Builds that haven't started yet, whether waiting on their directory
+    /// or on a slot
+    waiting: Mutex<HashMap<BuildId, QueuedBuild>>,
+    /// Builds waiting for a slot, in arrival order
+    queue: Mutex<VecDeque<BuildId>>,
+    /// Signalled whenever a build leaves the queue
+    queue_changed: Notify,
+    /// Serialize builds whose directories overlap (off with `--no-dir-lock`)
+    dir_lock: bool,
+    /// Limit on the size of one request (`--max-request-mb`)
+    max_request_bytes: usize,
+    /// Canonical directory held by each build that passed the directory lock
+    dir_locks: Mutex<HashMap<BuildId, PathBuf>>,
+    /// Signalled whenever a build releases its directory
+    dir_unlocked: Notify,
+    /// Most recently finished builds, oldest first
+    history: Mutex<VecDeque<HistoryEntry>>,
+    /// Number of finished builds kept in `history`
+    history_size: usize,
+    /// Most recent build that exited on its own, for `status`
+    last_build: Mutex<Option<LastBuild>>,
+    /// Shared secret every connection must present (`None` = open to all)
+    auth_token: Option<String>,
+    /// Directory holding `build-<id>.log` files (`None` = logs disabled)
+    log_dir: Option<PathBuf>,
+    /// Number of build logs kept (0 = unlimited)
+    keep_logs: usize,
+    /// Where connections, builds and errors are recorded (`--log-file`)
+    event_log: Option<EventLog>,
+}
 
-    // Run init script if provided
-    if let Some(ref script) = init_script {
-        println!("Running init script: {}", script.display());
-        run_init_script(script).await?;
-        println!("Init script completed successfully.");
+impl ServerState {
+    /// Remember a finished build, dropping the oldest once `history_size` is reached
+    fn record_history(&self, entry: HistoryEntry) {
+        if self.history_size == 0 {
+            return;
+        }
+        let mut history = self.history.lock().unwrap();
+        if history.len() >= self.history_size {
+            history.pop_front();
+        }
+        history.push_back(entry);
     }
 
-    initialized.store(true, Ordering::SeqCst);
+    /// Append an event to `--log-file`, if there is one
+    fn log_event(&self, event: &str, fields: &[(&str, &dyn fmt::Display)]) {
+        if let Some(ref event_log) = self.event_log {
+            event_log.record(event, fields);
+        }
+    }
+}
 
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", port))
-        .await
-        .context(format!("Failed to bind to port {}", port))?;
+/// Registry entry for a running build
+struct BuildInfo {
+    command: String,
+    dir: PathBuf,
+    client_addr: Peer,
+    started: Instant,
+    pid: Option<u32>,
+    /// Fires to make the build's handler kill the process tree
+    cancel: oneshot::Sender<()>,
+}
+
+/// A build waiting to start, as `list` shows it
+struct QueuedBuild {
+    command: String,
+    dir: PathBuf,
+    client_addr: Peer,
+    since: Instant,
+    /// Fires to take the build out of the queue
+    cancel: oneshot::Sender<()>,
+}
+
+/// Settings for `build-runner server`
+pub struct ServerConfig {
+    /// `--init` scripts, run in order, or the `--init-cmd` command line
+    pub init: Vec<InitSource>,
+    /// Kill an init script that runs longer than this
+    pub init_timeout: Option<Duration>,
+    /// Exit if the init script fails, rather than serving without builds
+    pub require_init: bool,
+    /// Command line for build requests that leave it empty
+    pub default_command: Option<String>,
+    /// Kill builds that run longer than this unless they ask otherwise
+    pub default_timeout: Option<Duration>,
+    /// Check on a build's client this often, killing the build if it's gone
+    pub heartbeat_interval: Option<Duration>,
+    /// Warn a build's client once it has printed nothing for this long
+    pub stall_warn: Option<Duration>,
+    /// Kill a build once it has printed nothing for this long
+    pub stall_kill: Option<Duration>,
+    /// TCP address or Unix socket to listen on
+    pub address: Address,
+    /// Serve TCP connections over TLS (`--tls-cert`/`--tls-key`)
+    pub tls: Option<TlsAcceptor>,
+    pub shell: Shell,
+    /// Other shells a build may ask for by program name
+    pub allowed_shells: Vec<String>,
+    /// Refuse build command lines containing `;`, `&&`, `|`, backticks or
+    /// the like, so each build runs a single command
+    pub no_shell_chaining: bool,
+    /// Maximum builds running at once (0 = unlimited)
+    pub max_concurrent: usize,
+    /// Run builds in the same or nested directories one after another
+    pub dir_lock: bool,
+    /// Let running builds finish on the first Ctrl+C rather than killing them
+    pub drain_on_shutdown: bool,
+    /// Connections sending a bigger message are answered with an error
+    pub max_request_bytes: usize,
+    /// Finished builds remembered for `history`
+    pub history_size: usize,
+    pub auth_token: Option<String>,
+    /// Where each build's full output is written (`None` = not kept)
+    pub log_dir: Option<PathBuf>,
+    /// Build logs kept in `log_dir` before the oldest are deleted (0 = all)
+    pub keep_logs: usize,
+    /// File that server events are appended to (`None` = console only)
+    pub log_file: Option<PathBuf>,
+    /// Size at which `log_file` is moved aside to `<log_file>.1` (0 = never)
+    pub log_max_bytes: u64,
+    /// Shut down on Ctrl+C, killing builds (or draining them first). Off for
+    /// servers embedded in another program, which keeps its own Ctrl+C.
+    pub catch_ctrl_c: bool,
+}
 
-    println!("Build server listening on port {}...", port);
-    println!("Ready to accept build requests.");
+impl ServerConfig {
+    /// Settings as `build-runner server` defaults them, listening on
+    /// `address`, except that there's no default command, no logs are
+    /// written and Ctrl+C is left alone
+    pub fn new(address: Address) -> Self {
+        ServerConfig {
+            init: Vec::new(),
+            init_timeout: None,
+            require_init: true,
+            default_command: None,
+            default_timeout: None,
+            heartbeat_interval: Some(Duration::from_secs(10)),
+            stall_warn: None,
+            stall_kill: None,
+            address,
+            tls: None,
+            shell: Shell::platform_default(),
+            allowed_shells: Vec::new(),
+            no_shell_chaining: false,
+            max_concurrent: 1,
+            dir_lock: true,
+            drain_on_shutdown: false,
+            max_request_bytes: 64 * 1024 * 1024,
+            history_size: 100,
+            auth_token: None,
+            log_dir: None,
+            keep_logs: 50,
+            log_file: None,
+            log_max_bytes: 10 * 1024 * 1024,
+            catch_ctrl_c: false,
+        }
+    }
+}
 
-    let running = Arc::new(AtomicBool::new(true));
+/// Run a server with `config` until it is stopped
+pub async fn run(config: ServerConfig) -> Result<()> {
+    Server::bind(config).await?.serve().await
+}
 
-    while running.load(Ordering::SeqCst) {
-        let (socket, addr) = listener.accept().await?;
-        println!("Connection from: {}", addr);
+/// A build server that has run its init script and is listening, but isn't
+/// taking connections until [`Server::serve`]
+pub struct Server {
+    state: Arc<ServerState>,
+    listener: Listener,
+    local_addr: String,
+    tls: Option<TlsAcceptor>,
+    drain_on_shutdown: bool,
+    catch_ctrl_c: bool,
+}
 
-        let running_clone = running.clone();
-        let initialized_clone = initialized.clone();
-        let init_script_clone = init_script_path.clone();
+impl Server {
+    /// Start listening and run the init script, so clients can connect as
+    /// soon as this returns. Fails if the address is taken, before the init
+    /// script runs, or if `config.require_init` is set and the script fails.
+    pub async fn bind(config: ServerConfig) -> Result<Self> {
+        let ServerConfig {
+            init,
+            init_timeout,
+            require_init,
+            default_command,
+            default_timeout,
+            heartbeat_interval,
+            stall_warn,
+            stall_kill,
+            address,
+            tls,
+            shell,
+            allowed_shells,
+            no_shell_chaining,
+            max_concurrent,
+            dir_lock,
+            drain_on_shutdown,
+            max_request_bytes,
+            history_size,
+            auth_token,
+            log_dir,
+            keep_logs,
+            log_file,
+            log_max_bytes,
+            catch_ctrl_c,
+        } = config;
 
-        tokio::spawn(async move {
-            if let Err(e) = handle_connection(
-                socket,
-                running_clone,
-                initialized_clone,
-                init_script_clone,
-            )
-            .await
-            {
-                eprintln!("Error handling connection: {}", e);
+        // Settings and the address are checked before the init script,
+        // which can take minutes, so mistakes come back right away
+        if tls.is_some() && !matches!(address, Address::Tcp { .. }) {
+            anyhow::bail!("--tls-cert only applies to TCP; a --socket or --pipe never leaves this machine");
+        }
+        let listener = Listener::bind(&address).await?;
+        let local_addr = listener.local_addr()?;
+
+        let event_log = match log_file {
+            Some(path) => {
+                let event_log = EventLog::open(path, log_max_bytes)?;
+                println!("Writing server events to {}", event_log.path.display());
+                Some(event_log)
+            }
+            None => None,
+        };
+
+        // Continue numbering after the logs of earlier runs so files never collide
+        let mut first_build_id = 1;
+        if let Some(ref log_dir) = log_dir {
+            std::fs::create_dir_all(log_dir)
+                .with_context(|| format!("Failed to create log directory {}", log_dir.display()))?;
+            if let Some(last) = build_log_ids(log_dir)?.last() {
+                first_build_id = last + 1;
+            }
+            println!("Writing build logs to {}", log_dir.display());
+        }
+
+        // Run init script if provided
+        let mut init_record = InitRecord::default();
+        let mut init_env = None;
+        if !init.is_empty() {
+            match run_init_script(&shell, &init, None, init_timeout, &mut init_record).await {
+                Ok(env) => {
+                    println!(
+                        "Init script completed successfully ({} environment variables captured).",
+                        env.len()
+                    );
+                    init_env = Some(Arc::new(env));
+                }
+                Err(e) if !require_init => {
+                    eprintln!(
+                        "Init script failed; builds are refused until a successful reinit: {:#}",
+                        e
+                    );
+                    init_record.error = Some(format!("{:#}", e));
+                    if let Some(ref event_log) = event_log {
+                        event_log.record("init_failed", &[("error", &format!("{:#}", e))]);
+                    }
+                }
+                Err(e) => {
+                    if let Some(ref event_log) = event_log {
+                        event_log.record("init_failed", &[("error", &format!("{:#}", e))]);
+                    }
+                    return Err(e);
+                }
             }
+        }
+        let init_ok = init_record.error.is_none();
+
+        let state = Arc::new(ServerState {
+            shutdown: Notify::new(),
+            stopping: AtomicBool::new(false),
+            draining: AtomicBool::new(false),
+            builds_in_flight: AtomicUsize::new(0),
+            streams: Mutex::new(HashMap::new()),
+            recent_streams: Mutex::new(VecDeque::with_capacity(RECENT_STREAMS)),
+            builds_drained: Notify::new(),
+            build_ended: Notify::new(),
+            initialized: AtomicBool::new(false),
+            reinit_running: AtomicBool::new(false),
+            init,
+            init_timeout,
+            default_command,
+            default_timeout,
+            heartbeat_interval,
+            stall_warn,
+            stall_kill,
+            init_record: Mutex::new(init_record),
+            init_env: Mutex::new(init_env),
+            shell: shell.clone(),
+            allowed_shells,
+            no_shell_chaining,
+            started: Instant::now(),
+            hostname: hostname(),
+            builds_started: AtomicU64::new(0),
+            next_build_id: AtomicU64::new(first_build_id),
+            active_builds: Mutex::new(HashMap::new()),
+            build_slots: (max_concurrent > 0).then(|| Arc::new(Semaphore::new(max_concurrent))),
+            waiting: Mutex::new(HashMap::new()),
+            queue: Mutex::new(VecDeque::new()),
+            queue_changed: Notify::new(),
+            dir_lock,
+            max_request_bytes,
+            dir_locks: Mutex::new(HashMap::new()),
+            dir_unlocked: Notify::new(),
+            history: Mutex::new(VecDeque::with_capacity(history_size)),
+            history_size,
+            last_build: Mutex::new(None),
+            auth_token,
+            log_dir,
+            keep_logs,
+            event_log,
         });
+
+        state.initialized.store(init_ok, Ordering::SeqCst);
+
+        println!(
+            "Build server listening on {}{} (shell: {})...",
+            local_addr,
+            if tls.is_some() { " with TLS" } else { "" },
+            shell
+        );
+        if listener.is_remote() && state.auth_token.is_none() {
+            eprintln!(
+                "Warning: listening beyond loopback without --auth-token; anyone who can reach \
+                 this port can run commands on this machine."
+            );
+        }
+        println!("Ready to accept build requests.");
+        state.log_event("started", &[("addr", &local_addr), ("shell", &shell)]);
+
+        Ok(Server {
+            state,
+            listener,
+            local_addr,
+            tls,
+            drain_on_shutdown,
+            catch_ctrl_c,
+        })
     }
 
-    println!("Server shutting down...");
-    Ok(())
+    /// Where clients connect, e.g. the port picked for port 0
+    pub fn local_addr(&self) -> &str {
+        &self.local_addr
+    }
+
+    /// Accept connections until a `stop` request (or Ctrl+C, with
+    /// `catch_ctrl_c`) and the builds it waits for are done. Dropping the
+    /// future stops listening but leaves running builds to their handlers.
+    pub async fn serve(self) -> Result<()> {
+        let Server {
+            state,
+            listener,
+            tls,
+            drain_on_shutdown,
+            catch_ctrl_c,
+            ..
+        } = self;
+
+        loop {
+            let (socket, addr) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = state.shutdown.notified() => break,
+                // Builds run in their own process groups, out of reach of the
+                // terminal's Ctrl+C, so the server has to kill or wait for them
+                _ = ctrl_c(catch_ctrl_c) => {
+                    if drain_on_shutdown {
+                        println!("Interrupted, waiting for running builds (Ctrl+C again to kill them)...");
+                    } else {
+                        println!("Interrupted, cancelling all builds...");
+                    }
+                    state.log_event("interrupted", &[("drain", &drain_on_shutdown)]);
+                    shut_down(&state, !drain_on_shutdown);
+                    continue;
+                }
+            };
+            println!("Connection from: {}", addr);
+            state.log_event("connection", &[("addr", &addr)]);
+
+            let state = state.clone();
+            let tls = tls.clone();
+
+            tokio::spawn(async move {
+                // Handshakes happen here rather than in the accept loop so a
+                // slow client can't hold up everyone else
+                let socket = match tls {
+                    Some(acceptor) => match socket.accept_tls(&acceptor).await {
+                        Ok(socket) => socket,
+                        Err(e) => {
+                            eprintln!("TLS handshake with {} failed: {}", addr, e);
+                            state.log_event("tls_failed", &[("addr", &addr), ("error", &e)]);
+                            return;
+                        }
+                    },
+                    None => socket,
+                };
+                if let Err(e) = handle_connection(socket, addr, state.clone()).await {
+                    eprintln!("Error handling connection: {}", e);
+                    state.log_event("error", &[("addr", &addr), ("error", &e)]);
+                }
+            });
+        }
+
+        // Refuse new connections while the builds already in flight finish
+        drop(listener);
+        let in_flight = state.builds_in_flight.load(Ordering::SeqCst);
+        if in_flight > 0 {
+            println!("Waiting for {} build(s) to finish...", in_flight);
+        }
+        let mut killed = false;
+        loop {
+            let drained = state.builds_drained.notified();
+            if state.builds_in_flight.load(Ordering::SeqCst) == 0 {
+                break;
+            }
+            tokio::select! {
+                _ = drained => {}
+                _ = ctrl_c(catch_ctrl_c) => {
+                    if killed {
+                        // Builds that won't die shouldn't keep the server up too
+                        println!("Interrupted again, exiting without waiting for builds.");
+                        state.log_event("stopped", &[("forced", &true)]);
+                        std::process::exit(130);
+                    }
+                    println!("Interrupted, cancelling all builds...");
+                    state.log_event("interrupted", &[("drain", &false)]);
+                    stop_builds(&state, true);
+                    killed = true;
+                }
+            }
+        }
+
+        println!("Server shutting down...");
+        state.log_event("stopped", &[]);
+        Ok(())
+    }
 }
 
-async fn run_init_script(script: &PathBuf) -> Result<()> {
-    let script_path = script.to_string_lossy();
+/// Ctrl+C, if the server handles it, otherwise never
+async fn ctrl_c(catch: bool) {
+    if catch {
+        let _ = tokio::signal::ctrl_c().await;
+    } else {
+        std::future::pending().await
+    }
+}
 
-    let status = Command::new("powershell")
-        .args(["-NoProfile", "-ExecutionPolicy", "Bypass", "-File", &script_path])
-        .status()
-        .await
-        .context("Failed to run init script")?;
+/// Lines of init script output kept for `status`
+const INIT_TAIL_LINES: usize = 200;
+
+/// How the most recent init script run went, for `status`
+#[derive(Default)]
+struct InitRecord {
+    duration: Option<Duration>,
+    /// Why the run failed, while that leaves the server without an environment
+    error: Option<String>,
+    /// Last lines the script printed, before its environment dump
+    output_tail: VecDeque<String>,
+}
+
+/// Run the init scripts in order and capture the environment the last one
+/// leaves behind. Each script starts from the environment of the one before,
+/// and the first failure stops the run.
+///
+/// The scripts' own output is echoed to the server console with an `[init]`
+/// prefix, kept in `record`, and sent to `output` when given.
+async fn run_init_script(
+    shell: &Shell,
+    init: &[InitSource],
+    output: Option<mpsc::Sender<OutputLine>>,
+    timeout: Option<Duration>,
+    record: &mut InitRecord,
+) -> Result<HashMap<String, String>> {
+    let started = Instant::now();
+    record.output_tail.clear();
+    let mut env = None;
+    let mut result = Ok(());
+    for (step, source) in init.iter().enumerate() {
+        println!("Running {}", source);
+        match run_init_step(shell, source, env.as_ref(), output.as_ref(), timeout, record).await {
+            Ok(captured) => env = Some(captured),
+            // Say how far the chain got, since the later scripts never ran
+            Err(e) if init.len() > 1 => {
+                result = Err(e.context(format!("Init step {} of {} failed", step + 1, init.len())));
+                break;
+            }
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        }
+    }
+    record.duration = Some(started.elapsed());
+    result?;
+    env.context("No init script to run")
+}
+
+/// Run one init script, starting from `env` if given (the server's own
+/// environment otherwise), and return the environment it leaves behind.
+///
+/// Stdout after the sentinel line is parsed as `KEY=VALUE` pairs. A script
+/// still running after `timeout` is killed.
+async fn run_init_step(
+    shell: &Shell,
+    init: &InitSource,
+    env: Option<&HashMap<String, String>>,
+    output: Option<&mpsc::Sender<OutputLine>>,
+    timeout: Option<Duration>,
+    record: &mut InitRecord,
+) -> Result<HashMap<String, String>> {
+    let mut process = Command::new(shell.program());
+    process
+        .args(shell.init_args(init))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(env) = env {
+        process.env_clear().envs(env);
+    }
+    #[cfg(unix)]
+    process.process_group(0);
+    let started = Instant::now();
+    let mut child = process
+        .spawn()
+        .with_context(|| format!("Failed to run {}", init))?;
+    let tree = ProcessTree::new(&child);
+
+    let (lines_tx, mut lines) = mpsc::channel(OUTPUT_CHANNEL_CAPACITY);
+    spawn_line_reader(child.stdout.take().unwrap(), false, started, lines_tx.clone());
+    spawn_line_reader(child.stderr.take().unwrap(), true, started, lines_tx);
 
+    let mut captured: Option<HashMap<String, String>> = None;
+    let run = async {
+        while let Some(line) = lines.recv().await {
+            if !line.is_stderr {
+                match captured {
+                    Some(ref mut env) => {
+                        if let Some((key, value)) = line.line.split_once('=') {
+                            if !key.is_empty() {
+                                env.insert(key.to_string(), value.to_string());
+                            }
+                        }
+                        continue;
+                    }
+                    None if line.line.trim_end() == ENV_SENTINEL => {
+                        captured = Some(HashMap::new());
+                        continue;
+                    }
+                    None => {}
+                }
+            }
+
+            if line.is_stderr {
+                eprintln!("[init] {}", line.line);
+            } else {
+                println!("[init] {}", line.line);
+            }
+            if record.output_tail.len() == INIT_TAIL_LINES {
+                record.output_tail.pop_front();
+            }
+            record.output_tail.push_back(line.line.clone());
+            if let Some(output) = output {
+                // The requesting client may be gone; the reinit carries on
+                let _ = output.send(line).await;
+            }
+        }
+        child
+            .wait()
+            .await
+            .with_context(|| format!("Failed to run {}", init))
+    };
+    let status = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, run).await.ok(),
+        None => Some(run.await),
+    };
+
+    let Some(status) = status else {
+        tree.kill(&mut child).await;
+        anyhow::bail!("{} timed out after {}s", init, timeout.unwrap_or_default().as_secs());
+    };
+    let status = status?;
+    if let Some(signal) = exit_signal(&status) {
+        anyhow::bail!("{} was killed by {}", init, signal_name(signal));
+    }
     if !status.success() {
         anyhow::bail!(
-            "Init script failed with exit code: {}",
+            "{} failed with exit code: {}",
+            init,
             status.code().unwrap_or(-1)
         );
     }
 
-    Ok(())
+    captured.with_context(|| format!("{} finished without reporting its environment", init))
 }
 
 async fn handle_connection(
-    mut socket: TcpStream,
-    running: Arc<AtomicBool>,
-    initialized: Arc<AtomicBool>,
-    init_script: Option<PathBuf>,
+    mut socket: Stream,
+    client_addr: Peer,
+    state: Arc<ServerState>,
 ) -> Result<()> {
     let (reader, mut writer) = socket.split();
-    let mut reader = BufReader::new(reader);
-    let mut line = String::new();
+    let mut reader = FrameReader::new(reader).with_max_frame_bytes(state.max_request_bytes);
 
-    reader.read_line(&mut line).await?;
-    let request: Request = serde_json::from_str(&line)?;
+    let Some(mut request) = read_request(&mut reader, &mut writer, None).await? else {
+        return Ok(());
+    };
+
+    // Clients since the handshake introduce themselves first; older ones
+    // start with the request and don't know newer responses like `Heartbeat`
+    let mut client_version = None;
+    let mut compression = None;
+    if let Request::Hello {
+        protocol,
+        version,
+        compression: wanted,
+    } = request
+    {
+        if protocol != PROTOCOL_VERSION {
+            let message = version_mismatch(&version, VERSION);
+            println!("Rejected client {}: {}", client_addr, message);
+            state.log_event(
+                "version_mismatch",
+                &[("addr", &client_addr), ("client_version", &version), ("protocol", &protocol)],
+            );
+            send_response(&mut writer, &Response::Error { message }).await?;
+            return Ok(());
+        }
+        // Every codec a client can ask for is supported
+        compression = wanted;
+        let hello = Response::Hello {
+            protocol: PROTOCOL_VERSION,
+            version: VERSION.to_string(),
+            compression,
+        };
+        send_response(&mut writer, &hello).await?;
+        let Some(next) = read_request(&mut reader, &mut writer, Some(&version)).await? else {
+            return Ok(());
+        };
+        client_version = Some(version);
+        request = next;
+    }
+
+    // A client with a token sends it in its own frame ahead of the request
+    let mut token = None;
+    if let Request::Auth { token: presented } = request {
+        token = Some(presented);
+        let Some(next) = read_request(&mut reader, &mut writer, client_version.as_deref()).await?
+        else {
+            return Ok(());
+        };
+        request = next;
+    }
+    if let Some(ref expected) = state.auth_token {
+        if token.as_ref() != Some(expected) {
+            println!("Rejected request from {}: wrong or missing auth token", client_addr);
+            state.log_event("unauthorized", &[("addr", &client_addr)]);
+            send_response(&mut writer, &Response::Unauthorized).await?;
+            return Ok(());
+        }
+    }
 
     match request {
-        Request::Build { dir, command } => {
-            println!("Build request: dir={}, cmd={}", dir.display(), command);
-            handle_build(&mut writer, dir, command).await?;
+        Request::Hello { .. } => {
+            let message = "Hello may only be sent once, as the first message".to_string();
+            send_response(&mut writer, &Response::Error { message }).await?;
+        }
+        Request::Auth { .. } => {
+            let message = "Auth may only be sent once, before the request".to_string();
+            send_response(&mut writer, &Response::Error { message }).await?;
+        }
+        Request::Build {
+            dir,
+            command,
+            timeout,
+            env,
+            max_lines,
+            keep_running,
+            dry_run,
+            stdin,
+            shell,
+            server_truncate,
+        } => {
+            let shell = match shell {
+                Some(program) if program != state.shell.program() => {
+                    if !state.allowed_shells.contains(&program) {
+                        let message = format!(
+                            "Shell '{}' is not allowed; start the server with --allow-shell {} to permit it",
+                            program, program
+                        );
+                        send_response(&mut writer, &Response::Error { message }).await?;
+                        return Ok(());
+                    }
+                    Some(Shell::new(program, None))
+                }
+                _ => None,
+            };
+            // The server's own --default-command is trusted
+            if state.no_shell_chaining {
+                if let Some(op) = chaining_operator(&command) {
+                    refuse_chaining(&mut writer, &state, &client_addr, op).await?;
+                    return Ok(());
+                }
+            }
+            let command = if command.trim().is_empty() {
+                let Some(ref default_command) = state.default_command else {
+                    let message = "No command given and the server has no --default-command".to_string();
+                    send_response(&mut writer, &Response::Error { message }).await?;
+                    return Ok(());
+                };
+                default_command.clone()
+            } else {
+                command
+            };
+            let job = BuildJob {
+                dir,
+                command: BuildCommand::Shell(command),
+                shell,
+                timeout: build_timeout(&state, timeout),
+                env,
+                max_lines,
+                keep_running,
+                dry_run,
+                stdin,
+                server_truncate,
+                client_addr,
+                said_hello: client_version.is_some(),
+                compression,
+            };
+            handle_build(&mut writer, &mut reader, &state, job).await?;
+        }
+        Request::BuildArgv {
+            dir,
+            program,
+            args,
+            timeout,
+            env,
+            max_lines,
+            keep_running,
+            dry_run,
+            stdin,
+            server_truncate,
+        } => {
+            // Otherwise any interpreter could be run with `--arg`, allow list
+            // or not, and given a chained command line of its own
+            if (!state.allowed_shells.is_empty() || state.no_shell_chaining)
+                && program != state.shell.program()
+                && !state.allowed_shells.contains(&program)
+            {
+                let message = format!(
+                    "Program '{}' is not allowed; --arg builds on this server may only run its shell or an --allow-shell one",
+                    program
+                );
+                send_response(&mut writer, &Response::Error { message }).await?;
+                return Ok(());
+            }
+            // Which leaves a shell, whose arguments may hold a command line
+            if state.no_shell_chaining {
+                if let Some(op) = args.iter().find_map(|arg| chaining_operator(arg)) {
+                    refuse_chaining(&mut writer, &state, &client_addr, op).await?;
+                    return Ok(());
+                }
+            }
+            let job = BuildJob {
+                dir,
+                command: BuildCommand::Argv { program, args },
+                shell: None,
+                timeout: build_timeout(&state, timeout),
+                env,
+                max_lines,
+                keep_running,
+                dry_run,
+                stdin,
+                server_truncate,
+                client_addr,
+                said_hello: client_version.is_some(),
+                compression,
+            };
+            handle_build(&mut writer, &mut reader, &state, job).await?;
         }
         Request::Status => {
-            let response = Response::Status {
-                initialized: initialized.load(Ordering::SeqCst),
-                init_script: init_script.map(|p| p.to_string_lossy().to_string()),
+            let response = {
+                let init_record = state.init_record.lock().unwrap();
+                Response::Status(Box::new(ServerStatus {
+                    initialized: state.initialized.load(Ordering::SeqCst),
+                    version: VERSION.to_string(),
+                    hostname: state.hostname.clone(),
+                    uptime_secs: state.started.elapsed().as_secs(),
+                    builds_started: state.builds_started.load(Ordering::Relaxed),
+                    running: running_builds(&state),
+                    init_scripts: state
+                        .init
+                        .iter()
+                        .filter_map(|source| match source {
+                            InitSource::Script(path) => Some(path.to_string_lossy().to_string()),
+                            InitSource::Command(_) => None,
+                        })
+                        .collect(),
+                    init_command: state.init.iter().find_map(|source| match source {
+                        InitSource::Command(command) => Some(command.clone()),
+                        InitSource::Script(_) => None,
+                    }),
+                    env_vars: state
+                        .init_env
+                        .lock()
+                        .unwrap()
+                        .as_ref()
+                        .map_or(0, |env| env.len()),
+                    shell: state.shell.to_string(),
+                    allowed_shells: state.allowed_shells.clone(),
+                    default_command: state.default_command.clone(),
+                    last_build: state.last_build.lock().unwrap().clone(),
+                    init_duration_secs: init_record.duration.as_ref().map(Duration::as_secs_f64),
+                    init_output_tail: init_record.output_tail.iter().cloned().collect(),
+                    init_error: init_record.error.clone(),
+                    init_running: state.reinit_running.load(Ordering::SeqCst),
+                }))
             };
             send_response(&mut writer, &response).await?;
         }
-        Request::Stop => {
+        Request::Stop { force, .. } => {
             println!("Stop request received.");
-            send_response(&mut writer, &Response::Stopping).await?;
-            running.store(false, Ordering::SeqCst);
+            state.log_event("stop", &[("addr", &client_addr), ("force", &force)]);
+            let mut active_builds = shut_down(&state, force);
+            send_response(&mut writer, &Response::Stopping { active_builds }).await?;
+            // Hold the connection until the builds have drained so the client
+            // can tell when it's over, counting them down meanwhile
+            loop {
+                let ended = state.build_ended.notified();
+                let running = state.active_builds.lock().unwrap().len();
+                if running != active_builds {
+                    active_builds = running;
+                    send_response(&mut writer, &Response::Stopping { active_builds }).await?;
+                }
+                if active_builds == 0 {
+                    break;
+                }
+                tokio::select! {
+                    _ = ended => {}
+                    _ = client_interrupt(&mut reader) => break,
+                }
+            }
+        }
+        Request::Reinit => {
+            println!("Reinit request received.");
+            handle_reinit(&mut writer, &state).await?;
+        }
+        Request::SetEnv { vars } => {
+            let keys: Vec<&str> = vars.iter().map(|(key, _)| key.as_str()).collect();
+            println!("Setting environment variables: {}", keys.join(", "));
+            let response = update_env(&state, |env| env.extend(vars));
+            send_response(&mut writer, &response).await?;
+        }
+        Request::UnsetEnv { keys } => {
+            println!("Removing environment variables: {}", keys.join(", "));
+            let response = update_env(&state, |env| {
+                for key in &keys {
+                    env.remove(key);
+                }
+            });
+            send_response(&mut writer, &response).await?;
+        }
+        Request::GetEnv { prefix } => {
+            let matches = |key: &str| prefix.as_deref().is_none_or(|prefix| key.starts_with(prefix));
+            let mut vars: Vec<(String, String)> = match *state.init_env.lock().unwrap() {
+                Some(ref env) => env
+                    .iter()
+                    .filter(|(key, _)| matches(key))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect(),
+                None => std::env::vars().filter(|(key, _)| matches(key)).collect(),
+            };
+            vars.sort();
+            send_response(&mut writer, &Response::Env { vars }).await?;
+        }
+        Request::Wait { id } => {
+            handle_wait(&mut writer, &mut reader, &state, id).await?;
+        }
+        Request::Attach { id, from_line, tail } => {
+            handle_attach(&mut writer, &mut reader, &state, id, from_line, tail).await?;
+        }
+        Request::GetLog { id, tail } => {
+            handle_get_log(&mut writer, &state, id, tail).await?;
+        }
+        Request::ListBuilds => {
+            let mut builds = running_builds(&state);
+            builds.extend(queued_builds(&state));
+            send_response(&mut writer, &Response::Builds { builds }).await?;
+        }
+        Request::History { limit } => {
+            let builds = state
+                .history
+                .lock()
+                .unwrap()
+                .iter()
+                .rev()
+                .take(limit.unwrap_or(usize::MAX))
+                .cloned()
+                .collect();
+            send_response(&mut writer, &Response::History { builds }).await?;
+        }
+        Request::Cancel { build_id } => {
+            println!("Cancel request received.");
+            let matches = |id: BuildId| build_id.is_none_or(|wanted| wanted == id);
+            // Queued builds go first so they can't take a slot freed by a
+            // cancelled running build
+            let dequeued: Vec<(BuildId, QueuedBuild)> = {
+                let mut waiting = state.waiting.lock().unwrap();
+                let ids: Vec<BuildId> = waiting.keys().copied().filter(|&id| matches(id)).collect();
+                ids.into_iter()
+                    .filter_map(|id| waiting.remove_entry(&id))
+                    .collect()
+            };
+            let cancelled: Vec<(BuildId, BuildInfo)> = {
+                let mut active_builds = state.active_builds.lock().unwrap();
+                match build_id {
+                    Some(id) => active_builds.remove_entry(&id).into_iter().collect(),
+                    None => active_builds.drain().collect(),
+                }
+            };
+
+            if dequeued.is_empty() && cancelled.is_empty() {
+                let message = match build_id {
+                    Some(id) => format!("No running or queued build with id {}", id),
+                    None => "No build is currently running".to_string(),
+                };
+                send_response(&mut writer, &Response::Error { message }).await?;
+            } else {
+                for (id, queued) in dequeued {
+                    println!("Cancelling build {} before it starts", id);
+                    let _ = queued.cancel.send(());
+                }
+                for (id, build) in cancelled {
+                    println!(
+                        "Cancelling build {} ('{}' in {}, pid {}, running {:.1}s)",
+                        id,
+                        build.command,
+                        build.dir.display(),
+                        display_pid(build.pid),
+                        build.started.elapsed().as_secs_f64()
+                    );
+                    let _ = build.cancel.send(());
+                }
+                send_response(&mut writer, &Response::Cancelled).await?;
+            }
         }
     }
 
     Ok(())
 }
 
-async fn handle_build(
-    writer: &mut tokio::net::tcp::WriteHalf<'_>,
-    dir: PathBuf,
-    command: String,
+/// Apply `change` to the environment new builds start with; running builds
+/// keep the one they were spawned with
+fn update_env(state: &ServerState, change: impl FnOnce(&mut HashMap<String, String>)) -> Response {
+    if let Some(message) = not_ready_reason(state) {
+        return Response::Error { message };
+    }
+    let mut init_env = state.init_env.lock().unwrap();
+    // Without an init script builds inherit the server's own environment
+    let env = init_env.get_or_insert_with(|| Arc::new(std::env::vars().collect()));
+    change(Arc::make_mut(env));
+    Response::EnvUpdated {
+        env_vars: env.len(),
+    }
+}
+
+/// Turn away a build whose command chains `op` onto it, under
+/// `--no-shell-chaining`
+async fn refuse_chaining(
+    writer: &mut WriteHalf<'_>,
+    state: &ServerState,
+    client_addr: &Peer,
+    op: &str,
 ) -> Result<()> {
-    // Validate directory exists
-    if !dir.exists() {
-        send_response(
-            writer,
-            &Response::Error {
-                message: format!("Directory does not exist: {}", dir.display()),
-            },
-        )
-        .await?;
-        return Ok(());
+    let op = match op {
+        "\n" => "a line break",
+        "\r" => "a carriage return",
+        op => op,
+    };
+    let message = format!(
+        "The build command contains {}; this server runs single commands only (--no-shell-chaining)",
+        op
+    );
+    println!("Rejected build from {}: command contains {}", client_addr, op);
+    state.log_event("chaining_rejected", &[("addr", client_addr), ("operator", &op)]);
+    send_response(writer, &Response::Error { message }).await
+}
+
+/// Why builds can't run right now, if they can't
+fn not_ready_reason(state: &ServerState) -> Option<String> {
+    if state.initialized.load(Ordering::SeqCst) {
+        return None;
     }
+    match state.init_record.lock().unwrap().error {
+        Some(ref error) => Some(format!("server not initialized: {}", error)),
+        None => Some(
+            "The server is re-running its init script; try again once it finishes".to_string(),
+        ),
+    }
+}
 
-    if !dir.is_dir() {
-        send_response(
-            writer,
-            &Response::Error {
-                message: format!("Path is not a directory: {}", dir.display()),
-            },
-        )
-        .await?;
+/// Re-run the init script, streaming its output to the client, and swap in
+/// the environment it produces. Builds are turned away until it finishes; on
+/// failure the previous environment stays in place.
+async fn handle_reinit(
+    writer: &mut WriteHalf<'_>,
+    state: &ServerState,
+) -> Result<()> {
+    if state.init.is_empty() {
+        let message =
+            "Server was started without --init or --init-cmd; there is no init script to re-run";
+        send_response(writer, &Response::Error { message: message.to_string() }).await?;
         return Ok(());
     }
 
-    // Parse command into program and args
-    let parts: Vec<&str> = command.split_whitespace().collect();
-    if parts.is_empty() {
-        send_response(
-            writer,
-            &Response::Error {
-                message: "Empty command".to_string(),
-            },
-        )
-        .await?;
+    if state.reinit_running.swap(true, Ordering::SeqCst) {
+        let message = "The init script is already being re-run".to_string();
+        send_response(writer, &Response::Error { message }).await?;
         return Ok(());
     }
+    // Only a server whose init has never succeeded is without an environment
+    let had_env = state.init_record.lock().unwrap().error.is_none();
+    state.initialized.store(false, Ordering::SeqCst);
 
-    // Spawn the build process
-    let mut child = match Command::new("powershell")
-        .args(["-NoProfile", "-Command", &format!("cd '{}'; {}", dir.display(), command)])
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-    {
-        Ok(child) => child,
+    let (output_tx, mut output) = mpsc::channel(OUTPUT_CHANNEL_CAPACITY);
+    let forward = async {
+        let mut connected = true;
+        while let Some(OutputLine { line, is_stderr, elapsed_ms }) = output.recv().await {
+            if connected {
+                let response = Response::Output { line, is_stderr, elapsed_ms };
+                connected = send_response(writer, &response).await.is_ok();
+            }
+        }
+        connected
+    };
+    let mut record = InitRecord::default();
+    let (result, connected) = tokio::join!(
+        run_init_script(
+            &state.shell,
+            &state.init,
+            Some(output_tx),
+            state.init_timeout,
+            &mut record
+        ),
+        forward
+    );
+
+    let response = match result {
+        Ok(env) => {
+            let env_vars = env.len();
+            *state.init_env.lock().unwrap() = Some(Arc::new(env));
+            println!(
+                "Init script completed successfully ({} environment variables captured).",
+                env_vars
+            );
+            state.log_event("reinit", &[("env_vars", &env_vars)]);
+            Response::ReinitComplete { env_vars }
+        }
+        Err(e) if had_env => {
+            eprintln!("Reinit failed, keeping the previous environment: {:#}", e);
+            state.log_event("reinit_failed", &[("error", &format!("{:#}", e))]);
+            Response::Error {
+                message: format!("{:#}", e),
+            }
+        }
         Err(e) => {
-            send_response(
-                writer,
-                &Response::Error {
-                    message: format!("Failed to spawn process 'powershell': {}", e),
-                },
-            )
-            .await?;
-            return Ok(());
+            eprintln!("Reinit failed; builds are still refused: {:#}", e);
+            state.log_event("reinit_failed", &[("error", &format!("{:#}", e))]);
+            record.error = Some(format!("{:#}", e));
+            Response::Error {
+                message: format!("{:#}", e),
+            }
         }
     };
+    state.initialized.store(record.error.is_none(), Ordering::SeqCst);
+    *state.init_record.lock().unwrap() = record;
+    state.reinit_running.store(false, Ordering::SeqCst);
 
-    let stdout = child.stdout.take().unwrap();
-    let stderr = child.stderr.take().unwrap();
+    if connected {
+        send_response(writer, &response).await?;
+    }
+    Ok(())
+}
+
+/// Lines buffered between the pipe readers and the client connection
+const OUTPUT_CHANNEL_CAPACITY: usize = 256;
+
+/// A build's client gets its output in batches of up to this many lines...
+const OUTPUT_BATCH_LINES: usize = 256;
+/// ...or this many bytes...
+const OUTPUT_BATCH_BYTES: usize = 1024 * 1024;
+/// ...sent at most this long after the first line of the batch was read
+const OUTPUT_BATCH_DELAY: Duration = Duration::from_millis(25);
+
+/// Output lines kept per running build for `attach` to replay
+const REPLAY_LINES: usize = 5000;
+
+/// Finished builds whose output stays in memory for `logs`
+const RECENT_STREAMS: usize = 10;
+
+/// Events buffered for each attached client that falls behind
+const ATTACH_CHANNEL_CAPACITY: usize = 1024;
+
+/// A running build's output as `attach` clients see it: recent lines to
+/// replay, and a broadcast of everything that follows
+struct BuildStream {
+    replay: Mutex<Replay>,
+    events: broadcast::Sender<StreamEvent>,
+}
+
+struct Replay {
+    /// Most recent lines, each with its index in the build's output
+    lines: VecDeque<(u64, OutputLine)>,
+    next_index: u64,
+    /// Final response, once the build has ended
+    finished: Option<Response>,
+}
+
+#[derive(Clone)]
+enum StreamEvent {
+    Line(u64, OutputLine),
+    Finished(Response),
+}
+
+impl BuildStream {
+    fn new() -> Self {
+        let (events, _) = broadcast::channel(ATTACH_CHANNEL_CAPACITY);
+        Self {
+            replay: Mutex::new(Replay {
+                lines: VecDeque::new(),
+                next_index: 0,
+                finished: None,
+            }),
+            events,
+        }
+    }
+
+    fn publish(&self, line: OutputLine) {
+        // Buffer and broadcast under one lock so a subscriber sees each line once
+        let mut replay = self.replay.lock().unwrap();
+        let index = replay.next_index;
+        replay.next_index += 1;
+        if replay.lines.len() == REPLAY_LINES {
+            replay.lines.pop_front();
+        }
+        replay.lines.push_back((index, line.clone()));
+        // An error only means nobody is attached
+        let _ = self.events.send(StreamEvent::Line(index, line));
+    }
+
+    fn finish(&self, response: Response) {
+        let mut replay = self.replay.lock().unwrap();
+        replay.finished = Some(response.clone());
+        let _ = self.events.send(StreamEvent::Finished(response));
+    }
+
+    /// Buffered lines from `from_line` on, the final response if the build
+    /// already ended, and a receiver for everything after
+    fn subscribe(
+        &self,
+        from_line: u64,
+    ) -> (
+        Vec<(u64, OutputLine)>,
+        Option<Response>,
+        broadcast::Receiver<StreamEvent>,
+    ) {
+        let replay = self.replay.lock().unwrap();
+        let backlog = replay
+            .lines
+            .iter()
+            .filter(|(index, _)| *index >= from_line)
+            .cloned()
+            .collect();
+        (backlog, replay.finished.clone(), self.events.subscribe())
+    }
+}
+
+/// Replay a running build's recent output to this client, then stream it
+/// live until the build ends. Attached clients only watch: disconnecting
+/// leaves the build alone.
+async fn handle_attach(
+    writer: &mut WriteHalf<'_>,
+    client: &mut FrameReader<ReadHalf<'_>>,
+    state: &ServerState,
+    id: Option<BuildId>,
+    from_line: u64,
+    tail: Option<u64>,
+) -> Result<()> {
+    let found = {
+        let active_builds = state.active_builds.lock().unwrap();
+        let entry = match id {
+            Some(id) => active_builds.get_key_value(&id),
+            None => active_builds.iter().max_by_key(|(&id, _)| id),
+        };
+        entry.and_then(|(&build_id, build)| {
+            let stream = state.streams.lock().unwrap().get(&build_id).cloned()?;
+            Some((build_id, build.pid, stream))
+        })
+    };
+    let Some((build_id, pid, stream)) = found else {
+        let message = match id {
+            Some(id) => format!("No running build with id {}", id),
+            None => "No build is currently running".to_string(),
+        };
+        send_response(writer, &Response::Error { message }).await?;
+        return Ok(());
+    };
+
+    println!("Client attached to build {}", build_id);
+    // Lines published meanwhile only make the tail a little longer
+    let from_line = match tail {
+        Some(tail) => stream.replay.lock().unwrap().next_index.saturating_sub(tail),
+        None => from_line,
+    };
+    let (backlog, finished, mut events) = stream.subscribe(from_line);
+    send_response(writer, &Response::BuildStarted { build_id, pid }).await?;
 
-    let mut stdout_reader = BufReader::new(stdout).lines();
-    let mut stderr_reader = BufReader::new(stderr).lines();
+    let mut next_line = from_line;
+    if backlog.first().is_some_and(|(index, _)| *index > from_line) {
+        let notice = Response::Output {
+            line: "[earlier output is no longer buffered; see `build-runner logs`]".to_string(),
+            is_stderr: true,
+            elapsed_ms: 0,
+        };
+        send_response(writer, &notice).await?;
+    }
+    for (index, OutputLine { line, is_stderr, elapsed_ms }) in backlog {
+        send_response(writer, &Response::Output { line, is_stderr, elapsed_ms }).await?;
+        next_line = index + 1;
+    }
+    if let Some(response) = finished {
+        return send_response(writer, &response).await;
+    }
 
-    // Stream output to client
     loop {
         tokio::select! {
-            line = stdout_reader.next_line() => {
-                match line {
-                    Ok(Some(line)) => {
-                        send_response(writer, &Response::Output { line, is_stderr: false }).await?;
-                    }
-                    Ok(None) => break,
-                    Err(e) => {
-                        eprintln!("Error reading stdout: {}", e);
-                        break;
+            event = events.recv() => match event {
+                Ok(StreamEvent::Line(index, OutputLine { line, is_stderr, elapsed_ms })) => {
+                    if index >= next_line {
+                        let response = Response::Output { line, is_stderr, elapsed_ms };
+                        send_response(writer, &response).await?;
+                        next_line = index + 1;
                     }
                 }
+                Ok(StreamEvent::Finished(response)) => {
+                    return send_response(writer, &response).await;
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    let notice = Response::Output {
+                        line: format!("[{} lines skipped: this client fell behind]", skipped),
+                        is_stderr: true,
+                        elapsed_ms: 0,
+                    };
+                    send_response(writer, &notice).await?;
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            _ = client_interrupt(client) => {
+                println!("Client detached from build {}", build_id);
+                return Ok(());
             }
-            line = stderr_reader.next_line() => {
-                match line {
-                    Ok(Some(line)) => {
-                        send_response(writer, &Response::Output { line, is_stderr: true }).await?;
+        }
+    }
+}
+
+/// Longest line forwarded whole; longer ones are split into pieces this size
+const MAX_LINE_BYTES: usize = 1024 * 1024;
+
+/// Forward lines from one of the child's pipes into the shared output channel.
+///
+/// The channel is bounded and its receiver only takes the next line once the
+/// last one was written to the client, so a client that reads slowly makes
+/// the child block on a full pipe rather than the server buffer its output.
+/// Capping the line length keeps a build that never prints a newline from
+/// doing the same, and bytes that aren't UTF-8 (e.g. a compiler writing in
+/// the console's code page) are replaced rather than ending the stream.
+fn spawn_line_reader<R>(
+    pipe: R,
+    is_stderr: bool,
+    started: Instant,
+    output: mpsc::Sender<OutputLine>,
+) where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut reader = BufReader::new(pipe);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match (&mut reader).take(MAX_LINE_BYTES as u64).read_until(b'\n', &mut buf).await {
+                Ok(0) => break,
+                Ok(_) => {
+                    if buf.last() == Some(&b'\n') {
+                        buf.pop();
+                        if buf.last() == Some(&b'\r') {
+                            buf.pop();
+                        }
                     }
-                    Ok(None) => {}
-                    Err(e) => {
-                        eprintln!("Error reading stderr: {}", e);
+                    let elapsed_ms = started.elapsed().as_millis() as u64;
+                    let line = OutputLine {
+                        line: String::from_utf8_lossy(&buf).into_owned(),
+                        is_stderr,
+                        elapsed_ms,
+                    };
+                    if output.send(line).await.is_err() {
+                        break;
                     }
                 }
+                Err(e) => {
+                    eprintln!("Error reading build output: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+}
+
+/// Wait until the client cancels or hangs up, ignoring anything else it sends
+async fn client_interrupt(client: &mut FrameReader<ReadHalf<'_>>) -> Interrupt {
+    loop {
+        match client.read_frame::<Request>().await {
+            Ok(Some(Request::Cancel { .. })) => return Interrupt::Cancelled,
+            Ok(Some(other)) => eprintln!("Ignoring unexpected message: {:?}", other),
+            Err(e) if e.is::<serde_json::Error>() => {
+                eprintln!("Ignoring malformed message: {}", e);
             }
+            Ok(None) | Err(_) => return Interrupt::Disconnected,
         }
     }
+}
 
-    // Wait for process to complete
-    let status = child.wait().await?;
-    let exit_code = status.code().unwrap_or(-1);
+/// The connection that requested a build. A `keep_running` build outlives
+/// it: once it hangs up, sends are skipped instead of ending the build.
+struct BuildClient<'c, 's> {
+    writer: &'c mut WriteHalf<'s>,
+    reader: &'c mut FrameReader<ReadHalf<'s>>,
+    build_id: BuildId,
+    keep_running: bool,
+    connected: bool,
+    /// A send that takes longer than this means the client is gone
+    /// (the heartbeat interval; `None` = wait as long as it takes)
+    send_timeout: Option<Duration>,
+    /// Output lines not sent yet (`None` = the client only understands
+    /// single `Output` lines, so nothing is held back)
+    batch: Option<Vec<OutputLine>>,
+    batch_bytes: usize,
+    /// When the oldest line in `batch` has waited long enough
+    flush_at: Option<tokio::time::Instant>,
+    compression: Option<Codec>,
+    /// The client's `server_truncate`, and the lines held back for it
+    truncation: Option<Truncation>,
+}
 
-    send_response(writer, &Response::BuildComplete { exit_code }).await?;
-    println!("Build completed with exit code: {}", exit_code);
+/// Where a build's output is in the client's `server_truncate`
+struct Truncation {
+    limits: ServerTruncation,
+    /// Lines sent from the start of the output
+    sent: usize,
+    /// The latest lines after those, sent when the build ends
+    tail: VecDeque<OutputLine>,
+    skipped: u64,
+    skipped_bytes: u64,
+}
+
+impl BuildClient<'_, '_> {
+    /// Send an output line, possibly held back to go out with the next ones,
+    /// or with the end of the output when the client asked for truncation
+    async fn send_line(&mut self, output_line: OutputLine) -> Result<(), Interrupt> {
+        if let Some(ref mut truncation) = self.truncation {
+            if truncation.sent >= truncation.limits.head {
+                truncation.tail.push_back(output_line);
+                if truncation.tail.len() > truncation.limits.tail {
+                    let dropped = truncation.tail.pop_front().unwrap();
+                    truncation.skipped += 1;
+                    truncation.skipped_bytes += dropped.line.len() as u64;
+                }
+                return Ok(());
+            }
+            truncation.sent += 1;
+        }
+        self.send_now(output_line).await
+    }
+
+    /// Send the truncation marker, if lines were left out, and the end of
+    /// the output held back for it
+    async fn send_tail(&mut self) -> Result<(), Interrupt> {
+        let Some(truncation) = self.truncation.take() else {
+            return Ok(());
+        };
+        if truncation.skipped > 0 {
+            let response = Response::Truncated {
+                skipped: truncation.skipped,
+                skipped_bytes: truncation.skipped_bytes,
+            };
+            self.send(&response).await?;
+        }
+        for output_line in truncation.tail {
+            self.send_now(output_line).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_now(&mut self, output_line: OutputLine) -> Result<(), Interrupt> {
+        let Some(ref mut batch) = self.batch else {
+            let OutputLine { line, is_stderr, elapsed_ms } = output_line;
+            return self.send(&Response::Output { line, is_stderr, elapsed_ms }).await;
+        };
+        if !self.connected {
+            return Ok(());
+        }
+        self.batch_bytes += output_line.line.len();
+        batch.push(output_line);
+        if batch.len() >= OUTPUT_BATCH_LINES || self.batch_bytes >= OUTPUT_BATCH_BYTES {
+            return self.flush().await;
+        }
+        self.flush_at
+            .get_or_insert_with(|| tokio::time::Instant::now() + OUTPUT_BATCH_DELAY);
+        Ok(())
+    }
+
+    /// Send the output lines held back so far
+    async fn flush(&mut self) -> Result<(), Interrupt> {
+        self.flush_at = None;
+        self.batch_bytes = 0;
+        let lines = match self.batch {
+            Some(ref mut batch) if !batch.is_empty() => std::mem::take(batch),
+            _ => return Ok(()),
+        };
+        self.write(&Response::OutputBatch { lines }).await
+    }
+
+    /// Send `response` if the client is still there, after any output lines
+    /// held back
+    async fn send(&mut self, response: &Response) -> Result<(), Interrupt> {
+        self.flush().await?;
+        self.write(response).await
+    }
+
+    async fn write(&mut self, response: &Response) -> Result<(), Interrupt> {
+        if !self.connected {
+            return Ok(());
+        }
+        let send = write_frame_with(self.writer, response, self.compression);
+        let sent = match self.send_timeout {
+            Some(limit) => tokio::time::timeout(limit, send)
+                .await
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out"))),
+            None => send.await,
+        };
+        if sent.is_err() {
+            self.hung_up()?;
+        }
+        Ok(())
+    }
+
+    /// Resolves when the client asks to cancel, or hangs up on a build that
+    /// doesn't outlive it
+    async fn interrupt(&mut self) -> Interrupt {
+        while self.connected {
+            match client_interrupt(self.reader).await {
+                Interrupt::Disconnected => {
+                    if let Err(interrupt) = self.hung_up() {
+                        return interrupt;
+                    }
+                }
+                interrupt => return interrupt,
+            }
+        }
+        std::future::pending().await
+    }
+
+    fn hung_up(&mut self) -> Result<(), Interrupt> {
+        if !self.keep_running {
+            return Err(Interrupt::Disconnected);
+        }
+        println!("Client disconnected, build {} keeps running", self.build_id);
+        self.connected = false;
+        Ok(())
+    }
+}
+
+/// Registers a build request for as long as it is handled: counted in
+/// `builds_in_flight` so shutdown can wait for it, with its stream published
+/// for `wait`
+struct InFlight<'a> {
+    state: &'a ServerState,
+    build_id: BuildId,
+    stream: Arc<BuildStream>,
+}
+
+impl<'a> InFlight<'a> {
+    fn new(state: &'a ServerState, build_id: BuildId) -> Self {
+        state.builds_in_flight.fetch_add(1, Ordering::SeqCst);
+        let stream = Arc::new(BuildStream::new());
+        state.streams.lock().unwrap().insert(build_id, stream.clone());
+        Self {
+            state,
+            build_id,
+            stream,
+        }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        // The stream outlives the handler in `recent_streams`, so a handler
+        // that bailed out without a result must still end it for `wait`
+        if self.stream.replay.lock().unwrap().finished.is_none() {
+            let message = format!("Build {} ended without a result", self.build_id);
+            self.stream.finish(Response::Error { message });
+        }
+        self.state.streams.lock().unwrap().remove(&self.build_id);
+        let mut recent = self.state.recent_streams.lock().unwrap();
+        if recent.len() == RECENT_STREAMS {
+            recent.pop_front();
+        }
+        recent.push_back((self.build_id, self.stream.clone()));
+        drop(recent);
+        if self.state.builds_in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.state.builds_drained.notify_waiters();
+        }
+        self.state.build_ended.notify_waiters();
+    }
+}
+
+/// A build's claim on its working directory, released on drop
+struct DirLock<'a> {
+    state: &'a ServerState,
+    build_id: BuildId,
+}
+
+impl Drop for DirLock<'_> {
+    fn drop(&mut self) {
+        self.state.dir_locks.lock().unwrap().remove(&self.build_id);
+        self.state.dir_unlocked.notify_waiters();
+    }
+}
+
+/// Wait until no running build uses `dir` or a directory above or below it,
+/// then claim it. Builds sharing intermediate files would corrupt each other.
+///
+/// `dir` must be canonical. An `Err` means the build was cancelled or the
+/// client hung up.
+async fn lock_build_dir<'a>(
+    client: &mut BuildClient<'_, '_>,
+    state: &'a ServerState,
+    build_id: BuildId,
+    dir: PathBuf,
+    cancel: &mut oneshot::Receiver<()>,
+) -> Result<Option<DirLock<'a>>, Interrupt> {
+    if !state.dir_lock {
+        return Ok(None);
+    }
+    let mut reported_holder = None;
+
+    loop {
+        let unlocked = state.dir_unlocked.notified();
+        tokio::pin!(unlocked);
+        unlocked.as_mut().enable();
+
+        let holder = {
+            let mut dir_locks = state.dir_locks.lock().unwrap();
+            let holder = dir_locks
+                .iter()
+                .find(|(_, held)| held.starts_with(&dir) || dir.starts_with(held))
+                .map(|(&id, _)| id);
+            if holder.is_none() {
+                dir_locks.insert(build_id, dir.clone());
+            }
+            holder
+        };
+        let Some(holder) = holder else {
+            return Ok(Some(DirLock { state, build_id }));
+        };
+
+        if reported_holder != Some(holder) {
+            let line = format!(
+                "waiting for build in same directory (id {}) to finish",
+                holder
+            );
+            println!("Build {} {}", build_id, line);
+            let response = Response::Output {
+                line,
+                is_stderr: true,
+                elapsed_ms: 0,
+            };
+            client.send(&response).await?;
+            reported_holder = Some(holder);
+        }
+
+        tokio::select! {
+            _ = &mut unlocked => {}
+            _ = &mut *cancel => return Err(Interrupt::Cancelled),
+            interrupt = client.interrupt() => return Err(interrupt),
+        }
+    }
+}
+
+/// Wait for a free build slot when `--max-concurrent` is set, telling the
+/// client its place in the queue whenever it changes.
+///
+/// The inner `Err` means the build was cancelled or the client hung up.
+async fn acquire_build_slot(
+    client: &mut BuildClient<'_, '_>,
+    state: &ServerState,
+    build_id: BuildId,
+    cancel: &mut oneshot::Receiver<()>,
+) -> Result<Result<Option<OwnedSemaphorePermit>, Interrupt>> {
+    let Some(ref slots) = state.build_slots else {
+        return Ok(Ok(None));
+    };
+    if let Ok(permit) = slots.clone().try_acquire_owned() {
+        return Ok(Ok(Some(permit)));
+    }
+
+    state.queue.lock().unwrap().push_back(build_id);
+
+    // Created once so the semaphore's FIFO order decides who goes next
+    let acquire = slots.clone().acquire_owned();
+    tokio::pin!(acquire);
+    let mut reported_position = 0;
+
+    let result = loop {
+        let changed = state.queue_changed.notified();
+        tokio::pin!(changed);
+        changed.as_mut().enable();
+
+        let position = state
+            .queue
+            .lock()
+            .unwrap()
+            .iter()
+            .position(|&id| id == build_id)
+            .map_or(1, |index| index + 1);
+        if position != reported_position {
+            println!("Build {} queued at position {}", build_id, position);
+            let response = Response::Queued { build_id, position };
+            if let Err(interrupt) = client.send(&response).await {
+                break Err(interrupt);
+            }
+            reported_position = position;
+        }
+
+        tokio::select! {
+            permit = &mut acquire => {
+                break Ok(Some(permit.expect("build slot semaphore is never closed")));
+            }
+            _ = &mut changed => {}
+            _ = &mut *cancel => break Err(Interrupt::Cancelled),
+            interrupt = client.interrupt() => break Err(interrupt),
+        }
+    };
+
+    state.queue.lock().unwrap().retain(|&id| id != build_id);
+    state.queue_changed.notify_waiters();
+    Ok(result)
+}
+
+/// Why the output loop stopped before the build finished on its own
+enum Interrupt {
+    Cancelled,
+    Disconnected,
+    TimedOut,
+    /// Silent for longer than `--stall-kill-secs`
+    Stalled,
+    /// Waiting for the build's process to exit failed
+    WaitFailed(std::io::Error),
+}
+
+/// A build request as received from `client_addr`
+struct BuildJob {
+    dir: PathBuf,
+    command: BuildCommand,
+    timeout: Option<Duration>,
+    env: Vec<(String, String)>,
+    /// Client's display limit, for the history's truncation flag
+    max_lines: usize,
+    /// Carry on if the client disconnects
+    keep_running: bool,
+    /// Only report what would be run
+    dry_run: bool,
+    /// Fed to the build's stdin (`None` = an empty stdin)
+    stdin: Option<Vec<u8>>,
+    /// Send this client only the start and end of the output
+    server_truncate: Option<ServerTruncation>,
+    client_addr: Peer,
+    /// The client sent a `Hello`, so it understands `Heartbeat` and
+    /// `OutputBatch`
+    said_hello: bool,
+    /// Compress the build's output frames, as agreed in `Hello`
+    compression: Option<Codec>,
+    /// Shell for a `BuildCommand::Shell` in place of the server's
+    shell: Option<Shell>,
+}
+
+async fn handle_build<'s>(
+    writer: &mut WriteHalf<'s>,
+    client: &mut FrameReader<ReadHalf<'s>>,
+    state: &ServerState,
+    job: BuildJob,
+) -> Result<()> {
+    let BuildJob {
+        dir,
+        command,
+        timeout,
+        env,
+        max_lines,
+        keep_running,
+        dry_run,
+        stdin,
+        server_truncate,
+        client_addr,
+        said_hello,
+        compression,
+        shell,
+    } = job;
+    let command_line = command.to_string();
+    let shell = shell.as_ref().unwrap_or(&state.shell);
+
+    println!("Build request: dir={}, cmd={}", dir.display(), command_line);
+    if !env.is_empty() {
+        let keys: Vec<&str> = env.iter().map(|(key, _)| key.as_str()).collect();
+        println!("  env overrides: {}", keys.join(", "));
+    }
+
+    if let Some(reason) = not_ready_reason(state) {
+        let response = if said_hello {
+            Response::NotReady { reason }
+        } else {
+            Response::Error { message: reason }
+        };
+        send_response(writer, &response).await?;
+        return Ok(());
+    }
+
+    // Check the directory up front; otherwise the failure only surfaces as a
+    // spawn error that doesn't say which path was wrong
+    let dir_error = match tokio::fs::metadata(&dir).await {
+        Ok(metadata) if metadata.is_dir() => None,
+        Ok(_) => Some(format!(
+            "working directory is not a directory: {}",
+            dir.display()
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Some(format!(
+            "working directory does not exist: {}",
+            dir.display()
+        )),
+        Err(e) => Some(format!(
+            "cannot access working directory {}: {}",
+            dir.display(),
+            e
+        )),
+    };
+    if let Some(message) = dir_error {
+        eprintln!("{}", message);
+        send_response(writer, &Response::Error { message }).await?;
+        return Ok(());
+    }
+    // What the directory lock compares, so `a/../b` and a symlink to `b`
+    // both count as `b`
+    let canonical_dir = match tokio::fs::canonicalize(&dir).await {
+        Ok(canonical_dir) => canonical_dir,
+        Err(e) => {
+            let message = format!(
+                "cannot access working directory {}: {}",
+                dir.display(),
+                e
+            );
+            eprintln!("{}", message);
+            send_response(writer, &Response::Error { message }).await?;
+            return Ok(());
+        }
+    };
+
+    let program = match command {
+        BuildCommand::Shell(ref line) => line.split_whitespace().next(),
+        BuildCommand::Argv { ref program, .. } => Some(program.as_str()).filter(|p| !p.is_empty()),
+    };
+    let Some(program) = program else {
+        send_response(
+            writer,
+            &Response::Error {
+                message: "Empty command".to_string(),
+            },
+        )
+        .await?;
+        return Ok(());
+    };
+
+    if dry_run {
+        let invocation = match command {
+            BuildCommand::Shell(ref line) => BuildCommand::Argv {
+                program: shell.program().to_string(),
+                args: shell.command_args(line),
+            },
+            BuildCommand::Argv { .. } => command,
+        };
+        let line = format!("[dry run] in {}: {}", dir.display(), invocation);
+        println!("{}", line);
+        let response = Response::Output {
+            line,
+            is_stderr: false,
+            elapsed_ms: 0,
+        };
+        send_response(writer, &response).await?;
+        let response = Response::BuildComplete {
+            build_id: 0,
+            exit_code: 0,
+            duration_ms: 0,
+            stdout_lines: 1,
+            stderr_lines: 0,
+            signal: None,
+            queued_ms: 0,
+        };
+        send_response(writer, &response).await?;
+        return Ok(());
+    }
+
+    let build_id = state.next_build_id.fetch_add(1, Ordering::SeqCst);
+    let in_flight = InFlight::new(state, build_id);
+    let build_stream = &in_flight.stream;
+    let mut client = BuildClient {
+        writer,
+        reader: client,
+        build_id,
+        keep_running,
+        connected: true,
+        send_timeout: state.heartbeat_interval.filter(|_| said_hello),
+        batch: said_hello.then(Vec::new),
+        batch_bytes: 0,
+        flush_at: None,
+        compression,
+        truncation: server_truncate.map(|limits| Truncation {
+            limits,
+            sent: 0,
+            tail: VecDeque::with_capacity(limits.tail + 1),
+            skipped: 0,
+            skipped_bytes: 0,
+        }),
+    };
+
+    // Register so a `cancel` request from another connection can stop us,
+    // first while waiting and then, once running, through `active_builds`
+    let (cancel_tx, mut cancel_rx) = oneshot::channel();
+    let queued = QueuedBuild {
+        command: command_line.clone(),
+        dir: dir.clone(),
+        client_addr,
+        since: Instant::now(),
+        cancel: cancel_tx,
+    };
+    state.waiting.lock().unwrap().insert(build_id, queued);
+
+    // Checked after registering, so a `stop` either sees this build in
+    // `waiting` and cancels it or this check sees the stop
+    if state.stopping.load(Ordering::SeqCst) {
+        state.waiting.lock().unwrap().remove(&build_id);
+        let message = if state.draining.load(Ordering::SeqCst) {
+            "The server is draining: running builds are finishing and new ones are refused"
+        } else {
+            "The server is shutting down"
+        };
+        let response = Response::Error {
+            message: message.to_string(),
+        };
+        build_stream.finish(response.clone());
+        let _ = client.send(&response).await;
+        return Ok(());
+    }
+
+    // A client that is already gone is noticed while waiting below
+    let _ = client.send(&Response::Accepted { build_id }).await;
+
+    let arrived = Instant::now();
+    let waited = async {
+        let dir_lock = match lock_build_dir(&mut client, state, build_id, canonical_dir, &mut cancel_rx).await {
+            Ok(dir_lock) => dir_lock,
+            Err(interrupt) => return Ok(Err(interrupt)),
+        };
+        let slot = match acquire_build_slot(&mut client, state, build_id, &mut cancel_rx).await? {
+            Ok(slot) => slot,
+            Err(interrupt) => return Ok(Err(interrupt)),
+        };
+        anyhow::Ok(Ok((dir_lock, slot)))
+    }
+    .await;
+    let cancel_tx = state
+        .waiting
+        .lock()
+        .unwrap()
+        .remove(&build_id)
+        .map(|queued| queued.cancel);
+
+    // Both held until the build finishes
+    let (_dir_lock, _slot, cancel_tx) = match (waited?, cancel_tx) {
+        (Ok((dir_lock, slot)), Some(cancel_tx)) => (dir_lock, slot, cancel_tx),
+        // A cancel that lands just as the wait ends has already taken the trigger
+        (Ok(_), None) | (Err(Interrupt::Cancelled), _) => {
+            println!("Build {} cancelled before it started.", build_id);
+            build_stream.finish(Response::Cancelled);
+            let _ = client.send(&Response::Cancelled).await;
+            return Ok(());
+        }
+        (Err(_), _) => {
+            println!("Build {} abandoned before it started.", build_id);
+            build_stream.finish(Response::Error {
+                message: "Build was abandoned because its client disconnected before it started".to_string(),
+            });
+            return Ok(());
+        }
+    };
+
+    let queued_ms = arrived.elapsed().as_millis() as u64;
+    println!("Running '{}' in {}", program, dir.display());
+
+    // Spawn the build process
+    let mut process = match command {
+        BuildCommand::Shell(ref line) => {
+            let mut process = Command::new(shell.program());
+            process.args(shell.command_args(line));
+            process
+        }
+        BuildCommand::Argv {
+            ref program,
+            ref args,
+        } => {
+            let mut process = Command::new(program);
+            process.args(args);
+            process
+        }
+    };
+    process
+        .current_dir(&dir)
+        // Never the server's own stdin: a build waiting on it would hang
+        .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let init_env = state.init_env.lock().unwrap().clone();
+    if let Some(ref init_env) = init_env {
+        // The captured environment is complete, so it replaces the server's own
+        process.env_clear().envs(init_env.iter());
+    }
+    process.envs(env);
+    // Own process group so a kill reaches everything the build spawned
+    #[cfg(unix)]
+    process.process_group(0);
+
+    let started = Instant::now();
+    let started_at = unix_now();
+    let mut child = match process.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            // Report the failure to this client only; the server keeps serving
+            // For a shell build it's the shell that couldn't be started
+            let spawned = match command {
+                BuildCommand::Shell(_) => {
+                    format!("shell '{}' to run '{}'", shell.program(), command_line)
+                }
+                BuildCommand::Argv { .. } => format!("'{}'", program),
+            };
+            let message = format!(
+                "Failed to spawn {} ({:?}): {}\n  command: {}\n  directory: {}",
+                spawned,
+                e.kind(),
+                e,
+                command_line,
+                dir.display()
+            );
+            eprintln!("{}", message);
+            state.log_event(
+                "spawn_failed",
+                &[("id", &build_id), ("command", &command_line), ("error", &e)],
+            );
+            let response = Response::Error { message };
+            build_stream.finish(response.clone());
+            let _ = client.send(&response).await;
+            return Ok(());
+        }
+    };
+    // Held until the build is over; on Windows dropping it also takes down
+    // anything the build left running
+    let tree = ProcessTree::new(&child);
+
+    // Fed from its own task so a build that reads slowly (or never) can't
+    // hold up its output; dropping the pipe afterwards closes it
+    if let (Some(mut pipe), Some(input)) = (child.stdin.take(), stdin) {
+        tokio::spawn(async move {
+            let _ = pipe.write_all(&input).await;
+        });
+    }
+
+    // Both pipes feed one channel so lines reach the client in the order they
+    // were read, rather than whichever `select!` branch happens to win
+    let (output_tx, mut output) = mpsc::channel(OUTPUT_CHANNEL_CAPACITY);
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    spawn_line_reader(stdout, false, started, output_tx.clone());
+    spawn_line_reader(stderr, true, started, output_tx);
+
+    let pid = child.id();
+    state.active_builds.lock().unwrap().insert(
+        build_id,
+        BuildInfo {
+            command: command_line.clone(),
+            dir: dir.clone(),
+            client_addr,
+            started,
+            pid,
+            cancel: cancel_tx,
+        },
+    );
+
+    state.builds_started.fetch_add(1, Ordering::Relaxed);
+    println!("Build {} started (pid {})", build_id, display_pid(pid));
+    state.log_event(
+        "build_started",
+        &[
+            ("id", &build_id),
+            ("pid", &display_pid(pid)),
+            ("addr", &client_addr),
+            ("dir", &dir.display()),
+            ("command", &command_line),
+        ],
+    );
+    // As with `Accepted`, a failed send is noticed by the loop below
+    let _ = client.send(&Response::BuildStarted { build_id, pid }).await;
+
+    let mut log = match state.log_dir {
+        Some(ref log_dir) => OutputLog::create(log_dir, state.keep_logs, build_id, &command_line, &dir)
+            .await
+            .map_err(|e| eprintln!("Not logging build {}: {:#}", build_id, e))
+            .ok(),
+        None => None,
+    };
+
+    let deadline = async {
+        match timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(deadline);
+
+    let mut output_open = true;
+    let mut stdout_lines = 0;
+    let mut stderr_lines = 0;
+    // Stall timers restart with every line of output
+    let mut last_output = tokio::time::Instant::now();
+    let mut stall_warned = false;
+    let mut next_heartbeat = client.send_timeout.map(|interval| last_output + interval);
+
+    // Stream output to client, watching the connection for a cancel or hang-up.
+    // Output is drained until both pipes close (linkers often print errors after
+    // stdout closes) and the build only finishes once the child has exited too.
+    let outcome = loop {
+        let stall_warn = state.stall_warn.filter(|_| !stall_warned);
+        let next_stall_check = [stall_warn, state.stall_kill]
+            .into_iter()
+            .flatten()
+            .min()
+            .map(|idle| last_output + idle);
+        let flush_at = client.flush_at;
+        tokio::select! {
+            _ = &mut cancel_rx => break Err(Interrupt::Cancelled),
+            _ = &mut deadline => break Err(Interrupt::TimedOut),
+            interrupt = client.interrupt() => break Err(interrupt),
+            _ = sleep_until(next_heartbeat) => {
+                if let Err(interrupt) = client.send(&Response::Heartbeat).await {
+                    break Err(interrupt);
+                }
+                next_heartbeat = client
+                    .send_timeout
+                    .map(|interval| tokio::time::Instant::now() + interval);
+            }
+            _ = sleep_until(flush_at) => {
+                if let Err(interrupt) = client.flush().await {
+                    break Err(interrupt);
+                }
+            }
+            _ = sleep_until(next_stall_check) => {
+                let idle = last_output.elapsed();
+                if state.stall_kill.is_some_and(|kill| idle >= kill) {
+                    break Err(Interrupt::Stalled);
+                }
+                stall_warned = true;
+                let note = format!("no output for {}s", idle.as_secs());
+                println!("Build {}: {}", build_id, note);
+                if let Err(interrupt) = send_note(&mut client, build_stream, started, note).await {
+                    break Err(interrupt);
+                }
+            }
+            line = output.recv(), if output_open => {
+                match line {
+                    Some(output_line) => {
+                        last_output = tokio::time::Instant::now();
+                        stall_warned = false;
+                        if let Some(ref mut output_log) = log {
+                            let OutputLine { ref line, is_stderr, .. } = output_line;
+                            if let Err(e) = output_log.write_line(line, is_stderr).await {
+                                eprintln!("Stopped logging build {}: {}", build_id, e);
+                                log = None;
+                            }
+                        }
+                        build_stream.publish(output_line.clone());
+                        if output_line.is_stderr {
+                            stderr_lines += 1;
+                        } else {
+                            stdout_lines += 1;
+                        }
+
+                        if let Err(interrupt) = client.send_line(output_line).await {
+                            break Err(interrupt);
+                        }
+                    }
+                    None => output_open = false,
+                }
+            }
+            status = child.wait(), if !output_open => break status.map_err(Interrupt::WaitFailed),
+        }
+    };
+
+    state.active_builds.lock().unwrap().remove(&build_id);
+
+    let mut signal = None;
+    let result = match outcome {
+        Ok(status) => {
+            let exit_code = status.code().unwrap_or(-1);
+            signal = exit_signal(&status);
+            match signal {
+                Some(signal) => println!("Build was killed by {}", signal_name(signal)),
+                None => println!("Build completed with exit code: {}", exit_code),
+            }
+            BuildResult::Exited(exit_code)
+        }
+        Err(Interrupt::Cancelled) => {
+            println!("Cancelling build...");
+            tree.kill(&mut child).await;
+            println!("Build cancelled.");
+            if state.stopping.load(Ordering::SeqCst) {
+                let note = "cancelled because the server is shutting down".to_string();
+                let _ = send_note(&mut client, build_stream, started, note).await;
+            }
+            BuildResult::Cancelled
+        }
+        Err(Interrupt::Disconnected) => {
+            println!("Client disconnected, killing build...");
+            tree.kill(&mut child).await;
+            BuildResult::Disconnected
+        }
+        Err(Interrupt::TimedOut) => {
+            println!(
+                "Build timed out after {}s, killing...",
+                started.elapsed().as_secs()
+            );
+            tree.kill(&mut child).await;
+            let note = format!(
+                "killed after the {}s timeout",
+                timeout.unwrap_or_default().as_secs()
+            );
+            let _ = send_note(&mut client, build_stream, started, note).await;
+            BuildResult::TimedOut
+        }
+        Err(Interrupt::Stalled) => {
+            let idle_secs = last_output.elapsed().as_secs();
+            println!("Build produced no output for {}s, killing...", idle_secs);
+            tree.kill(&mut child).await;
+            let note = format!("killed after {}s without output", idle_secs);
+            let _ = send_note(&mut client, build_stream, started, note).await;
+            BuildResult::Stalled(idle_secs)
+        }
+        Err(Interrupt::WaitFailed(e)) => {
+            eprintln!("Lost track of build {}: {}", build_id, e);
+            tree.kill(&mut child).await;
+            BuildResult::Lost
+        }
+    };
+    let duration = started.elapsed();
+    let duration_ms = duration.as_millis() as u64;
+    match (result, signal) {
+        (BuildResult::Exited(_), Some(signal)) => state.log_event(
+            "build_finished",
+            &[("id", &build_id), ("signal", &signal_name(signal)), ("duration_ms", &duration_ms)],
+        ),
+        (BuildResult::Exited(exit_code), None) => state.log_event(
+            "build_finished",
+            &[("id", &build_id), ("exit_code", &exit_code), ("duration_ms", &duration_ms)],
+        ),
+        _ => state.log_event(
+            "build_finished",
+            &[("id", &build_id), ("result", &result), ("duration_ms", &duration_ms)],
+        ),
+    }
+
+    if let Some(log) = log {
+        if let Err(e) = log.finish(result).await {
+            eprintln!("Failed to finish log for build {}: {}", build_id, e);
+        }
+    }
+
+    if let BuildResult::Exited(exit_code) = result {
+        *state.last_build.lock().unwrap() = Some(LastBuild {
+            id: build_id,
+            command: command_line.clone(),
+            exit_code,
+            finished_at: unix_now(),
+        });
+    }
+
+    let line_count = stdout_lines + stderr_lines;
+    let entry = HistoryEntry {
+        id: build_id,
+        started_at,
+        dir,
+        command: command_line,
+        duration_ms,
+        result,
+        truncated: max_lines > 0 && line_count > max_lines as u64,
+        stdout_lines,
+        stderr_lines,
+        signal,
+        queued_ms,
+    };
+    let response = final_response(&entry);
+    state.record_history(entry);
+    build_stream.finish(response.clone());
+    // Nobody is left to tell when the build died with its client
+    if !matches!(result, BuildResult::Disconnected) {
+        let _ = client.send_tail().await;
+        let _ = client.send(&response).await;
+    }
+
+    Ok(())
+}
+
+/// Signal that ended a process, which only Unix reports; its exit code is
+/// then missing
+fn exit_signal(status: &std::process::ExitStatus) -> Option<i32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        status.signal()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = status;
+        None
+    }
+}
+
+/// How long a build may run: what the client asked for (0 = unlimited),
+/// otherwise the server's `--default-timeout`
+fn build_timeout(state: &ServerState, requested: Option<u64>) -> Option<Duration> {
+    match requested {
+        Some(0) => None,
+        Some(secs) => Some(Duration::from_secs(secs)),
+        None => state.default_timeout,
+    }
+}
+
+/// What a client following a build is told once it has ended
+fn final_response(entry: &HistoryEntry) -> Response {
+    match entry.result {
+        BuildResult::Exited(exit_code) => Response::BuildComplete {
+            build_id: entry.id,
+            exit_code,
+            duration_ms: entry.duration_ms,
+            stdout_lines: entry.stdout_lines,
+            stderr_lines: entry.stderr_lines,
+            signal: entry.signal,
+            queued_ms: entry.queued_ms,
+        },
+        BuildResult::Cancelled => Response::Cancelled,
+        BuildResult::TimedOut => Response::Timeout {
+            elapsed_secs: entry.duration_ms / 1000,
+        },
+        BuildResult::Disconnected => Response::Error {
+            message: "Build was killed because its client disconnected".to_string(),
+        },
+        BuildResult::Stalled(idle_secs) => Response::Stalled { idle_secs },
+        BuildResult::Lost => Response::Error {
+            message: "The server could not wait for the build's process to exit".to_string(),
+        },
+    }
+}
+
+/// Tell the client, and anyone attached, something about the build as a
+/// `[build-runner]` line on its stderr
+async fn send_note(
+    client: &mut BuildClient<'_, '_>,
+    build_stream: &BuildStream,
+    started: Instant,
+    note: String,
+) -> Result<(), Interrupt> {
+    let line = OutputLine {
+        line: format!("[build-runner] {}", note),
+        is_stderr: true,
+        elapsed_ms: started.elapsed().as_millis() as u64,
+    };
+    build_stream.publish(line.clone());
+    let OutputLine { line, is_stderr, elapsed_ms } = line;
+    client.send(&Response::Output { line, is_stderr, elapsed_ms }).await
+}
+
+/// Wait until `at`, or forever without one
+async fn sleep_until(at: Option<tokio::time::Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Block until build `id` ends and send how it ended. A build that already
+/// finished is answered from the history.
+async fn handle_wait(
+    writer: &mut WriteHalf<'_>,
+    client: &mut FrameReader<ReadHalf<'_>>,
+    state: &ServerState,
+    id: BuildId,
+) -> Result<()> {
+    let stream = state.streams.lock().unwrap().get(&id).cloned();
+    let Some(stream) = stream else {
+        let finished = state
+            .history
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|entry| entry.id == id)
+            .map(final_response);
+        let response = finished.unwrap_or_else(|| Response::Error {
+            message: format!("No running or recently finished build with id {}", id),
+        });
+        return send_response(writer, &response).await;
+    };
+
+    println!("Client waiting for build {}", id);
+    // The handler finishes the stream however it ends (see `InFlight`)
+    let (_, finished, mut events) = stream.subscribe(u64::MAX);
+    drop(stream);
+    if let Some(response) = finished {
+        return send_response(writer, &response).await;
+    }
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(StreamEvent::Finished(response)) => {
+                    return send_response(writer, &response).await;
+                }
+                Ok(StreamEvent::Line(..)) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => {
+                    let message = format!("Build {} ended without a result", id);
+                    return send_response(writer, &Response::Error { message }).await;
+                }
+            },
+            _ = client_interrupt(client) => return Ok(()),
+        }
+    }
+}
+
+/// Seconds since the Unix epoch
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// File holding the complete output of build `id`
+fn build_log_path(log_dir: &Path, id: BuildId) -> PathBuf {
+    log_dir.join(format!("build-{}.log", id))
+}
+
+/// Ids of the build logs in `log_dir`, oldest first
+fn build_log_ids(log_dir: &Path) -> Result<Vec<BuildId>> {
+    let mut ids: Vec<BuildId> = std::fs::read_dir(log_dir)?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name();
+            name.to_str()?
+                .strip_prefix("build-")?
+                .strip_suffix(".log")?
+                .parse()
+                .ok()
+        })
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// A build's complete output, kept on the server for `logs`.
+///
+/// Uses the same layout as the client's `--log-file`: stderr lines are marked
+/// `[stderr] ` and the last line records how the build ended.
+struct OutputLog {
+    writer: tokio::io::BufWriter<tokio::fs::File>,
+}
+
+impl OutputLog {
+    /// Start the log for `build_id`, deleting the oldest logs beyond `keep`
+    async fn create(
+        log_dir: &Path,
+        keep: usize,
+        build_id: BuildId,
+        command: &str,
+        dir: &Path,
+    ) -> Result<Self> {
+        let path = build_log_path(log_dir, build_id);
+        let file = tokio::fs::File::create(&path)
+            .await
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        let mut log = Self {
+            writer: tokio::io::BufWriter::new(file),
+        };
+        log.writer
+            .write_all(format!("# {} (in {})\n", command, dir.display()).as_bytes())
+            .await?;
+
+        if keep > 0 {
+            let ids = build_log_ids(log_dir)?;
+            for id in &ids[..ids.len().saturating_sub(keep)] {
+                tokio::fs::remove_file(build_log_path(log_dir, *id)).await?;
+            }
+        }
+        Ok(log)
+    }
+
+    async fn write_line(&mut self, line: &str, is_stderr: bool) -> std::io::Result<()> {
+        if is_stderr {
+            self.writer.write_all(b"[stderr] ").await?;
+        }
+        self.writer.write_all(line.as_bytes()).await?;
+        self.writer.write_all(b"\n").await
+    }
+
+    async fn finish(mut self, result: BuildResult) -> std::io::Result<()> {
+        self.writer
+            .write_all(format!("result: {}\n", result).as_bytes())
+            .await?;
+        self.writer.flush().await
+    }
+}
+
+/// Server events appended to `--log-file`, one line each, so they outlive the
+/// console: `time=<unix secs> event=<name>` followed by `key=value` fields,
+/// with values quoted when they hold spaces
+struct EventLog {
+    path: PathBuf,
+    /// Size past which the file is moved to `<path>.1` (0 = never)
+    max_bytes: u64,
+    /// Held for a whole line so concurrent connections never interleave
+    file: Mutex<EventFile>,
+}
+
+struct EventFile {
+    file: std::fs::File,
+    /// Bytes in `file`, counting what was there before the server started
+    len: u64,
+}
+
+impl EventLog {
+    fn open(path: PathBuf, max_bytes: u64) -> Result<Self> {
+        let file = open_event_file(&path)
+            .with_context(|| format!("Failed to open log file {}", path.display()))?;
+        let len = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_bytes,
+            file: Mutex::new(EventFile { file, len }),
+        })
+    }
+
+    /// Append one event; a failed write is reported on the console rather
+    /// than disturbing whatever was being logged
+    fn record(&self, event: &str, fields: &[(&str, &dyn fmt::Display)]) {
+        let mut line = format!("time={} event={}", unix_now(), event);
+        for (key, value) in fields {
+            let value = value.to_string();
+            if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"') {
+                line.push_str(&format!(" {}={:?}", key, value));
+            } else {
+                line.push_str(&format!(" {}={}", key, value));
+            }
+        }
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = self.append(&mut file, line.as_bytes()) {
+            eprintln!("Failed to write to {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn append(&self, file: &mut EventFile, line: &[u8]) -> std::io::Result<()> {
+        use std::io::Write;
+
+        let len = line.len() as u64;
+        if self.max_bytes > 0 && file.len > 0 && file.len + len > self.max_bytes {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            std::fs::rename(&self.path, rotated)?;
+            file.file = open_event_file(&self.path)?;
+            file.len = 0;
+        }
+        file.file.write_all(line)?;
+        file.len += len;
+        Ok(())
+    }
+}
+
+fn open_event_file(path: &Path) -> std::io::Result<std::fs::File> {
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+}
+
+/// Stream a build's output back as `Output` lines, then `LogComplete`: from
+/// its log file, or from memory for the last few builds when there is none
+async fn handle_get_log(
+    writer: &mut WriteHalf<'_>,
+    state: &ServerState,
+    id: Option<BuildId>,
+    tail: Option<usize>,
+) -> Result<()> {
+    let latest_on_disk = || build_log_ids(state.log_dir.as_deref()?).ok()?.last().copied();
+    let latest_in_memory = || Some(state.recent_streams.lock().unwrap().back()?.0);
+    let build_id = match id.or_else(latest_on_disk).or_else(latest_in_memory) {
+        Some(build_id) => build_id,
+        None => {
+            let message = "No build logs yet".to_string();
+            send_response(writer, &Response::Error { message }).await?;
+            return Ok(());
+        }
+    };
+
+    // The log file is complete; memory only holds the last `REPLAY_LINES`
+    if let Some(ref log_dir) = state.log_dir {
+        match tokio::fs::File::open(build_log_path(log_dir, build_id)).await {
+            Ok(file) => return send_log_file(writer, file, build_id, tail).await,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let recent = state
+        .recent_streams
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(id, _)| *id == build_id)
+        .map(|(_, stream)| stream.clone());
+    let Some(stream) = recent else {
+        let message = if state.log_dir.is_some() {
+            format!("No log for build {}", build_id)
+        } else {
+            format!(
+                "No log for build {}; without build logs only the last {} builds are kept",
+                build_id, RECENT_STREAMS
+            )
+        };
+        send_response(writer, &Response::Error { message }).await?;
+        return Ok(());
+    };
+
+    let (lines, _, _) = stream.subscribe(0);
+    let skip = lines.len().saturating_sub(tail.unwrap_or(usize::MAX));
+    let dropped = lines.first().map_or(0, |(index, _)| *index);
+    if skip == 0 && dropped > 0 {
+        let response = Response::Output {
+            line: format!("[build-runner] first {} lines no longer kept", dropped),
+            is_stderr: true,
+            elapsed_ms: 0,
+        };
+        send_response(writer, &response).await?;
+    }
+    for (_, OutputLine { line, is_stderr, elapsed_ms }) in lines.into_iter().skip(skip) {
+        send_response(writer, &Response::Output { line, is_stderr, elapsed_ms }).await?;
+    }
+
+    send_response(writer, &Response::LogComplete { build_id }).await
+}
+
+/// Send a build's log file as `Output` lines, or only its last `tail` lines.
+///
+/// With `tail`, only that many lines are held in memory at once.
+async fn send_log_file(
+    writer: &mut WriteHalf<'_>,
+    file: tokio::fs::File,
+    build_id: BuildId,
+    tail: Option<usize>,
+) -> Result<()> {
+    let mut lines = BufReader::new(file).lines();
+
+    let output = |line| Response::Output {
+        line,
+        is_stderr: false,
+        elapsed_ms: 0,
+    };
+    match tail {
+        Some(tail) => {
+            let mut last = VecDeque::with_capacity(tail);
+            while let Some(line) = lines.next_line().await? {
+                if last.len() == tail {
+                    last.pop_front();
+                }
+                if tail > 0 {
+                    last.push_back(line);
+                }
+            }
+            for line in last {
+                send_response(writer, &output(line)).await?;
+            }
+        }
+        None => {
+            while let Some(line) = lines.next_line().await? {
+                send_response(writer, &output(line)).await?;
+            }
+        }
+    }
+
+    send_response(writer, &Response::LogComplete { build_id }).await
+}
+
+/// Stop accepting connections, then exit once the builds in flight are done.
+/// Shared by `stop` and Ctrl+C at the server console; see [`stop_builds`]
+/// for `force` and the count returned.
+fn shut_down(state: &ServerState, force: bool) -> usize {
+    let active_builds = stop_builds(state, force);
+    state.shutdown.notify_one();
+    active_builds
+}
+
+/// Refuse new builds and cancel the queued ones; running ones finish unless
+/// `force` kills them too. Returns the number of builds still running or
+/// being killed.
+fn stop_builds(state: &ServerState, force: bool) -> usize {
+    state.draining.store(!force, Ordering::SeqCst);
+    state.stopping.store(true, Ordering::SeqCst);
+    let dequeued: Vec<_> = state.waiting.lock().unwrap().drain().collect();
+    for (id, queued) in dequeued {
+        println!("Cancelling build {} before it starts", id);
+        let _ = queued.cancel.send(());
+    }
+    if !force {
+        return state.active_builds.lock().unwrap().len();
+    }
+    let killed: Vec<_> = state.active_builds.lock().unwrap().drain().collect();
+    let count = killed.len();
+    for (id, build) in killed {
+        println!("Cancelling build {} ('{}')", id, build.command);
+        let _ = build.cancel.send(());
+    }
+    count
+}
+
+/// Read the next request, answering one this server can't parse (most
+/// likely from a newer client) with an `Error` rather than hanging up.
+/// `None` means the connection is done with.
+async fn read_request(
+    reader: &mut FrameReader<ReadHalf<'_>>,
+    writer: &mut WriteHalf<'_>,
+    client_version: Option<&str>,
+) -> Result<Option<Request>> {
+    match reader.read_frame::<Request>().await {
+        Ok(request) => Ok(request),
+        Err(e) if e.downcast_ref::<serde_json::Error>().is_some_and(|e| !e.is_data()) => {
+            let message = format!("Malformed request, expected a JSON message: {}", e);
+            eprintln!("{}", message);
+            send_response(writer, &Response::Error { message }).await?;
+            Ok(None)
+        }
+        // Valid JSON, but not a request this server knows
+        Err(e) if e.is::<serde_json::Error>() => {
+            let message = match client_version {
+                Some(version) if version != VERSION => {
+                    format!("Unsupported request ({}): {}", e, version_mismatch(version, VERSION))
+                }
+                _ => format!("Unsupported request ({}); is the client newer than this server (v{})?", e, VERSION),
+            };
+            eprintln!("{}", message);
+            send_response(writer, &Response::Error { message }).await?;
+            Ok(None)
+        }
+        Err(e) if e.is::<FrameTooLarge>() => {
+            let message = format!("Request refused: {} (server --max-request-mb)", e);
+            eprintln!("{}", message);
+            send_response(writer, &Response::Error { message }).await?;
+            // Hanging up on unread data would reset the connection before
+            // the client reads the error
+            let _ = tokio::time::timeout(DISCARD_TIMEOUT, reader.discard_rest()).await;
+            Ok(None)
+        }
+        Err(e) if e.is::<TruncatedFrame>() => {
+            // The client may only have shut down its side, so it can still
+            // hear why nothing happens
+            let message = format!("Incomplete request: {}", e);
+            eprintln!("{}", message);
+            let _ = send_response(writer, &Response::Error { message }).await;
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
 
-    Ok(())
+/// How long a refused request may keep sending before the server hangs up
+const DISCARD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The builds running now, oldest first
+fn running_builds(state: &ServerState) -> Vec<BuildSummary> {
+    let mut builds: Vec<BuildSummary> = state
+        .active_builds
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(&id, build)| BuildSummary {
+            id,
+            dir: build.dir.clone(),
+            command: build.command.clone(),
+            elapsed_secs: build.started.elapsed().as_secs(),
+            client: build.client_addr.to_string(),
+            pid: build.pid,
+            queued: false,
+        })
+        .collect();
+    builds.sort_by_key(|build| build.id);
+    builds
+}
+
+/// Builds waiting to start, oldest first, for `list`; their elapsed time
+/// is how long they have waited
+fn queued_builds(state: &ServerState) -> Vec<BuildSummary> {
+    let mut builds: Vec<BuildSummary> = state
+        .waiting
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(&id, build)| BuildSummary {
+            id,
+            dir: build.dir.clone(),
+            command: build.command.clone(),
+            elapsed_secs: build.since.elapsed().as_secs(),
+            client: build.client_addr.to_string(),
+            pid: None,
+            queued: true,
+        })
+        .collect();
+    builds.sort_by_key(|build| build.id);
+    builds
+}
+
+/// This machine's name, so users can tell which server they reached
+fn hostname() -> Option<String> {
+    #[cfg(unix)]
+    {
+        let mut buf = [0u8; 256];
+        // SAFETY: the length passed is the buffer's, and the name is read
+        // back only up to its terminating NUL
+        if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+            return None;
+        }
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+    }
+    #[cfg(not(unix))]
+    {
+        std::env::var("COMPUTERNAME").ok()
+    }
+}
+
+/// `child.id()` is only `None` once the process has been reaped
+fn display_pid(pid: Option<u32>) -> String {
+    pid.map_or_else(|| "unknown".to_string(), |pid| pid.to_string())
 }
 
 async fn send_response(
-    writer: &mut tokio::net::tcp::WriteHalf<'_>,
+    writer: &mut WriteHalf<'_>,
     response: &Response,
 ) -> Result<()> {
-    let json = serde_json::to_string(response)?;
-    writer.write_all(json.as_bytes()).await?;
-    writer.write_all(b"\n").await?;
-    writer.flush().await?;
-    Ok(())
+    write_frame(writer, response).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Start a server on a free loopback port, with `configure` applied to
+    /// the defaults, and return where to reach it
+    async fn start(configure: impl FnOnce(&mut ServerConfig)) -> Address {
+        let mut config = ServerConfig::new(Address::Tcp {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+        });
+        configure(&mut config);
+        let server = Server::bind(config).await.unwrap();
+        let port = server.local_addr().rsplit_once(':').unwrap().1.parse().unwrap();
+        tokio::spawn(server.serve());
+        Address::Tcp {
+            host: "127.0.0.1".to_string(),
+            port,
+        }
+    }
+
+    /// Connect and exchange `Hello`, as current clients do
+    async fn connect(address: &Address) -> Stream {
+        let mut stream = Stream::connect(address).await.unwrap();
+        let hello = Request::Hello {
+            protocol: PROTOCOL_VERSION,
+            version: VERSION.to_string(),
+            compression: None,
+        };
+        write_frame(&mut stream, &hello).await.unwrap();
+        let response = FrameReader::new(&mut stream).read_frame().await.unwrap();
+        assert!(matches!(response, Some(Response::Hello { .. })), "{:?}", response);
+        stream
+    }
+
+    /// Send `request` and collect the responses until the server hangs up
+    async fn exchange(address: &Address, request: Request) -> Vec<Response> {
+        let mut stream = connect(address).await;
+        write_frame(&mut stream, &request).await.unwrap();
+        let mut reader = FrameReader::new(&mut stream);
+        let mut responses = Vec::new();
+        while let Some(response) = reader.read_frame().await.unwrap() {
+            responses.push(response);
+        }
+        responses
+    }
+
+    /// Send `request` on a connection of its own, whose responses are read
+    /// as they arrive; dropping the reader hangs up
+    async fn open(address: &Address, request: Request) -> FrameReader<Stream> {
+        let mut stream = connect(address).await;
+        write_frame(&mut stream, &request).await.unwrap();
+        FrameReader::new(stream)
+    }
+
+    /// Responses up to and including the first one `last` picks out
+    async fn read_until(
+        reader: &mut FrameReader<Stream>,
+        last: impl Fn(&Response) -> bool,
+    ) -> Vec<Response> {
+        let mut responses = Vec::new();
+        loop {
+            let response = reader.read_frame().await.unwrap().expect("server hung up");
+            let done = last(&response);
+            responses.push(response);
+            if done {
+                return responses;
+            }
+        }
+    }
+
+    /// Empty directory for a test's files, cleared of any earlier run's
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("build-runner-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn started(response: &Response) -> bool {
+        matches!(response, Response::BuildStarted { .. })
+    }
+
+    fn complete(response: &Response) -> bool {
+        matches!(response, Response::BuildComplete { .. })
+    }
+
+    /// `Build` of `command` through the server's shell, in the temp directory
+    fn build(command: &str) -> Request {
+        build_in(&std::env::temp_dir(), command)
+    }
+
+    fn build_in(dir: &Path, command: &str) -> Request {
+        Request::Build {
+            dir: dir.to_path_buf(),
+            command: command.to_string(),
+            timeout: None,
+            env: Vec::new(),
+            max_lines: 0,
+            keep_running: false,
+            dry_run: false,
+            stdin: None,
+            shell: None,
+            server_truncate: None,
+        }
+    }
+
+    /// `BuildArgv` of `program` with `args`, in the temp directory
+    fn build_argv(program: &str, args: &[&str]) -> Request {
+        Request::BuildArgv {
+            dir: std::env::temp_dir(),
+            program: program.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            timeout: None,
+            env: Vec::new(),
+            max_lines: 0,
+            keep_running: false,
+            dry_run: false,
+            stdin: None,
+            server_truncate: None,
+        }
+    }
+
+    /// The output lines among `responses`, batched or not, marking stderr
+    fn output(responses: &[Response]) -> Vec<(String, bool)> {
+        let mut lines = Vec::new();
+        for response in responses {
+            match response {
+                Response::Output { line, is_stderr, .. } => lines.push((line.clone(), *is_stderr)),
+                Response::OutputBatch { lines: batch } => {
+                    lines.extend(batch.iter().map(|line| (line.line.clone(), line.is_stderr)))
+                }
+                _ => {}
+            }
+        }
+        lines
+    }
+
+    // Build commands in these tests are written for `sh`, the default shell on Unix
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn stderr_is_read_after_stdout_closes() {
+        let address = start(|_| {}).await;
+        let responses = exchange(
+            &address,
+            build("echo out; exec 1>&-; sleep 0.2; echo late >&2; exit 3"),
+        )
+        .await;
+        assert_eq!(
+            output(&responses),
+            [("out".to_string(), false), ("late".to_string(), true)]
+        );
+        assert!(
+            matches!(
+                responses.last(),
+                Some(Response::BuildComplete { exit_code: 3, stdout_lines: 1, stderr_lines: 1, .. })
+            ),
+            "{:?}",
+            responses.last()
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn stdout_and_stderr_keep_their_order() {
+        let address = start(|_| {}).await;
+        let command = "echo one; sleep 0.05; echo two >&2; sleep 0.05; echo three; sleep 0.05; echo four >&2";
+        let lines = output(&exchange(&address, build(command)).await);
+        assert_eq!(
+            lines,
+            [
+                ("one".to_string(), false),
+                ("two".to_string(), true),
+                ("three".to_string(), false),
+                ("four".to_string(), true),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn builds_beyond_max_concurrent_are_queued() {
+        let address = start(|config| config.dir_lock = false).await;
+        // Each build notes when it starts and ends, so overlaps would show
+        let trace = scratch_dir("queue").join("trace");
+        let traced = |n: u32| {
+            build(&format!(
+                "echo start {0} >> {1}; sleep 0.2; echo end {0} >> {1}",
+                n,
+                trace.display()
+            ))
+        };
+        let mut first = open(&address, traced(1)).await;
+        read_until(&mut first, started).await;
+
+        let queued = |response: &Response| matches!(response, Response::Queued { .. });
+        let mut second = open(&address, traced(2)).await;
+        let waiting = read_until(&mut second, queued).await;
+        assert!(
+            matches!(
+                waiting[..],
+                [Response::Accepted { build_id: 2 }, Response::Queued { build_id: 2, position: 1 }]
+            ),
+            "{:?}",
+            waiting
+        );
+        let mut third = open(&address, traced(3)).await;
+        let waiting = read_until(&mut third, queued).await;
+        assert!(
+            matches!(
+                waiting[..],
+                [Response::Accepted { build_id: 3 }, Response::Queued { build_id: 3, position: 2 }]
+            ),
+            "{:?}",
+            waiting
+        );
+
+        let first = read_until(&mut first, complete).await;
+        assert!(matches!(first.last(), Some(Response::BuildComplete { exit_code: 0, .. })));
+        let second = read_until(&mut second, complete).await;
+        assert!(matches!(second[0], Response::BuildStarted { build_id: 2, .. }), "{:?}", second);
+        let Some(&Response::BuildComplete { queued_ms, .. }) = second.last() else {
+            unreachable!()
+        };
+        assert!(queued_ms >= 100, "queued for {}ms", queued_ms);
+        // The third moves up once the second leaves the queue
+        let third = read_until(&mut third, complete).await;
+        assert!(
+            matches!(
+                third[..],
+                [Response::Queued { build_id: 3, position: 1 }, Response::BuildStarted { build_id: 3, .. }, ..]
+            ),
+            "{:?}",
+            third
+        );
+        assert!(matches!(third.last(), Some(Response::BuildComplete { exit_code: 0, .. })));
+
+        let trace = std::fs::read_to_string(&trace).unwrap();
+        let trace: Vec<_> = trace.lines().collect();
+        assert_eq!(trace, ["start 1", "end 1", "start 2", "end 2", "start 3", "end 3"]);
+    }
+
+    #[tokio::test]
+    async fn missing_or_file_directory_is_refused() {
+        let address = start(|_| {}).await;
+        let missing = std::env::temp_dir().join("build-runner-no-such-dir");
+        let file = std::env::current_exe().unwrap();
+        for (dir, expected) in [
+            (&missing, "working directory does not exist"),
+            (&file, "working directory is not a directory"),
+        ] {
+            let responses = exchange(&address, build_in(dir, "echo hi")).await;
+            let Some(Response::Error { message }) = responses.last() else {
+                panic!("expected an error, got {:?}", responses);
+            };
+            assert_eq!(*message, format!("{}: {}", expected, dir.display()));
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_need_the_auth_token() {
+        let address = start(|config| config.auth_token = Some("secret".to_string())).await;
+        let responses = exchange(&address, Request::Status).await;
+        assert!(matches!(responses[..], [Response::Unauthorized]), "{:?}", responses);
+
+        for (token, authorized) in [("guess", false), ("secret", true)] {
+            let mut stream = connect(&address).await;
+            let auth = Request::Auth {
+                token: token.to_string(),
+            };
+            write_frame(&mut stream, &auth).await.unwrap();
+            write_frame(&mut stream, &Request::Status).await.unwrap();
+            let response = FrameReader::new(&mut stream).read_frame().await.unwrap();
+            match response {
+                Some(Response::Status(_)) => assert!(authorized),
+                Some(Response::Unauthorized) => assert!(!authorized),
+                other => panic!("unexpected response {:?}", other),
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn builds_run_over_a_unix_socket() {
+        let address = Address::Unix(scratch_dir("unix-socket").join("server.sock"));
+        let server = Server::bind(ServerConfig::new(address.clone())).await.unwrap();
+        tokio::spawn(server.serve());
+        let responses = exchange(&address, build("echo over the socket")).await;
+        assert_eq!(output(&responses), [("over the socket".to_string(), false)]);
+        assert!(matches!(responses.last(), Some(Response::BuildComplete { exit_code: 0, .. })));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn status_reports_the_last_completed_build() {
+        let address = start(|_| {}).await;
+        exchange(&address, build("exit 7")).await;
+        // Builds that didn't run to completion don't count
+        let mut abandoned = open(&address, build("sleep 5")).await;
+        read_until(&mut abandoned, started).await;
+        drop(abandoned);
+
+        let responses = exchange(&address, Request::Status).await;
+        let Some(Response::Status(status)) = responses.first() else {
+            panic!("expected status, got {:?}", responses);
+        };
+        let last = status.last_build.as_ref().unwrap();
+        assert_eq!((last.id, last.exit_code, last.command.as_str()), (1, 7, "exit 7"));
+        assert_eq!(status.builds_started, 2);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn attach_replays_buffered_output_then_follows() {
+        let address = start(|_| {}).await;
+        let mut builder = open(&address, build("echo one; echo two; sleep 0.3; echo three")).await;
+        let printed_two = |response: &Response| {
+            output(std::slice::from_ref(response)).contains(&("two".to_string(), false))
+        };
+        read_until(&mut builder, printed_two).await;
+
+        let attach = || Request::Attach {
+            id: Some(1),
+            from_line: 0,
+            tail: None,
+        };
+        let responses = exchange(&address, attach()).await;
+        assert!(matches!(responses[0], Response::BuildStarted { build_id: 1, .. }), "{:?}", responses);
+        let lines: Vec<String> = output(&responses).into_iter().map(|(line, _)| line).collect();
+        assert_eq!(lines, ["one", "two", "three"]);
+        assert!(matches!(responses.last(), Some(Response::BuildComplete { exit_code: 0, .. })));
+
+        let responses = exchange(&address, attach()).await;
+        assert!(
+            matches!(&responses[..], [Response::Error { message }] if message == "No running build with id 1"),
+            "{:?}",
+            responses
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn stop_drains_running_builds_unless_forced() {
+        for force in [false, true] {
+            let address = start(|_| {}).await;
+            let mut builder = open(&address, build("sleep 0.3; echo done")).await;
+            read_until(&mut builder, started).await;
+            // Connected before the stop, asking for a build after it
+            let mut late = connect(&address).await;
+
+            let responses = exchange(&address, Request::Stop { force, drain: !force }).await;
+            assert!(
+                matches!(
+                    responses[..],
+                    [Response::Stopping { active_builds: 1 }, Response::Stopping { active_builds: 0 }]
+                ),
+                "{:?}",
+                responses
+            );
+            write_frame(&mut late, &build("echo late")).await.unwrap();
+            let response = FrameReader::new(&mut late).read_frame().await.unwrap();
+            let Some(Response::Error { message }) = response else {
+                panic!("{:?}", response)
+            };
+            if force {
+                assert_eq!(message, "The server is shutting down");
+            } else {
+                assert_eq!(
+                    message,
+                    "The server is draining: running builds are finishing and new ones are refused"
+                );
+            }
+            let rest = read_until(&mut builder, |response| {
+                matches!(response, Response::BuildComplete { .. } | Response::Cancelled)
+            })
+            .await;
+            if force {
+                assert!(matches!(rest.last(), Some(Response::Cancelled)), "{:?}", rest);
+            } else {
+                assert_eq!(output(&rest), [("done".to_string(), false)]);
+                assert!(matches!(rest.last(), Some(Response::BuildComplete { exit_code: 0, .. })));
+            }
+            assert!(Stream::connect(&address).await.is_err());
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn completion_reports_duration_and_line_counts() {
+        let address = start(|_| {}).await;
+        let responses = exchange(&address, build("echo a; echo b; echo c >&2; sleep 0.2")).await;
+        let Some(&Response::BuildComplete {
+            build_id,
+            exit_code,
+            duration_ms,
+            stdout_lines,
+            stderr_lines,
+            signal,
+            ..
+        }) = responses.last()
+        else {
+            panic!("build didn't complete: {:?}", responses);
+        };
+        assert_eq!((build_id, exit_code, stdout_lines, stderr_lines, signal), (1, 0, 2, 1, None));
+        assert!((200..5000).contains(&duration_ms), "took {}ms", duration_ms);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn env_set_unset_and_show() {
+        let address = start(|_| {}).await;
+        let vars = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs.iter().map(|&(key, value)| (key.to_string(), value.to_string())).collect()
+        };
+        let set = Request::SetEnv {
+            vars: vars(&[("BR_TEST_B", "two words"), ("BR_TEST_A", "1")]),
+        };
+        let Response::EnvUpdated { env_vars } = exchange(&address, set).await[0] else {
+            panic!("expected EnvUpdated");
+        };
+
+        let show = || Request::GetEnv {
+            prefix: Some("BR_TEST_".to_string()),
+        };
+        let Response::Env { vars: ref shown } = exchange(&address, show()).await[0] else {
+            panic!("expected Env");
+        };
+        assert_eq!(*shown, vars(&[("BR_TEST_A", "1"), ("BR_TEST_B", "two words")]));
+        let lines = output(&exchange(&address, build("echo \"$BR_TEST_B\"")).await);
+        assert_eq!(lines, [("two words".to_string(), false)]);
+
+        let unset = Request::UnsetEnv {
+            keys: vec!["BR_TEST_A".to_string(), "BR_TEST_MISSING".to_string()],
+        };
+        let Response::EnvUpdated { env_vars: left } = exchange(&address, unset).await[0] else {
+            panic!("expected EnvUpdated");
+        };
+        assert_eq!(left, env_vars - 1);
+        let Response::Env { vars: ref shown } = exchange(&address, show()).await[0] else {
+            panic!("expected Env");
+        };
+        assert_eq!(*shown, vars(&[("BR_TEST_B", "two words")]));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn build_started_reports_the_shell_pid() {
+        let address = start(|_| {}).await;
+        let mut builder = open(&address, build("echo $$; sleep 0.3")).await;
+        let responses = read_until(&mut builder, started).await;
+        let Some(&Response::BuildStarted { pid: Some(pid), .. }) = responses.last() else {
+            panic!("no pid in {:?}", responses);
+        };
+
+        let listed = exchange(&address, Request::ListBuilds).await;
+        let Response::Builds { ref builds } = listed[0] else {
+            panic!("expected Builds, got {:?}", listed);
+        };
+        assert_eq!(builds[0].pid, Some(pid));
+
+        let rest = read_until(&mut builder, complete).await;
+        assert_eq!(output(&rest), [(pid.to_string(), false)]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn concurrent_builds_are_all_listed() {
+        let address = start(|config| config.max_concurrent = 2).await;
+        let (first_dir, second_dir) = (scratch_dir("list-first"), scratch_dir("list-second"));
+        let mut first = open(&address, build_in(&first_dir, "sleep 0.3")).await;
+        read_until(&mut first, started).await;
+        let mut second = open(&address, build_in(&second_dir, "sleep 0.3")).await;
+        read_until(&mut second, started).await;
+
+        let listed = exchange(&address, Request::ListBuilds).await;
+        let [Response::Builds { ref builds }] = listed[..] else {
+            panic!("{:?}", listed)
+        };
+        let mut running: Vec<_> = builds
+            .iter()
+            .map(|build| (build.id, build.dir.clone(), build.queued))
+            .collect();
+        running.sort();
+        assert_eq!(running, [(1, first_dir, false), (2, second_dir, false)]);
+
+        read_until(&mut first, complete).await;
+        read_until(&mut second, complete).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn waiting_on_a_build_abandoned_in_the_queue_gets_an_answer() {
+        let address = start(|_| {}).await;
+        let mut running = open(&address, build("sleep 0.5")).await;
+        read_until(&mut running, started).await;
+        let mut queued = open(&address, build("echo never")).await;
+        let responses = read_until(&mut queued, |response| matches!(response, Response::Accepted { .. })).await;
+        let Some(&Response::Accepted { build_id }) = responses.last() else {
+            panic!("{:?}", responses)
+        };
+
+        let waiter = tokio::spawn({
+            let address = address.clone();
+            async move { exchange(&address, Request::Wait { id: build_id }).await }
+        });
+        // Give the waiter time to subscribe before the build goes away
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        drop(queued);
+
+        let responses = tokio::time::timeout(Duration::from_secs(5), waiter)
+            .await
+            .expect("the waiter was never answered")
+            .unwrap();
+        let [Response::Error { message }] = responses.as_slice() else {
+            panic!("{:?}", responses)
+        };
+        assert_eq!(message, "Build was abandoned because its client disconnected before it started");
+        read_until(&mut running, complete).await;
+    }
+
+    #[tokio::test]
+    async fn dry_run_shows_the_invocation_without_running_it() {
+        let address = start(|config| config.shell = Shell::new("bash", None)).await;
+        let marker = std::env::temp_dir().join(format!("build-runner-dry-run-{}", std::process::id()));
+        let mut request = build(&format!("touch {}", marker.display()));
+        if let Request::Build { ref mut dry_run, .. } = request {
+            *dry_run = true;
+        }
+        let responses = exchange(&address, request).await;
+        let expected = format!(
+            "[dry run] in {}: bash -c \"touch {}\"",
+            std::env::temp_dir().display(),
+            marker.display()
+        );
+        assert_eq!(output(&responses), [(expected, false)]);
+        assert!(matches!(responses.last(), Some(Response::BuildComplete { build_id: 0, exit_code: 0, .. })));
+        assert!(!marker.exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn failed_init_refuses_builds_unless_required() {
+        let failing_init = || vec![InitSource::Command("echo broken; exit 3".to_string())];
+        let mut config = ServerConfig::new(Address::Tcp {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+        });
+        config.init = failing_init();
+        assert!(Server::bind(config).await.is_err());
+
+        let address = start(|config| {
+            config.init = failing_init();
+            config.require_init = false;
+        })
+        .await;
+        let responses = exchange(&address, build("echo hi")).await;
+        let [Response::NotReady { ref reason }] = responses[..] else {
+            panic!("expected NotReady, got {:?}", responses);
+        };
+        assert!(reason.starts_with("server not initialized: "), "{}", reason);
+
+        let Response::Status(ref status) = exchange(&address, Request::Status).await[0] else {
+            panic!("expected Status");
+        };
+        assert!(!status.initialized);
+        assert!(status.init_error.is_some());
+        assert_eq!(status.init_output_tail.last().map(String::as_str), Some("broken"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reinit_reruns_the_init_script_for_later_builds() {
+        let script = scratch_dir("reinit").join("init.sh");
+        let write_script = |value: &str| {
+            let contents = format!("export BR_TEST_INIT={0}\necho loaded {0}\n", value);
+            std::fs::write(&script, contents).unwrap();
+        };
+        write_script("one");
+        let address = start(|config| config.init = vec![InitSource::Script(script.clone())]).await;
+        let echo_init = || build("echo \"$BR_TEST_INIT\"");
+        assert_eq!(output(&exchange(&address, echo_init()).await), [("one".to_string(), false)]);
+
+        write_script("two");
+        let responses = exchange(&address, Request::Reinit).await;
+        assert!(
+            output(&responses).iter().any(|(line, _)| line.ends_with("loaded two")),
+            "{:?}",
+            responses
+        );
+        assert!(
+            matches!(responses.last(), Some(Response::ReinitComplete { env_vars }) if *env_vars > 0),
+            "{:?}",
+            responses
+        );
+        assert_eq!(output(&exchange(&address, echo_init()).await), [("two".to_string(), false)]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn builds_are_not_ready_while_init_runs() {
+        let address = start(|config| {
+            config.init = vec![InitSource::Command("sleep 0.5".to_string())];
+            config.require_init = false;
+        })
+        .await;
+        let mut reinit = open(&address, Request::Reinit).await;
+        loop {
+            let Response::Status(ref status) = exchange(&address, Request::Status).await[0] else {
+                panic!("expected Status");
+            };
+            if status.init_running {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let responses = exchange(&address, build("echo early")).await;
+        let [Response::NotReady { ref reason }] = responses[..] else {
+            panic!("expected NotReady, got {:?}", responses);
+        };
+        assert_eq!(reason, "The server is re-running its init script; try again once it finishes");
+
+        let reinit_complete = |response: &Response| matches!(response, Response::ReinitComplete { .. });
+        read_until(&mut reinit, reinit_complete).await;
+        let responses = exchange(&address, build("echo ready")).await;
+        assert_eq!(output(&responses), [("ready".to_string(), false)]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn failing_second_init_script_is_named() {
+        let dir = scratch_dir("init-chain");
+        let (first, second) = (dir.join("first.sh"), dir.join("second.sh"));
+        std::fs::write(&first, "export BR_TEST_FIRST=1\n").unwrap();
+        std::fs::write(&second, "echo second failing\nreturn 3\n").unwrap();
+        let scripts = || vec![InitSource::Script(first.clone()), InitSource::Script(second.clone())];
+        let expected = format!(
+            "Init step 2 of 2 failed: init script {} failed with exit code: 3",
+            second.display()
+        );
+
+        let mut config = ServerConfig::new(Address::Tcp {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+        });
+        config.init = scripts();
+        let Err(error) = Server::bind(config).await else {
+            panic!("started despite a failing init script")
+        };
+        assert_eq!(format!("{:#}", error), expected);
+
+        let address = start(|config| {
+            config.init = scripts();
+            config.require_init = false;
+        })
+        .await;
+        let Response::Status(ref status) = exchange(&address, Request::Status).await[0] else {
+            panic!("expected Status");
+        };
+        assert_eq!(status.init_error.as_deref(), Some(expected.as_str()));
+        assert_eq!(status.init_scripts.len(), 2);
+    }
+
+    #[test]
+    fn event_log_quotes_values_and_rotates() {
+        let dir = scratch_dir("events");
+        let path = dir.join("server.log");
+        let log = EventLog::open(path.clone(), 120).unwrap();
+        log.record("started", &[("addr", &"127.0.0.1:19527"), ("shell", &"bash -l")]);
+        let first = std::fs::read_to_string(&path).unwrap();
+        assert!(
+            first.ends_with(" event=started addr=127.0.0.1:19527 shell=\"bash -l\"\n"),
+            "{}",
+            first
+        );
+
+        log.record("build_finished", &[("id", &1), ("command", &""), ("exit_code", &0)]);
+        assert_eq!(std::fs::read_to_string(dir.join("server.log.1")).unwrap(), first);
+        let second = std::fs::read_to_string(&path).unwrap();
+        assert!(second.ends_with(" event=build_finished id=1 command=\"\" exit_code=0\n"), "{}", second);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn builds_are_recorded_in_the_log_file() {
+        let path = scratch_dir("event-log").join("server.log");
+        let address = start(|config| config.log_file = Some(path.clone())).await;
+        exchange(&address, build("exit 4")).await;
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let events: Vec<_> = log
+            .lines()
+            .map(|line| line.split_once(' ').unwrap().1)
+            .filter(|line| line.starts_with("event=build_"))
+            .collect();
+        assert_eq!(events.len(), 2, "{}", log);
+        assert!(events[0].starts_with("event=build_started id=1 pid="), "{}", events[0]);
+        assert!(events[0].ends_with(" command=\"exit 4\""), "{}", events[0]);
+        assert!(events[1].starts_with("event=build_finished id=1 exit_code=4 duration_ms="), "{}", events[1]);
+        assert!(log.contains(" event=connection addr=127.0.0.1:"), "{}", log);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn silent_builds_are_warned_then_killed() {
+        let address = start(|config| {
+            config.stall_warn = Some(Duration::from_millis(200));
+            config.stall_kill = Some(Duration::from_millis(600));
+        })
+        .await;
+        let responses = exchange(&address, build("echo start; sleep 5")).await;
+        let lines = output(&responses);
+        assert_eq!(lines[0], ("start".to_string(), false));
+        assert_eq!(lines[1], ("[build-runner] no output for 0s".to_string(), true));
+        assert_eq!(lines[2], ("[build-runner] killed after 0s without output".to_string(), true));
+        assert_eq!(lines.len(), 3);
+        assert!(matches!(responses.last(), Some(Response::Stalled { idle_secs: 0 })));
+
+        // Output restarts the clock, so a build that keeps talking runs on
+        let responses = exchange(&address, build("for i in 1 2 3 4; do echo $i; sleep 0.1; done")).await;
+        assert_eq!(output(&responses).len(), 4);
+        assert!(matches!(responses.last(), Some(Response::BuildComplete { exit_code: 0, .. })));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn default_timeout_kills_builds_that_do_not_set_their_own() {
+        let address = start(|config| config.default_timeout = Some(Duration::from_millis(300))).await;
+        let responses = exchange(&address, build("echo start; sleep 5")).await;
+        assert_eq!(
+            output(&responses),
+            [
+                ("start".to_string(), false),
+                ("[build-runner] killed after the 0s timeout".to_string(), true)
+            ]
+        );
+        assert!(matches!(responses.last(), Some(Response::Timeout { elapsed_secs: 0 })));
+
+        // A timeout of 0 asks for none at all
+        let mut request = build("sleep 0.5; echo done");
+        if let Request::Build { ref mut timeout, .. } = request {
+            *timeout = Some(0);
+        }
+        let responses = exchange(&address, request).await;
+        assert_eq!(output(&responses), [("done".to_string(), false)]);
+        assert!(matches!(responses.last(), Some(Response::BuildComplete { exit_code: 0, .. })));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn logs_of_recent_builds_are_kept_in_memory() {
+        let address = start(|_| {}).await;
+        for n in 1..=RECENT_STREAMS + 1 {
+            exchange(&address, build(&format!("echo build {}; echo more >&2", n))).await;
+        }
+        let get_log = |id, tail| Request::GetLog { id, tail };
+
+        let responses = exchange(&address, get_log(None, None)).await;
+        let last = RECENT_STREAMS as BuildId + 1;
+        assert_eq!(
+            output(&responses),
+            [(format!("build {}", last), false), ("more".to_string(), true)]
+        );
+        assert!(matches!(
+            responses.last(),
+            Some(Response::LogComplete { build_id }) if *build_id == last
+        ));
+
+        let responses = exchange(&address, get_log(Some(2), Some(1))).await;
+        assert_eq!(output(&responses), [("more".to_string(), true)]);
+
+        // The oldest has dropped out of the ring
+        let responses = exchange(&address, get_log(Some(1), None)).await;
+        let [Response::Error { message }] = responses.as_slice() else {
+            panic!("{:?}", responses)
+        };
+        let expected = format!(
+            "No log for build 1; without build logs only the last {} builds are kept",
+            RECENT_STREAMS
+        );
+        assert_eq!(message, &expected);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn empty_command_runs_the_default_command() {
+        let address = start(|_| {}).await;
+        let responses = exchange(&address, build("  ")).await;
+        let [Response::Error { message }] = responses.as_slice() else {
+            panic!("{:?}", responses)
+        };
+        assert_eq!(message, "No command given and the server has no --default-command");
+
+        let address = start(|config| config.default_command = Some("echo default".to_string())).await;
+        let responses = exchange(&address, build("")).await;
+        assert_eq!(output(&responses), [("default".to_string(), false)]);
+        let responses = exchange(&address, build("echo given")).await;
+        assert_eq!(output(&responses), [("given".to_string(), false)]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn heartbeats_flow_and_clients_that_stop_reading_are_dropped() {
+        let address = start(|config| {
+            config.heartbeat_interval = Some(Duration::from_millis(100));
+        })
+        .await;
+        let responses = exchange(&address, build("sleep 0.5; echo done")).await;
+        let heartbeats = responses.iter().filter(|r| matches!(r, Response::Heartbeat)).count();
+        assert!(heartbeats >= 2, "{:?}", responses);
+        assert!(matches!(responses.last(), Some(Response::BuildComplete { exit_code: 0, .. })));
+
+        // Never read: once the socket's buffers fill, sends time out
+        let _stalled = open(&address, build("yes")).await;
+        let result = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let responses = exchange(&address, Request::History { limit: Some(1) }).await;
+                if let [Response::History { builds }] = responses.as_slice() {
+                    if builds[0].id == 2 {
+                        return builds[0].result;
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("the build was not dropped");
+        assert!(matches!(result, BuildResult::Disconnected), "{:?}", result);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn slow_clients_hold_back_the_build_rather_than_fill_memory() {
+        let address = Address::Unix(scratch_dir("slow-client").join("server.sock"));
+        let mut config = ServerConfig::new(address.clone());
+        config.heartbeat_interval = Some(Duration::from_secs(1));
+        let server = Server::bind(config).await.unwrap();
+        let state = server.state.clone();
+        tokio::spawn(server.serve());
+
+        // 4KB lines, so the pipe and socket buffers only hold a few dozen
+        let mut reader = open(&address, build(&format!("yes {}", "x".repeat(4095)))).await;
+        read_until(&mut reader, started).await;
+        let stream = state.streams.lock().unwrap().get(&1).cloned().unwrap();
+        let mut received = 0;
+        let mut most_behind = 0;
+        let reading = Instant::now();
+        while reading.elapsed() < Duration::from_secs(2) {
+            let response = reader.read_frame::<Response>().await.unwrap().expect("server hung up");
+            received += output(std::slice::from_ref(&response)).len() as u64;
+            // Lines taken off the output channel that haven't reached us yet
+            let published = stream.replay.lock().unwrap().next_index;
+            most_behind = most_behind.max(published - received);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(received > 0);
+        assert!(
+            most_behind <= (OUTPUT_CHANNEL_CAPACITY + OUTPUT_BATCH_LINES) as u64,
+            "{} lines behind",
+            most_behind
+        );
+
+        // Slow isn't gone: the build is still running
+        let responses = exchange(&address, Request::ListBuilds).await;
+        let [Response::Builds { ref builds }] = responses[..] else {
+            panic!("{:?}", responses)
+        };
+        assert_eq!(builds.iter().map(|build| build.id).collect::<Vec<_>>(), [1]);
+        let cancelled = read_until(&mut reader, |response| {
+            matches!(response, Response::Cancelled | Response::BuildComplete { .. })
+        });
+        let responses = exchange(&address, Request::Cancel { build_id: Some(1) });
+        let (cancelled, _) = tokio::join!(cancelled, responses);
+        assert!(matches!(cancelled.last(), Some(Response::Cancelled)), "{:?}", cancelled.last());
+    }
+
+    #[tokio::test]
+    async fn hello_from_another_protocol_version_is_refused() {
+        let address = start(|_| {}).await;
+        let hello = Request::Hello {
+            protocol: PROTOCOL_VERSION + 1,
+            version: "9.9.9".to_string(),
+            compression: None,
+        };
+        let mut stream = Stream::connect(&address).await.unwrap();
+        write_frame(&mut stream, &hello).await.unwrap();
+        let response = FrameReader::new(&mut stream).read_frame().await.unwrap();
+        let Some(Response::Error { message }) = response else {
+            panic!("{:?}", response)
+        };
+        assert_eq!(message, version_mismatch("9.9.9", VERSION));
+
+        // A request this server doesn't know from a client of another version
+        let mut stream = Stream::connect(&address).await.unwrap();
+        let hello = Request::Hello {
+            protocol: PROTOCOL_VERSION,
+            version: "9.9.9".to_string(),
+            compression: None,
+        };
+        write_frame(&mut stream, &hello).await.unwrap();
+        write_frame(&mut stream, &serde_json::json!({ "Rebuild": {} })).await.unwrap();
+        let mut reader = FrameReader::new(&mut stream);
+        let response = reader.read_frame().await.unwrap();
+        assert!(matches!(response, Some(Response::Hello { .. })), "{:?}", response);
+        let response = reader.read_frame().await.unwrap();
+        let Some(Response::Error { message }) = response else {
+            panic!("{:?}", response)
+        };
+        assert!(message.starts_with("Unsupported request ("), "{}", message);
+        assert!(message.ends_with(&version_mismatch("9.9.9", VERSION)), "{}", message);
+
+        // Clients from before the handshake go straight to their request
+        let mut stream = Stream::connect(&address).await.unwrap();
+        write_frame(&mut stream, &Request::Status).await.unwrap();
+        let response = FrameReader::new(&mut stream).read_frame().await.unwrap();
+        assert!(matches!(response, Some(Response::Status(_))), "{:?}", response);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn stdin_is_fed_to_the_build_or_left_empty() {
+        let address = start(|_| {}).await;
+        let mut request = build("cat; echo end");
+        if let Request::Build { ref mut stdin, .. } = request {
+            *stdin = Some(b"one\ntwo\n".to_vec());
+        }
+        let responses = exchange(&address, request).await;
+        let lines: Vec<_> = output(&responses).into_iter().map(|(line, _)| line).collect();
+        assert_eq!(lines, ["one", "two", "end"]);
+
+        // Without input, reading stdin hits its end rather than waiting
+        let responses = exchange(&address, build("cat; echo end")).await;
+        assert_eq!(output(&responses), [("end".to_string(), false)]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn oversized_and_cut_off_requests_are_answered() {
+        let address = start(|config| config.max_request_bytes = 1024).await;
+        // The server hears the rest out until the client has read why and gone
+        let mut reader = open(&address, build(&format!("echo {}", "x".repeat(2000)))).await;
+        let response = reader.read_frame().await.unwrap();
+        let Some(Response::Error { message }) = response else {
+            panic!("{:?}", response)
+        };
+        assert!(message.starts_with("Request refused: "), "{}", message);
+        assert!(message.ends_with(" (server --max-request-mb)"), "{}", message);
+        drop(reader);
+
+        // A frame that ends early, from a client that then stops sending
+        let mut stream = connect(&address).await;
+        let frame = serde_json::to_vec(&Request::Status).unwrap();
+        stream.write_all(&(frame.len() as u32 + 10).to_be_bytes()).await.unwrap();
+        stream.write_all(&frame).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let response = FrameReader::new(&mut stream).read_frame().await.unwrap();
+        let Some(Response::Error { message }) = response else {
+            panic!("{:?}", response)
+        };
+        assert!(message.starts_with("Incomplete request: "), "{}", message);
+
+        // Requests within the limit still run
+        let responses = exchange(&address, build("echo small")).await;
+        assert_eq!(output(&responses), [("small".to_string(), false)]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn status_names_the_host_and_version() {
+        let address = start(|_| {}).await;
+        let responses = exchange(&address, Request::Status).await;
+        let [Response::Status(status)] = responses.as_slice() else {
+            panic!("{:?}", responses)
+        };
+        let uname = std::process::Command::new("uname").arg("-n").output().unwrap();
+        let expected = String::from_utf8(uname.stdout).unwrap();
+        assert_eq!(status.hostname.as_deref(), Some(expected.trim_end()));
+        assert_eq!(status.version, VERSION);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn output_is_batched_in_order_for_clients_that_said_hello() {
+        let address = start(|_| {}).await;
+        let responses = exchange(&address, build("seq 1000")).await;
+        let expected: Vec<_> = (1..=1000).map(|n| (n.to_string(), false)).collect();
+        assert_eq!(output(&responses), expected);
+        let batches: Vec<_> = responses
+            .iter()
+            .filter_map(|response| match response {
+                Response::OutputBatch { lines } => Some(lines.len()),
+                _ => None,
+            })
+            .collect();
+        assert!(batches.len() < 100, "{:?}", batches);
+        assert!(batches.iter().all(|&len| len <= OUTPUT_BATCH_LINES), "{:?}", batches);
+        assert!(!responses.iter().any(|response| matches!(response, Response::Output { .. })));
+
+        // Clients from before the handshake get one line at a time
+        let mut stream = Stream::connect(&address).await.unwrap();
+        write_frame(&mut stream, &build("seq 3")).await.unwrap();
+        let mut reader = FrameReader::new(&mut stream);
+        let mut responses = Vec::new();
+        while let Some(response) = reader.read_frame().await.unwrap() {
+            responses.push(response);
+        }
+        let lines = responses.iter().filter(|response| matches!(response, Response::Output { .. }));
+        assert_eq!(lines.count(), 3);
+        assert!(!responses.iter().any(|response| matches!(response, Response::OutputBatch { .. })));
+    }
+
+    /// Throughput benchmark: `cargo test --release -- --ignored --nocapture batched_output`
+    #[cfg(unix)]
+    #[tokio::test]
+    #[ignore]
+    async fn batched_output_outpaces_one_frame_per_line() {
+        const LINES: usize = 100_000;
+        let address = start(|_| {}).await;
+        let command = format!("seq {}", LINES);
+        let mut timings = Vec::new();
+        for batched in [false, true] {
+            let started = Instant::now();
+            // Only clients that said Hello get their output batched
+            let mut stream = if batched {
+                connect(&address).await
+            } else {
+                Stream::connect(&address).await.unwrap()
+            };
+            write_frame(&mut stream, &build(&command)).await.unwrap();
+            let mut reader = FrameReader::new(&mut stream);
+            let (mut frames, mut lines) = (0, 0);
+            while let Some(response) = reader.read_frame::<Response>().await.unwrap() {
+                frames += 1;
+                lines += output(std::slice::from_ref(&response)).len();
+            }
+            let elapsed = started.elapsed();
+            assert_eq!(lines, LINES);
+            println!(
+                "{}: {} lines in {} frames, {:?} ({:.0} lines/s)",
+                if batched { "batched" } else { "per line" },
+                lines,
+                frames,
+                elapsed,
+                LINES as f64 / elapsed.as_secs_f64()
+            );
+            timings.push(elapsed);
+        }
+        assert!(timings[1] < timings[0], "batched {:?}, per line {:?}", timings[1], timings[0]);
+    }
+
+    #[tokio::test]
+    async fn malformed_request_is_answered_and_the_server_keeps_serving() {
+        let address = start(|_| {}).await;
+        let mut stream = Stream::connect(&address).await.unwrap();
+        let garbage = b"{\"Status\": ";
+        stream.write_all(&(garbage.len() as u32).to_be_bytes()).await.unwrap();
+        stream.write_all(garbage).await.unwrap();
+        let mut reader = FrameReader::new(&mut stream);
+        let response = reader.read_frame().await.unwrap();
+        let Some(Response::Error { message }) = response else {
+            panic!("{:?}", response)
+        };
+        assert!(message.starts_with("Malformed request, expected a JSON message: "), "{}", message);
+        assert!(reader.read_frame::<Response>().await.unwrap().is_none());
+
+        let responses = exchange(&address, Request::Status).await;
+        assert!(matches!(responses.as_slice(), [Response::Status(_)]), "{:?}", responses);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn attach_with_tail_starts_from_the_latest_lines() {
+        let address = start(|_| {}).await;
+        let mut builder = open(&address, build("seq 5; sleep 0.3; echo six")).await;
+        let printed_5 = |response: &Response| {
+            output(std::slice::from_ref(response)).contains(&("5".to_string(), false))
+        };
+        read_until(&mut builder, printed_5).await;
+
+        let attach = |tail| Request::Attach {
+            id: None,
+            from_line: 0,
+            tail: Some(tail),
+        };
+        let (latest, new) = tokio::join!(exchange(&address, attach(2)), exchange(&address, attach(0)));
+        let lines = |responses: &[Response]| -> Vec<String> {
+            output(responses).into_iter().map(|(line, _)| line).collect()
+        };
+        assert_eq!(lines(&latest), ["4", "5", "six"]);
+        assert_eq!(lines(&new), ["six"]);
+        assert!(matches!(new.last(), Some(Response::BuildComplete { exit_code: 0, .. })));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn builds_pick_a_shell_from_the_allow_list() {
+        let address = start(|config| config.allowed_shells = vec!["bash".to_string()]).await;
+        let with_shell = |shell: &str| {
+            let mut request = build("echo ran");
+            if let Request::Build { shell: ref mut requested, .. } = request {
+                *requested = Some(shell.to_string());
+            }
+            request
+        };
+        for shell in ["sh", "bash"] {
+            let responses = exchange(&address, with_shell(shell)).await;
+            assert_eq!(output(&responses), [("ran".to_string(), false)], "{}", shell);
+            assert!(matches!(responses.last(), Some(Response::BuildComplete { exit_code: 0, .. })));
+        }
+
+        let responses = exchange(&address, with_shell("zsh")).await;
+        let [Response::Error { message }] = responses.as_slice() else {
+            panic!("{:?}", responses)
+        };
+        assert_eq!(
+            message,
+            "Shell 'zsh' is not allowed; start the server with --allow-shell zsh to permit it"
+        );
+
+        // Nor can --arg run it, or any other program but the allowed shells
+        for program in ["zsh", "python3"] {
+            let responses = exchange(&address, build_argv(program, &["-c", "print(1)"])).await;
+            let [Response::Error { message }] = responses.as_slice() else {
+                panic!("{:?}", responses)
+            };
+            let expected = format!(
+                "Program '{}' is not allowed; --arg builds on this server may only run its shell or an --allow-shell one",
+                program
+            );
+            assert_eq!(message, &expected);
+        }
+        let responses = exchange(&address, build_argv("bash", &["-c", "echo ran"])).await;
+        assert_eq!(output(&responses), [("ran".to_string(), false)]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn builds_killed_by_a_signal_report_it() {
+        let address = start(|_| {}).await;
+        let responses = exchange(&address, build("echo before; kill -9 $$")).await;
+        assert_eq!(output(&responses), [("before".to_string(), false)]);
+        let Some(&Response::BuildComplete { exit_code, signal, .. }) = responses.last() else {
+            panic!("{:?}", responses)
+        };
+        assert_eq!((exit_code, signal), (-1, Some(9)));
+        assert_eq!(signal_name(9), "SIGKILL");
+        assert_eq!(signal_name(31), "signal 31");
+
+        let responses = exchange(&address, build("exit 3")).await;
+        assert!(matches!(
+            responses.last(),
+            Some(Response::BuildComplete { exit_code: 3, signal: None, .. })
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn taken_port_is_reported_before_running_init() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let marker = scratch_dir("taken-port").join("init-ran");
+        let mut config = ServerConfig::new(Address::Tcp {
+            host: "127.0.0.1".to_string(),
+            port: taken.local_addr().unwrap().port(),
+        });
+        config.init = vec![InitSource::Command(format!("touch '{}'", marker.display()))];
+        let Err(error) = Server::bind(config).await else {
+            panic!("bound a port that is taken")
+        };
+        assert!(error.to_string().contains(" is already in use on "), "{}", error);
+        assert!(!marker.exists());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn chained_commands_are_refused_with_no_shell_chaining() {
+        let address = start(|config| {
+            config.no_shell_chaining = true;
+            config.default_command = Some("echo one && echo two".to_string());
+        })
+        .await;
+        let refusals = [
+            (build("make; rm -rf out"), ";"),
+            (build("make\nrm -rf out"), "a line break"),
+            (build("make\rrm -rf out"), "a carriage return"),
+            // A shell run through --arg is held to the same rule
+            (build_argv("sh", &["-c", "make && rm -rf out"]), "&&"),
+        ];
+        for (request, operator) in refusals {
+            let responses = exchange(&address, request).await;
+            let [Response::Error { message }] = responses.as_slice() else {
+                panic!("{:?}", responses)
+            };
+            let expected = format!(
+                "The build command contains {}; this server runs single commands only (--no-shell-chaining)",
+                operator
+            );
+            assert_eq!(message, &expected);
+        }
+        // and nothing but a shell can be run that way
+        let responses = exchange(&address, build_argv("perl", &["-e", "print 1"])).await;
+        let [Response::Error { message }] = responses.as_slice() else {
+            panic!("{:?}", responses)
+        };
+        assert!(message.starts_with("Program 'perl' is not allowed; "), "{}", message);
+        let responses = exchange(&address, build_argv("sh", &["-c", "echo single"])).await;
+        assert_eq!(output(&responses), [("single".to_string(), false)]);
+
+        let responses = exchange(&address, build("echo single > /dev/null")).await;
+        assert!(matches!(responses.last(), Some(Response::BuildComplete { exit_code: 0, .. })));
+        // The server's own default command may chain
+        let responses = exchange(&address, build("")).await;
+        assert_eq!(output(&responses).len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn waiting_for_a_locked_directory_counts_as_queued() {
+        let address = start(|config| config.max_concurrent = 2).await;
+        let dir = scratch_dir("dir-lock");
+        let nested = dir.join("sub");
+        std::fs::create_dir(&nested).unwrap();
+        let mut first = open(&address, build_in(&dir, "sleep 0.3")).await;
+        read_until(&mut first, started).await;
+
+        // A free slot, but the directory overlaps the running build's
+        let second = exchange(&address, build_in(&nested, "echo second")).await;
+        assert_eq!(
+            output(&second),
+            [
+                ("waiting for build in same directory (id 1) to finish".to_string(), true),
+                ("second".to_string(), false)
+            ]
+        );
+        let Some(&Response::BuildComplete { queued_ms, .. }) = second.last() else {
+            panic!("{:?}", second)
+        };
+        assert!(queued_ms >= 100, "queued for {}ms", queued_ms);
+
+        let responses = exchange(&address, Request::History { limit: None }).await;
+        let [Response::History { builds }] = responses.as_slice() else {
+            panic!("{:?}", responses)
+        };
+        let queued: Vec<_> = builds.iter().map(|entry| (entry.id, entry.queued_ms)).collect();
+        assert!(matches!(queued[..], [(2, ms), (1, 0..=50)] if ms == queued_ms), "{:?}", queued);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn server_truncation_sends_the_start_then_the_end() {
+        let address = start(|_| {}).await;
+        let truncated = |command| {
+            let mut request = build(command);
+            if let Request::Build { ref mut server_truncate, .. } = request {
+                *server_truncate = Some(ServerTruncation { head: 3, tail: 2 });
+            }
+            request
+        };
+        let responses = exchange(&address, truncated("seq 10")).await;
+        let at = responses
+            .iter()
+            .position(|response| matches!(response, Response::Truncated { .. }))
+            .expect("no truncation marker");
+        let lines = |responses: &[Response]| -> Vec<String> {
+            output(responses).into_iter().map(|(line, _)| line).collect()
+        };
+        assert_eq!(lines(&responses[..at]), ["1", "2", "3"]);
+        assert!(matches!(responses[at], Response::Truncated { skipped: 5, skipped_bytes: 5 }));
+        assert_eq!(lines(&responses[at..]), ["9", "10"]);
+        assert!(matches!(responses.last(), Some(Response::BuildComplete { stdout_lines: 10, .. })));
+
+        // Short output needs no marker
+        let responses = exchange(&address, truncated("seq 4")).await;
+        assert_eq!(lines(&responses), ["1", "2", "3", "4"]);
+        assert!(!responses.iter().any(|response| matches!(response, Response::Truncated { .. })));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn argv_arguments_reach_the_program_intact() {
+        let address = start(|_| {}).await;
+        let responses = exchange(&address, build_argv("printf", &["%s\\n", "two words", "a;b"])).await;
+        assert_eq!(
+            output(&responses),
+            [("two words".to_string(), false), ("a;b".to_string(), false)]
+        );
+        assert!(matches!(responses.last(), Some(Response::BuildComplete { exit_code: 0, .. })));
+    }
+
+    #[tokio::test]
+    async fn spawn_failure_names_the_program() {
+        let address = start(|_| {}).await;
+        let request = build_argv("build-runner-no-such-program", &["--version"]);
+        let responses = exchange(&address, request).await;
+        let Some(Response::Error { message }) = responses.last() else {
+            panic!("expected an error, got {:?}", responses);
+        };
+        assert!(
+            message.contains("Failed to spawn 'build-runner-no-such-program'"),
+            "{}",
+            message
+        );
+        assert!(message.contains("NotFound"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn spawn_failure_reports_shell_command_and_directory() {
+        let shell = Shell::new("build-runner-no-such-shell", None);
+        let address = start(|config| config.shell = shell).await;
+        let responses = exchange(&address, build("make all")).await;
+        let Some(Response::Error { message }) = responses.last() else {
+            panic!("expected an error, got {:?}", responses);
+        };
+        let expected = format!(
+            "\n  command: make all\n  directory: {}",
+            std::env::temp_dir().display()
+        );
+        assert!(
+            message.starts_with(
+                "Failed to spawn shell 'build-runner-no-such-shell' to run 'make all' (NotFound)"
+            ),
+            "{}",
+            message
+        );
+        assert!(message.ends_with(&expected), "{}", message);
+    }
 }
@@ -0,0 +1,343 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Marker printed between the init script's own output and the environment dump
+pub const ENV_SENTINEL: &str = "__BUILD_RUNNER_ENV__";
+
+/// Command-line dialect of a shell, which decides how flags, sourcing and
+/// quoting are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShellKind {
+    Powershell,
+    Bash,
+    /// Any other POSIX-style shell invoked as `<program> -c <command>`
+    Sh,
+    Cmd,
+}
+
+impl ShellKind {
+    /// Guess the dialect from the program name (`pwsh.exe` -> PowerShell)
+    fn detect(program: &str) -> Self {
+        let name = Path::new(program)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        match name.as_str() {
+            "powershell" | "pwsh" => ShellKind::Powershell,
+            "cmd" => ShellKind::Cmd,
+            "bash" => ShellKind::Bash,
+            _ => ShellKind::Sh,
+        }
+    }
+}
+
+/// What the server runs to set up the build environment
+#[derive(Debug, Clone)]
+pub enum InitSource {
+    /// `--init`: a script file, sourced into the shell session
+    Script(PathBuf),
+    /// `--init-cmd`: a command line run directly in the shell session
+    Command(String),
+}
+
+impl fmt::Display for InitSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InitSource::Script(path) => write!(f, "init script {}", path.display()),
+            InitSource::Command(command) => write!(f, "init command '{}'", command),
+        }
+    }
+}
+
+/// Shell used by the server to run init scripts and build commands
+#[derive(Debug, Clone)]
+pub struct Shell {
+    program: String,
+    kind: ShellKind,
+    /// Flags placed before the command string, replacing the dialect's defaults
+    args: Option<Vec<String>>,
+}
+
+impl Shell {
+    pub fn new(program: impl Into<String>, args: Option<Vec<String>>) -> Self {
+        let program = program.into();
+        let kind = ShellKind::detect(&program);
+        Self {
+            program,
+            kind,
+            args,
+        }
+    }
+
+    /// `powershell` on Windows, `sh` everywhere else
+    pub fn platform_default() -> Self {
+        if cfg!(windows) {
+            Self::new("powershell", None)
+        } else {
+            Self::new("sh", None)
+        }
+    }
+
+    /// Executable to spawn for this shell
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+
+    /// Arguments that run `command`.
+    ///
+    /// The working directory is set on the process itself rather than spliced
+    /// into the command as a `cd`, so odd directory names can't break out of it.
+    pub fn command_args(&self, command: &str) -> Vec<String> {
+        let flags: &[&str] = match self.kind {
+            ShellKind::Powershell => &["-NoProfile", "-Command"],
+            ShellKind::Bash | ShellKind::Sh => &["-c"],
+            ShellKind::Cmd => &["/C"],
+        };
+        self.with_flags(flags, command.to_string())
+    }
+
+    /// Arguments that run `init` in the current shell session, then print
+    /// [`ENV_SENTINEL`] followed by the resulting environment as `KEY=VALUE`
+    /// lines.
+    ///
+    /// A script is sourced rather than executed so that any variables it
+    /// sets are still present when the environment is dumped.
+    pub fn init_args(&self, init: &InitSource) -> Vec<String> {
+        match self.kind {
+            ShellKind::Powershell => {
+                let source = match init {
+                    InitSource::Script(script) => {
+                        format!(". {}", quote_path_for_shell(self.kind, script))
+                    }
+                    InitSource::Command(command) => command.clone(),
+                };
+                self.with_flags(
+                    &["-NoProfile", "-ExecutionPolicy", "Bypass", "-Command"],
+                    format!(
+                        "{}; Write-Output '{}'; Get-ChildItem env: | ForEach-Object {{ \"$($_.Name)=$($_.Value)\" }}",
+                        source,
+                        ENV_SENTINEL
+                    ),
+                )
+            }
+            ShellKind::Bash | ShellKind::Sh => {
+                let source = match init {
+                    InitSource::Script(script) => {
+                        // `.` searches PATH for bare file names, so anchor relative paths
+                        let script = if script.is_relative() {
+                            Path::new(".").join(script)
+                        } else {
+                            script.to_path_buf()
+                        };
+                        format!(". {}", quote_path_for_shell(self.kind, &script))
+                    }
+                    // Grouped so `a || b` succeeds or fails as a whole
+                    InitSource::Command(command) => format!("{{ {}\n}}", command),
+                };
+                let source = format!("{} && echo {} && env", source, ENV_SENTINEL);
+                // A login bash picks up the user's profile before the init script
+                // runs; builds then inherit it through the captured environment
+                let flag = if self.kind == ShellKind::Bash { "-lc" } else { "-c" };
+                self.with_flags(&[flag], source)
+            }
+            ShellKind::Cmd => {
+                let source = match init {
+                    InitSource::Script(script) => {
+                        format!("call {}", quote_path_for_shell(self.kind, script))
+                    }
+                    InitSource::Command(command) => format!("({})", command),
+                };
+                self.with_flags(&["/C"], format!("{} && echo {}&& set", source, ENV_SENTINEL))
+            }
+        }
+    }
+
+    /// `--shell-args` if given, otherwise `defaults`, followed by `script`
+    fn with_flags(&self, defaults: &[&str], script: String) -> Vec<String> {
+        let mut args = match self.args {
+            Some(ref args) => args.clone(),
+            None => defaults.iter().map(|flag| flag.to_string()).collect(),
+        };
+        args.push(script);
+        args
+    }
+}
+
+/// Quote `path` as a single literal argument in the syntax of `kind`
+fn quote_path_for_shell(kind: ShellKind, path: &Path) -> String {
+    quote_for_shell(kind, &path.to_string_lossy())
+}
+
+/// Quote `value` as a single argument in the syntax of `kind`.
+///
+/// Single-quoted strings in PowerShell and POSIX shells expand nothing, so
+/// spaces, `$`, `;` and backticks are all safe once the quote itself is escaped.
+/// cmd's double quotes only keep spaces and separators together: `%VAR%` is
+/// still expanded inside them and a `"` in `value` ends the quoting early.
+pub fn quote_for_shell(kind: ShellKind, value: &str) -> String {
+    match kind {
+        // A quote inside a single-quoted string is escaped by doubling it
+        ShellKind::Powershell => format!("'{}'", value.replace('\'', "''")),
+        // Close the string, emit an escaped quote, reopen
+        ShellKind::Bash | ShellKind::Sh => format!("'{}'", value.replace('\'', "'\\''")),
+        // cmd can't escape `"` inside quotes; the Windows paths quoted here
+        // never contain one
+        ShellKind::Cmd => format!("\"{}\"", value),
+    }
+}
+
+/// Operators that would let one build command run further statements:
+/// separators, pipes, backgrounding and command substitution. A carriage
+/// return ends a line for cmd just as a line feed does for the others.
+const CHAINING_OPERATORS: &[&str] = &[";", "&&", "||", "&", "|", "`", "$(", "\n", "\r"];
+
+/// The first operator in `command` that chains another command onto it,
+/// for `--no-shell-chaining`. Quoting isn't understood, so `echo 'a;b'`
+/// counts too.
+pub fn chaining_operator(command: &str) -> Option<&'static str> {
+    // Earliest first, and `&&` rather than the `&` inside it
+    CHAINING_OPERATORS
+        .iter()
+        .filter_map(|op| command.find(op).map(|at| (at, std::cmp::Reverse(op.len()), *op)))
+        .min()
+        .map(|(_, _, op)| op)
+}
+
+impl fmt::Display for Shell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.program)?;
+        if let Some(ref args) = self.args {
+            write!(f, " {}", args.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn detects_kind_from_program_name() {
+        assert_eq!(Shell::new("pwsh.exe", None).kind, ShellKind::Powershell);
+        assert_eq!(Shell::new("CMD.EXE", None).kind, ShellKind::Cmd);
+        assert_eq!(Shell::new("/usr/local/bin/bash", None).kind, ShellKind::Bash);
+        assert_eq!(Shell::new("zsh", None).kind, ShellKind::Sh);
+    }
+
+    #[test]
+    fn command_args_per_shell() {
+        let command = "make all";
+        assert_eq!(
+            Shell::new("powershell", None).command_args(command),
+            args(&["-NoProfile", "-Command", "make all"])
+        );
+        assert_eq!(Shell::new("bash", None).command_args(command), args(&["-c", "make all"]));
+        assert_eq!(Shell::new("sh", None).command_args(command), args(&["-c", "make all"]));
+        assert_eq!(Shell::new("cmd", None).command_args(command), args(&["/C", "make all"]));
+    }
+
+    #[test]
+    fn shell_args_replace_default_flags() {
+        let shell = Shell::new("bash", Some(args(&["--norc", "-ec"])));
+        assert_eq!(shell.command_args("make"), args(&["--norc", "-ec", "make"]));
+        assert_eq!(shell.to_string(), "bash --norc -ec");
+    }
+
+    #[test]
+    fn init_args_source_a_script() {
+        let script = InitSource::Script(PathBuf::from("env setup.sh"));
+        assert_eq!(
+            Shell::new("bash", None).init_args(&script),
+            args(&["-lc", ". './env setup.sh' && echo __BUILD_RUNNER_ENV__ && env"])
+        );
+        assert_eq!(
+            Shell::new("sh", None).init_args(&script),
+            args(&["-c", ". './env setup.sh' && echo __BUILD_RUNNER_ENV__ && env"])
+        );
+
+        let script = InitSource::Script(PathBuf::from(r"C:\tools\env.ps1"));
+        let powershell = Shell::new("powershell", None).init_args(&script);
+        assert_eq!(powershell[..4], args(&["-NoProfile", "-ExecutionPolicy", "Bypass", "-Command"]));
+        assert!(
+            powershell[4].starts_with(r". 'C:\tools\env.ps1'; Write-Output '__BUILD_RUNNER_ENV__'; "),
+            "{}",
+            powershell[4]
+        );
+
+        let script = InitSource::Script(PathBuf::from(r"C:\tools\vcvars64.bat"));
+        assert_eq!(
+            Shell::new("cmd", None).init_args(&script),
+            args(&["/C", r#"call "C:\tools\vcvars64.bat" && echo __BUILD_RUNNER_ENV__&& set"#])
+        );
+    }
+
+    #[test]
+    fn init_args_run_a_command() {
+        let command = InitSource::Command("source a || source b".to_string());
+        assert_eq!(
+            Shell::new("bash", None).init_args(&command),
+            args(&["-lc", "{ source a || source b\n} && echo __BUILD_RUNNER_ENV__ && env"])
+        );
+        assert_eq!(
+            Shell::new("cmd", None).init_args(&command),
+            args(&["/C", "(source a || source b) && echo __BUILD_RUNNER_ENV__&& set"])
+        );
+        let powershell = Shell::new("pwsh", None).init_args(&command);
+        assert!(powershell[4].starts_with("source a || source b; Write-Output"), "{}", powershell[4]);
+    }
+
+    #[test]
+    fn quotes_each_value_as_one_literal_argument() {
+        let cases = [
+            ("two words", "'two words'", "'two words'"),
+            ("it's", "'it''s'", "'it'\\''s'"),
+            ("say \"hi\"", "'say \"hi\"'", "'say \"hi\"'"),
+            ("`date`", "'`date`'", "'`date`'"),
+            ("a; rm -rf b", "'a; rm -rf b'", "'a; rm -rf b'"),
+            ("$HOME", "'$HOME'", "'$HOME'"),
+        ];
+        for (value, powershell, posix) in cases {
+            assert_eq!(quote_for_shell(ShellKind::Powershell, value), powershell);
+            assert_eq!(quote_for_shell(ShellKind::Bash, value), posix);
+            assert_eq!(quote_for_shell(ShellKind::Sh, value), posix);
+        }
+    }
+
+    #[test]
+    fn cmd_quotes_keep_spaces_and_separators_together() {
+        let cases = [
+            (r"C:\Program Files\env.bat", r#""C:\Program Files\env.bat""#),
+            ("it's", "\"it's\""),
+            ("a & b; c", "\"a & b; c\""),
+        ];
+        for (value, cmd) in cases {
+            assert_eq!(quote_for_shell(ShellKind::Cmd, value), cmd);
+        }
+    }
+
+    #[test]
+    fn finds_the_first_chaining_operator() {
+        assert_eq!(chaining_operator("cargo build --release"), None);
+        assert_eq!(chaining_operator("make && make install"), Some("&&"));
+        assert_eq!(chaining_operator("make & rm -rf ~; ls"), Some("&"));
+        assert_eq!(chaining_operator("make || true"), Some("||"));
+        assert_eq!(chaining_operator("echo $(whoami)"), Some("$("));
+        assert_eq!(chaining_operator("make\nrm -rf ~"), Some("\n"));
+        assert_eq!(chaining_operator("build.bat\rdel /q *"), Some("\r"));
+        assert_eq!(chaining_operator("build.bat\r\ndel /q *"), Some("\r"));
+    }
+
+    #[test]
+    fn absolute_init_script_is_not_anchored() {
+        let script = InitSource::Script(PathBuf::from("/opt/env.sh"));
+        assert_eq!(
+            Shell::new("sh", None).init_args(&script)[1],
+            ". '/opt/env.sh' && echo __BUILD_RUNNER_ENV__ && env"
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use anyhow::{anyhow, Context, Result};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{
+    self, CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig,
+    SignatureScheme,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+/// Server side of TLS, from the PEM files given to `--tls-cert` and `--tls-key`
+pub fn acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor> {
+    let certs = read_certs(cert)?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("Failed to read a private key from {}", key.display()))?;
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("The --tls-cert and --tls-key files don't make a usable pair")?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Client side of TLS, trusting the certificates in `ca` (the server's own
+/// certificate, or the CA that signed it). Without one the server's
+/// certificate isn't checked at all, which is only fit for testing.
+pub fn connector(ca: Option<&Path>) -> Result<TlsConnector> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?;
+    let config = match ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(ca)? {
+                roots
+                    .add(cert)
+                    .with_context(|| format!("Unusable certificate in {}", ca.display()))?;
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        None => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(provider())))
+            .with_no_client_auth(),
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read certificates from {}", path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("No PEM certificates found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+/// Say what went wrong in a client's handshake with `host`, and what to do
+/// about it
+pub fn handshake_error(e: io::Error, host: &str) -> anyhow::Error {
+    let tls_error = e
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<rustls::Error>());
+    let problem = match tls_error {
+        Some(rustls::Error::InvalidCertificate(cert_error)) => match cert_error {
+            CertificateError::UnknownIssuer => {
+                "the server's certificate isn't signed by anything in --tls-ca".to_string()
+            }
+            CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. } => {
+                format!(
+                    "the server's certificate isn't issued for '{}'; use a --host it names",
+                    host
+                )
+            }
+            CertificateError::Expired | CertificateError::ExpiredContext { .. } => {
+                "the server's certificate has expired".to_string()
+            }
+            CertificateError::NotValidYet | CertificateError::NotValidYetContext { .. } => {
+                "the server's certificate isn't valid yet; check both machines' clocks".to_string()
+            }
+            other => format!("the server's certificate was rejected: {:?}", other),
+        },
+        Some(rustls::Error::InvalidMessage(_)) | Some(rustls::Error::InappropriateMessage { .. }) => {
+            "the server didn't answer in TLS; is it running without --tls-cert?".to_string()
+        }
+        Some(other) => other.to_string(),
+        None => e.to_string(),
+    };
+    anyhow!("TLS handshake with {} failed: {}", host, problem)
+}
+
+/// `--tls-insecure`: encrypts, but takes any certificate the server presents
+#[derive(Debug)]
+struct AcceptAnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(error: rustls::Error) -> String {
+        let e = io::Error::new(io::ErrorKind::InvalidData, error);
+        handshake_error(e, "buildbox").to_string()
+    }
+
+    #[test]
+    fn handshake_errors_say_what_to_do() {
+        assert_eq!(
+            rejected(rustls::Error::InvalidCertificate(CertificateError::UnknownIssuer)),
+            "TLS handshake with buildbox failed: the server's certificate isn't signed by \
+             anything in --tls-ca"
+        );
+        assert_eq!(
+            rejected(rustls::Error::InvalidCertificate(CertificateError::NotValidForName)),
+            "TLS handshake with buildbox failed: the server's certificate isn't issued for \
+             'buildbox'; use a --host it names"
+        );
+        assert_eq!(
+            rejected(rustls::Error::InvalidCertificate(CertificateError::Expired)),
+            "TLS handshake with buildbox failed: the server's certificate has expired"
+        );
+
+        let e = io::Error::new(io::ErrorKind::ConnectionReset, "connection reset by peer");
+        assert_eq!(
+            handshake_error(e, "buildbox").to_string(),
+            "TLS handshake with buildbox failed: connection reset by peer"
+        );
+    }
+}
@@ -0,0 +1,501 @@
+use anyhow::{Context, Result};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{self, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{tcp, TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{unix, UnixListener, UnixStream};
+#[cfg(windows)]
+use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient, NamedPipeServer, ServerOptions};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+
+/// Where the server listens and the client connects: a TCP port, a Unix
+/// domain socket (`--socket`) whose file permissions decide who may connect,
+/// or a Windows named pipe (`--pipe`) that only local clients can open
+#[derive(Debug, Clone)]
+pub enum Address {
+    Tcp { host: String, port: u16 },
+    Unix(PathBuf),
+    Pipe(String),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp { host, port } => write!(f, "{}:{}", host, port),
+            Address::Unix(path) => write!(f, "{}", path.display()),
+            Address::Pipe(name) => f.write_str(name),
+        }
+    }
+}
+
+/// Who is on the other end of a server connection, for logs and `list`
+#[derive(Debug, Clone, Copy)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// Unix socket clients have no address; the kernel reports their pid
+    Unix { pid: Option<i32> },
+    /// Named pipe clients are local too, and Windows reports their pid
+    #[cfg(windows)]
+    Pipe { pid: Option<u32> },
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr),
+            Peer::Unix { pid: Some(pid) } => write!(f, "unix:pid {}", pid),
+            Peer::Unix { pid: None } => f.write_str("unix"),
+            #[cfg(windows)]
+            Peer::Pipe { pid: Some(pid) } => write!(f, "pipe:pid {}", pid),
+            #[cfg(windows)]
+            Peer::Pipe { pid: None } => f.write_str("pipe"),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn unix_unsupported() -> anyhow::Error {
+    anyhow::anyhow!("Unix domain sockets (--socket) are not supported on this platform; use --port")
+}
+
+#[cfg(not(windows))]
+fn pipe_unsupported() -> anyhow::Error {
+    anyhow::anyhow!("Named pipes (--pipe) are only available on Windows; use --socket or --port")
+}
+
+/// How long a client waits for a busy pipe before trying it again
+#[cfg(windows)]
+const PIPE_BUSY_RETRY: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// A connection between client and server
+pub enum Stream {
+    Tcp(TcpStream),
+    /// TCP secured with `--tls-cert` on the server and `--tls` on the client
+    Tls(Box<TlsStream<TcpStream>>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    /// The server's end of a named pipe
+    #[cfg(windows)]
+    PipeServer(NamedPipeServer),
+    #[cfg(windows)]
+    PipeClient(NamedPipeClient),
+}
+
+impl Stream {
+    pub async fn connect(address: &Address) -> io::Result<Self> {
+        match address {
+            Address::Tcp { host, port } => {
+                Ok(Stream::Tcp(TcpStream::connect((host.as_str(), *port)).await?))
+            }
+            #[cfg(unix)]
+            Address::Unix(path) => Ok(Stream::Unix(UnixStream::connect(path).await?)),
+            #[cfg(not(unix))]
+            Address::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                unix_unsupported().to_string(),
+            )),
+            #[cfg(windows)]
+            Address::Pipe(name) => loop {
+                // Every instance is taken until the server creates the next
+                // one, which it does as soon as it accepts a connection
+                match ClientOptions::new().open(name) {
+                    Ok(client) => break Ok(Stream::PipeClient(client)),
+                    Err(e)
+                        if e.raw_os_error()
+                            == Some(windows_sys::Win32::Foundation::ERROR_PIPE_BUSY as i32) =>
+                    {
+                        tokio::time::sleep(PIPE_BUSY_RETRY).await
+                    }
+                    Err(e) => break Err(e),
+                }
+            },
+            #[cfg(not(windows))]
+            Address::Pipe(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                pipe_unsupported().to_string(),
+            )),
+        }
+    }
+
+    /// Secure a connection to `address` as a TLS client. A failed handshake
+    /// says what was wrong with the server's certificate.
+    pub async fn start_tls(self, connector: &TlsConnector, address: &Address) -> Result<Self> {
+        let (Stream::Tcp(stream), Address::Tcp { host, .. }) = (self, address) else {
+            anyhow::bail!("TLS is only available over TCP, not with --socket or --pipe");
+        };
+        let name = ServerName::try_from(host.clone())
+            .with_context(|| format!("'{}' can't be checked against a certificate", host))?;
+        let stream = connector
+            .connect(name, stream)
+            .await
+            .map_err(|e| crate::tls::handshake_error(e, host))?;
+        Ok(Stream::Tls(Box::new(stream.into())))
+    }
+
+    /// Secure an accepted connection as a TLS server
+    pub async fn accept_tls(self, acceptor: &TlsAcceptor) -> io::Result<Self> {
+        match self {
+            Stream::Tcp(stream) => Ok(Stream::Tls(Box::new(acceptor.accept(stream).await?.into()))),
+            other => Ok(other),
+        }
+    }
+
+    /// Borrow the reading and writing sides separately
+    pub fn split(&mut self) -> (ReadHalf<'_>, WriteHalf<'_>) {
+        match self {
+            Stream::Tcp(stream) => {
+                let (reader, writer) = stream.split();
+                (ReadHalf::Tcp(reader), WriteHalf::Tcp(writer))
+            }
+            #[cfg(unix)]
+            Stream::Unix(stream) => {
+                let (reader, writer) = stream.split();
+                (ReadHalf::Unix(reader), WriteHalf::Unix(writer))
+            }
+            // No borrowed split for these, so both halves share the stream
+            _ => {
+                let (reader, writer) = tokio::io::split(self);
+                (ReadHalf::Shared(reader), WriteHalf::Shared(writer))
+            }
+        }
+    }
+}
+
+/// Accepts client connections for the server
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix { listener: UnixListener, path: PathBuf },
+    /// The pipe instance the next client will connect to
+    #[cfg(windows)]
+    Pipe {
+        next: tokio::sync::Mutex<NamedPipeServer>,
+        name: String,
+    },
+}
+
+impl Listener {
+    /// Listen on `address`. A socket file left behind by a server that is no
+    /// longer running is replaced; one that still answers is an error. The
+    /// socket is made accessible to its owner only.
+    pub async fn bind(address: &Address) -> Result<Self> {
+        match address {
+            Address::Tcp { host, port } => {
+                let listener = match TcpListener::bind((host.as_str(), *port)).await {
+                    Ok(listener) => listener,
+                    // Most often a server left running from an earlier session
+                    Err(e) if e.kind() == io::ErrorKind::AddrInUse => anyhow::bail!(
+                        "Port {} is already in use on {}. Is another build-runner server running? \
+                         Try `build-runner status --port {}` or choose a different --port",
+                        port,
+                        host,
+                        port
+                    ),
+                    Err(e) => {
+                        return Err(e).context(format!("Failed to bind to {}:{}", host, port));
+                    }
+                };
+                Ok(Listener::Tcp(listener))
+            }
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                use std::os::unix::fs::PermissionsExt;
+
+                if path.exists() {
+                    if UnixStream::connect(path).await.is_ok() {
+                        anyhow::bail!("A build server is already listening on {}", path.display());
+                    }
+                    std::fs::remove_file(path)
+                        .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+                }
+                let listener = UnixListener::bind(path)
+                    .with_context(|| format!("Failed to bind to {}", path.display()))?;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+                    .with_context(|| format!("Failed to restrict access to {}", path.display()))?;
+                Ok(Listener::Unix {
+                    listener,
+                    path: path.clone(),
+                })
+            }
+            #[cfg(not(unix))]
+            Address::Unix(_) => Err(unix_unsupported()),
+            #[cfg(windows)]
+            Address::Pipe(name) => {
+                // Refuses a name another server already owns; pipes only
+                // accept local clients, and only their owner may write to them
+                let first = ServerOptions::new()
+                    .first_pipe_instance(true)
+                    .create(name)
+                    .with_context(|| format!("Failed to create pipe {} (is a server already using it?)", name))?;
+                Ok(Listener::Pipe {
+                    next: tokio::sync::Mutex::new(first),
+                    name: name.clone(),
+                })
+            }
+            #[cfg(not(windows))]
+            Address::Pipe(_) => Err(pipe_unsupported()),
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<(Stream, Peer)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::Tcp(stream), Peer::Tcp(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => {
+                let (stream, _) = listener.accept().await?;
+                let pid = stream.peer_cred().ok().and_then(|cred| cred.pid());
+                Ok((Stream::Unix(stream), Peer::Unix { pid }))
+            }
+            #[cfg(windows)]
+            Listener::Pipe { next, name } => {
+                let mut next = next.lock().await;
+                next.connect().await?;
+                // Have the next instance ready before handing this one out
+                let connected = std::mem::replace(&mut *next, ServerOptions::new().create(name)?);
+                let pid = pipe_client_pid(&connected);
+                Ok((Stream::PipeServer(connected), Peer::Pipe { pid }))
+            }
+        }
+    }
+
+    /// Where clients reach this listener
+    pub fn local_addr(&self) -> io::Result<String> {
+        match self {
+            Listener::Tcp(listener) => Ok(listener.local_addr()?.to_string()),
+            #[cfg(unix)]
+            Listener::Unix { path, .. } => Ok(path.display().to_string()),
+            #[cfg(windows)]
+            Listener::Pipe { name, .. } => Ok(name.clone()),
+        }
+    }
+
+    /// Only TCP listeners beyond loopback are reachable from other machines
+    pub fn is_remote(&self) -> bool {
+        match self {
+            Listener::Tcp(listener) => listener
+                .local_addr()
+                .is_ok_and(|addr| !addr.ip().is_loopback()),
+            #[cfg(unix)]
+            Listener::Unix { .. } => false,
+            #[cfg(windows)]
+            Listener::Pipe { .. } => false,
+        }
+    }
+}
+
+/// Process id of the client connected to `pipe`
+#[cfg(windows)]
+fn pipe_client_pid(pipe: &NamedPipeServer) -> Option<u32> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::System::Pipes::GetNamedPipeClientProcessId;
+
+    let mut pid: u32 = 0;
+    // SAFETY: the handle is a connected pipe we own, and `pid` outlives the call
+    let ok = unsafe { GetNamedPipeClientProcessId(pipe.as_raw_handle() as _, &mut pid) };
+    (ok != 0).then_some(pid)
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix { path, .. } = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Reading side of a [`Stream`]
+pub enum ReadHalf<'a> {
+    Tcp(tcp::ReadHalf<'a>),
+    /// TLS and named pipes, which can't be split without sharing
+    Shared(tokio::io::ReadHalf<&'a mut Stream>),
+    #[cfg(unix)]
+    Unix(unix::ReadHalf<'a>),
+}
+
+/// Writing side of a [`Stream`]
+pub enum WriteHalf<'a> {
+    Tcp(tcp::WriteHalf<'a>),
+    Shared(tokio::io::WriteHalf<&'a mut Stream>),
+    #[cfg(unix)]
+    Unix(unix::WriteHalf<'a>),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+            #[cfg(windows)]
+            Stream::PipeServer(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(windows)]
+            Stream::PipeClient(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+            #[cfg(windows)]
+            Stream::PipeServer(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(windows)]
+            Stream::PipeClient(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+            #[cfg(windows)]
+            Stream::PipeServer(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(windows)]
+            Stream::PipeClient(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+            #[cfg(windows)]
+            Stream::PipeServer(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(windows)]
+            Stream::PipeClient(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+impl AsyncRead for ReadHalf<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ReadHalf::Tcp(reader) => Pin::new(reader).poll_read(cx, buf),
+            ReadHalf::Shared(reader) => Pin::new(reader).poll_read(cx, buf),
+            #[cfg(unix)]
+            ReadHalf::Unix(reader) => Pin::new(reader).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for WriteHalf<'_> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            WriteHalf::Tcp(writer) => Pin::new(writer).poll_write(cx, buf),
+            WriteHalf::Shared(writer) => Pin::new(writer).poll_write(cx, buf),
+            #[cfg(unix)]
+            WriteHalf::Unix(writer) => Pin::new(writer).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            WriteHalf::Tcp(writer) => Pin::new(writer).poll_flush(cx),
+            WriteHalf::Shared(writer) => Pin::new(writer).poll_flush(cx),
+            #[cfg(unix)]
+            WriteHalf::Unix(writer) => Pin::new(writer).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            WriteHalf::Tcp(writer) => Pin::new(writer).poll_shutdown(cx),
+            WriteHalf::Shared(writer) => Pin::new(writer).poll_shutdown(cx),
+            #[cfg(unix)]
+            WriteHalf::Unix(writer) => Pin::new(writer).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp(host: &str, port: u16) -> Address {
+        Address::Tcp {
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    fn port(listener: &Listener) -> u16 {
+        listener.local_addr().unwrap().rsplit_once(':').unwrap().1.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn loopback_listener_is_local_and_reachable_by_name() {
+        let listener = Listener::bind(&tcp("127.0.0.1", 0)).await.unwrap();
+        assert!(!listener.is_remote());
+        let address = tcp("localhost", port(&listener));
+        let (connected, accepted) = tokio::join!(Stream::connect(&address), listener.accept());
+        assert!(matches!(connected.unwrap(), Stream::Tcp(_)));
+        assert!(matches!(accepted.unwrap().1, Peer::Tcp(addr) if addr.ip().is_loopback()));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_socket_is_private_and_replaces_stale_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("build-runner-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        // Left behind by a server that died without cleaning up
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let address = Address::Unix(path.clone());
+
+        let listener = Listener::bind(&address).await.unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!listener.is_remote());
+        let error = Listener::bind(&address).await.err().unwrap();
+        assert!(error.to_string().starts_with("A build server is already listening on"), "{}", error);
+
+        let (connected, accepted) = tokio::join!(Stream::connect(&address), listener.accept());
+        assert!(matches!(connected.unwrap(), Stream::Unix(_)));
+        assert!(matches!(accepted.unwrap().1, Peer::Unix { .. }));
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn wildcard_listener_is_remote() {
+        let listener = Listener::bind(&tcp("0.0.0.0", 0)).await.unwrap();
+        assert!(listener.is_remote());
+        assert_eq!(tcp("build-box", 19527).to_string(), "build-box:19527");
+    }
+}
@@ -0,0 +1,56 @@
+//! The flow the crate documentation shows: an embedded server, and a client
+//! that builds on it, follows the output and stops it
+
+use build_runner::client::{BuildClient, BuildEvent, BuildOutcome};
+use build_runner::server::{Server, ServerConfig};
+use build_runner::transport::Address;
+use tokio_stream::StreamExt;
+
+fn loopback(port: u16) -> Address {
+    Address::Tcp {
+        host: "127.0.0.1".to_string(),
+        port,
+    }
+}
+
+#[tokio::test]
+async fn bind_serve_build_stream_stop() {
+    let server = Server::bind(ServerConfig::new(loopback(0))).await.unwrap();
+    let port = server.local_addr().rsplit_once(':').unwrap().1.parse().unwrap();
+    let serving = tokio::spawn(server.serve());
+
+    let client = BuildClient::connect(loopback(port)).await.unwrap();
+    let mut events = client.build(".", "echo built").await.unwrap();
+    let mut started = None;
+    let mut lines = Vec::new();
+    let mut outcome = None;
+    while let Some(event) = events.next().await {
+        match event.unwrap() {
+            BuildEvent::Started { build_id, .. } => started = Some(build_id),
+            BuildEvent::Output(line) => lines.push(line.line),
+            BuildEvent::Finished(finished) => outcome = Some(finished),
+            BuildEvent::Queued { .. } => {}
+        }
+    }
+    assert_eq!(started, Some(1));
+    assert_eq!(lines, ["built"]);
+    let Some(BuildOutcome::Completed {
+        exit_code,
+        stdout_lines,
+        stderr_lines,
+        ..
+    }) = outcome
+    else {
+        panic!("build didn't complete: {:?}", outcome);
+    };
+    assert_eq!((exit_code, stdout_lines, stderr_lines), (0, 1, 0));
+
+    let status = client.status().await.unwrap();
+    assert!(status.initialized);
+    assert_eq!(status.builds_started, 1);
+    assert!(status.running.is_empty());
+
+    client.stop(false).await.unwrap();
+    serving.await.unwrap().unwrap();
+    assert!(BuildClient::connect(loopback(port)).await.is_err());
+}